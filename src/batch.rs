@@ -0,0 +1,133 @@
+//! Concurrent sending of many requests, with limits on how many run at once.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::stream::{FuturesUnordered, Stream};
+use tokio::sync::Semaphore;
+
+/// Default maximum number of requests of a batch running at once.
+const DEFAULT_MAX_CONCURRENT: usize = 8;
+/// Default maximum number of requests of a batch running at once to the same host.
+const DEFAULT_MAX_PER_HOST: usize = 4;
+
+/// Limits on the requests of [`crate::Client::send_all`] running at once.
+///
+/// A value of 0 is treated as 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchLimits {
+    /// Maximum number of requests running at once.
+    pub max_concurrent: usize,
+    /// Maximum number of requests to the same host running at once.
+    pub max_per_host: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            max_per_host: DEFAULT_MAX_PER_HOST,
+        }
+    }
+}
+
+/// Run `run` on all the items within the limits, yielding the index of each item with its output
+/// as they complete.
+///
+/// The futures are polled in the calling task, so that they can borrow the client, and a
+/// single-threaded runtime still runs them concurrently.
+pub(crate) fn run_limited<T, F, Fut>(
+    items: Vec<(String, T)>,
+    limits: BatchLimits,
+    run: F,
+) -> impl Stream<Item = (usize, Fut::Output)>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future,
+{
+    let global = Arc::new(Semaphore::new(limits.max_concurrent.max(1)));
+    let mut hosts = HashMap::new();
+
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, (host, item))| {
+            let host = hosts
+                .entry(host.to_ascii_lowercase())
+                .or_insert_with(|| Arc::new(Semaphore::new(limits.max_per_host.max(1))))
+                .clone();
+            let global = global.clone();
+            let future = run(item);
+            async move {
+                // Waiting for the host first keeps the global permits for the other hosts.
+                let _host = host.acquire_owned().await.expect("semaphore never closed");
+                let _global = global
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore never closed");
+                (index, future.await)
+            }
+        })
+        .collect::<FuturesUnordered<_>>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Running {
+        total: AtomicUsize,
+        per_host: Mutex<HashMap<&'static str, usize>>,
+        max_total: AtomicUsize,
+        max_per_host: AtomicUsize,
+    }
+
+    #[tokio::test]
+    async fn test_run_limited() {
+        let running = Running::default();
+        let items = (0..20)
+            .map(|i| {
+                let host = ["a.example", "b.example", "c.example"][i % 3];
+                (host.to_owned(), (i, host))
+            })
+            .collect();
+        let limits = BatchLimits {
+            max_concurrent: 5,
+            max_per_host: 2,
+        };
+
+        let mut outputs = run_limited(items, limits, |(i, host)| {
+            let running = &running;
+            async move {
+                let total = running.total.fetch_add(1, Ordering::SeqCst) + 1;
+                running.max_total.fetch_max(total, Ordering::SeqCst);
+                let per_host = {
+                    let mut per_host = running.per_host.lock().unwrap();
+                    let count = per_host.entry(host).or_default();
+                    *count += 1;
+                    *count
+                };
+                running.max_per_host.fetch_max(per_host, Ordering::SeqCst);
+
+                tokio::time::sleep(Duration::from_millis(5)).await;
+
+                *running.per_host.lock().unwrap().get_mut(host).unwrap() -= 1;
+                running.total.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+        outputs.sort_unstable();
+        assert_eq!(outputs, (0..20).map(|i| (i, i * 2)).collect::<Vec<_>>());
+        assert_eq!(running.max_total.load(Ordering::SeqCst), 5);
+        assert_eq!(running.max_per_host.load(Ordering::SeqCst), 2);
+    }
+}
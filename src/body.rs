@@ -0,0 +1,288 @@
+//! Bodies of requests and responses, which can be streamed instead of held in memory.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{ensure, Context as _, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream};
+use http::HeaderMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::connection::Connection;
+use crate::http::{BodyReader, Framing};
+use crate::pool::{Pool, PoolKey};
+use crate::timeout::Abort;
+use crate::Error;
+
+/// Streaming body of a response returned by [`crate::Client::send_streaming`].
+///
+/// It can be read either through [`AsyncRead`] or as a [`Stream`] of [`Bytes`]. Once it was read
+/// to the end, the connection goes back to the client's pool; dropping it before closes the
+/// connection.
+///
+/// Reading fails with an error wrapping [`crate::Timeout::Total`] or [`crate::Cancelled`] if the
+/// request times out or is cancelled before the body was read, and with one wrapping
+/// [`crate::LimitExceeded`] if the body is larger than allowed.
+pub struct Body {
+    reader: BodyReader<Connection>,
+    /// Where to return the connection once the body was read, if the server allows reusing it.
+    release: Option<(Arc<Pool>, PoolKey)>,
+    trailers: HeaderMap,
+    /// Resolves when the request times out or is cancelled.
+    abort: Option<BoxFuture<'static, io::Error>>,
+}
+
+impl Body {
+    pub(crate) fn new(
+        reader: BodyReader<Connection>,
+        release: Option<(Arc<Pool>, PoolKey)>,
+    ) -> Self {
+        Self {
+            reader,
+            release,
+            trailers: HeaderMap::new(),
+            abort: None,
+        }
+    }
+
+    /// Stop reading the body once the request times out or is cancelled.
+    pub(crate) fn set_abort(&mut self, abort: Abort) {
+        self.abort = abort.is_set().then(|| abort.wait().boxed());
+    }
+
+    /// Length of the body as announced by the server, if any, which is useful to report progress.
+    pub fn content_length(&self) -> Option<u64> {
+        match self.reader.framing() {
+            Framing::Empty => Some(0),
+            Framing::Length(length) => Some(length),
+            Framing::Chunked | Framing::Close => None,
+        }
+    }
+
+    /// Whether the body was sent with the chunked transfer coding.
+    pub fn is_chunked(&self) -> bool {
+        self.reader.framing() == Framing::Chunked
+    }
+
+    /// Trailer fields sent after a chunked body, available once it was read to the end.
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    /// Once the body was read to the end, keep its trailers and give the connection back to the
+    /// pool.
+    fn finish(&mut self) {
+        self.abort = None;
+        self.trailers.extend(self.reader.take_trailers());
+        if let Some((pool, key)) = self.release.take() {
+            if let Some(stream) = self.reader.take_reusable() {
+                pool.checkin(key, stream);
+            }
+        }
+    }
+
+    /// Fail if the request timed out or was cancelled, closing the connection.
+    fn poll_abort(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let err = match self.abort.as_mut().map(|abort| abort.poll_unpin(cx)) {
+            Some(Poll::Ready(err)) => err,
+            _ => return Ok(()),
+        };
+        self.abort = None;
+        self.release = None;
+        self.reader.close();
+        Err(err)
+    }
+}
+
+impl AsyncRead for Body {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_abort(cx)?;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        if buf.filled().len() == filled && buf.remaining() > 0 {
+            self.finish();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for Body {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Err(err) = self.poll_abort(cx) {
+            return Poll::Ready(Some(Err(err)));
+        }
+        let next = ready!(Pin::new(&mut self.reader).poll_next(cx));
+        if next.is_none() {
+            self.finish();
+        }
+        Poll::Ready(next)
+    }
+}
+
+/// Size of the buffer used to copy a streamed request body.
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Body of a request, either held in memory or read from a source while it is sent.
+///
+/// A body of known length is sent with a Content-Length header, otherwise it uses the chunked
+/// transfer coding.
+pub struct RequestBody(Source);
+
+enum Source {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Pin<Box<dyn AsyncRead + Send>>,
+        length: Option<u64>,
+    },
+}
+
+impl RequestBody {
+    /// Stream the body from a reader, which yields exactly `length` bytes if it is given.
+    pub fn from_reader(reader: impl AsyncRead + Send + 'static, length: Option<u64>) -> Self {
+        Self(Source::Reader {
+            reader: Box::pin(reader),
+            length,
+        })
+    }
+
+    /// Stream the body from the file at the given path.
+    pub async fn from_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("open {}", path.display()))?;
+        let length = file.metadata().await.context("read file metadata")?.len();
+
+        Ok(Self::from_reader(file, Some(length)))
+    }
+
+    /// Length of the body, if known in advance.
+    pub fn length(&self) -> Option<u64> {
+        match &self.0 {
+            Source::Bytes(bytes) => Some(bytes.len() as u64),
+            Source::Reader { length, .. } => *length,
+        }
+    }
+
+    /// Whether the body can be sent again, e.g. after the connection failed.
+    pub fn is_replayable(&self) -> bool {
+        matches!(self.0, Source::Bytes(_))
+    }
+
+    /// A copy of the body, if it is held in memory.
+    pub(crate) fn try_clone(&self) -> Option<Self> {
+        match &self.0 {
+            Source::Bytes(bytes) => Some(Self(Source::Bytes(bytes.clone()))),
+            Source::Reader { .. } => None,
+        }
+    }
+
+    /// Write the body to the stream, using the chunked transfer coding if its length is unknown.
+    ///
+    /// A body held in memory is left untouched, so that it can be sent again.
+    pub(crate) async fn write_to<W: AsyncWrite + Unpin>(&mut self, stream: &mut W) -> Result<()> {
+        match &mut self.0 {
+            Source::Bytes(bytes) => stream.write_all(bytes).await.context("write body"),
+            Source::Reader { reader, length } => {
+                let chunked = length.is_none();
+                let mut written = 0u64;
+                let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+                loop {
+                    let read = reader.read(&mut buf).await.context("read body source")?;
+                    if read == 0 {
+                        break;
+                    }
+                    if chunked {
+                        stream
+                            .write_all(format!("{:x}\r\n", read).as_bytes())
+                            .await
+                            .context("write chunk size")?;
+                    }
+                    stream.write_all(&buf[..read]).await.context("write body")?;
+                    if chunked {
+                        stream.write_all(b"\r\n").await.context("write chunk end")?;
+                    }
+                    written += read as u64;
+                }
+
+                match length {
+                    Some(length) => ensure!(
+                        written == *length,
+                        "body source yielded {} bytes instead of {}",
+                        written,
+                        length
+                    ),
+                    None => stream
+                        .write_all(b"0\r\n\r\n")
+                        .await
+                        .context("write last chunk")?,
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Source::Bytes(bytes))
+    }
+}
+
+impl fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Source::Bytes(bytes) => write!(f, "RequestBody({} bytes)", bytes.len()),
+            Source::Reader { length, .. } => write!(f, "RequestBody(reader, {:?} bytes)", length),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_chunked() -> Result<()> {
+        let mut body = RequestBody::from_reader(b"hello".as_slice(), None);
+        let mut raw = Vec::new();
+        body.write_to(&mut raw).await?;
+
+        assert_eq!(raw, b"5\r\nhello\r\n0\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_wrong_length() {
+        let mut body = RequestBody::from_reader(b"hello".as_slice(), Some(6));
+        let mut raw = Vec::new();
+        assert!(body.write_to(&mut raw).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_try_clone() -> Result<()> {
+        let mut body = RequestBody::from(b"hello".to_vec())
+            .try_clone()
+            .expect("body in memory");
+        let mut raw = Vec::new();
+        body.write_to(&mut raw).await?;
+
+        assert_eq!(raw, b"hello");
+        assert!(RequestBody::from_reader(b"hello".as_slice(), None)
+            .try_clone()
+            .is_none());
+        Ok(())
+    }
+}
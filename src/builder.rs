@@ -0,0 +1,550 @@
+//! Configuration and creation of a [`Client`].
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{ensure, Context, Result};
+use arti_client::{TorClient, TorClientConfig};
+use futures::stream::Stream;
+use time::OffsetDateTime;
+use tor_config::CfgPath;
+use tor_dirmgr::Error;
+use tor_rtcompat::tokio::TokioRustlsRuntime as Runtime;
+use tracing::debug;
+use url::Url;
+
+use crate::churn::ChurnRefresh;
+use crate::client::{UpdateNeeded, DIRECTORY_CACHE_C4DT, DIRECTORY_CHURN_C4DT};
+use crate::flatfiledirmgr::{
+    check_directory, BuiltDirMgr, FlatFileDirMgrBuilder, HsDirValidity, DEFAULT_CHURN_FRACTION,
+};
+use crate::progress::ProgressSender;
+use crate::{
+    BootstrapProgress, Client, CookieJar, Decompression, HttpCache, IsolationPolicy, Limits,
+    PoolConfig, RedirectPolicy, RelayPolicy, RetryPolicy, Timeouts, TlsConfig, AUTHORITY_FILENAME,
+    CHURN_FILENAME, MICRODESCRIPTORS_FILENAME,
+};
+
+/// When a file of the directory cache is downloaded again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refresh {
+    /// Once downloaded, the file is kept.
+    Never,
+    /// When it wasn't modified during the current week, starting on Monday.
+    Weekly,
+    /// When it wasn't modified today.
+    Daily,
+    /// When it is older than the given duration.
+    MaxAge(Duration),
+}
+
+impl Refresh {
+    /// Whether a file modified at the given time must be downloaded again.
+    pub(crate) fn is_stale(&self, modified: OffsetDateTime, now: OffsetDateTime) -> bool {
+        let same_week = modified.year() == now.year()
+            && modified.monday_based_week() == now.monday_based_week();
+        match self {
+            Self::Never => false,
+            Self::Weekly => !same_week,
+            Self::Daily => !same_week || modified.weekday() != now.weekday(),
+            Self::MaxAge(max_age) => now - modified > *max_age,
+        }
+    }
+}
+
+/// When the files of the directory cache are downloaded again.
+///
+/// The default follows the publication of the directory cache, once a week, and of the churn,
+/// once a day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreshnessPolicy {
+    /// Refresh of the consensus, microdescriptors and certificate.
+    pub directory: Refresh,
+    /// Refresh of the churn file.
+    pub churn: Refresh,
+}
+
+impl Default for FreshnessPolicy {
+    fn default() -> Self {
+        Self {
+            directory: Refresh::Weekly,
+            churn: Refresh::Daily,
+        }
+    }
+}
+
+impl FreshnessPolicy {
+    /// Returns which cache files need to be updated, from the modification dates of the files.
+    ///
+    /// With the default policy, this will probably fail for the first minutes of the day, when
+    /// the churn is not yet available in the new version.
+    pub(crate) fn cache_state(&self, cache_path: &Path) -> Result<UpdateNeeded> {
+        if !cache_path.is_dir() {
+            return Err(cache_dir_missing().into());
+        }
+        if check_directory(cache_path).is_err() {
+            return Ok(UpdateNeeded::All);
+        }
+
+        let now = OffsetDateTime::now_utc();
+        if self
+            .directory
+            .is_stale(modified(cache_path, MICRODESCRIPTORS_FILENAME)?, now)
+        {
+            return Ok(UpdateNeeded::All);
+        }
+        Ok(
+            if self
+                .churn
+                .is_stale(modified(cache_path, CHURN_FILENAME)?, now)
+            {
+                UpdateNeeded::Churn
+            } else {
+                UpdateNeeded::None
+            },
+        )
+    }
+}
+
+/// Returns the modification time of a file of the cache.
+pub(crate) fn modified(cache_path: &Path, file_name: &str) -> Result<OffsetDateTime> {
+    let sec = fs::metadata(cache_path.join(file_name))?
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok(OffsetDateTime::from_unix_timestamp(sec.as_secs() as i64)?)
+}
+
+/// Error of a cache directory which doesn't exist.
+fn cache_dir_missing() -> crate::Error {
+    crate::Error::CacheMissing("Corrupt cache: cache-directory doesn't exist".into())
+}
+
+/// Checks whether the AUTHORITY_FILENAME is present, which is needed to verify the
+/// signatures of the other files.
+fn check_authority(cache_path: &Path) -> Result<()> {
+    if !cache_path.is_dir() {
+        return Err(crate::Error::CacheMissing(Box::new(Error::CacheCorruption(
+            "directory cache does not exist",
+        )))
+        .into());
+    }
+    if !cache_path.join(AUTHORITY_FILENAME).exists() {
+        debug!("required file missing: {}", AUTHORITY_FILENAME);
+        return Err(crate::Error::CacheMissing(Box::new(Error::CacheCorruption(
+            "required file(s) missing in cache",
+        )))
+        .into());
+    }
+    Ok(())
+}
+
+/// Builder of a [`Client`], created by [`Client::builder`].
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    cache_path: PathBuf,
+    state_path: Option<PathBuf>,
+    directory_cache_url: String,
+    churn_url: String,
+    freshness: FreshnessPolicy,
+    churn_fraction: usize,
+    relay_policy: RelayPolicy,
+    tls: TlsConfig,
+    timeouts: Timeouts,
+    retry_policy: RetryPolicy,
+    pool: PoolConfig,
+    redirect_policy: RedirectPolicy,
+    decompression: Option<Decompression>,
+    isolation_policy: IsolationPolicy,
+    limits: Limits,
+    plain_http: bool,
+    cookie_jar: Option<Arc<CookieJar>>,
+    http_cache: Option<HttpCache>,
+    churn_refresh: Option<Duration>,
+    progress: ProgressSender,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new(cache_path: PathBuf) -> Self {
+        Self {
+            cache_path,
+            state_path: None,
+            directory_cache_url: DIRECTORY_CACHE_C4DT.to_owned(),
+            churn_url: DIRECTORY_CHURN_C4DT.to_owned(),
+            freshness: FreshnessPolicy::default(),
+            churn_fraction: DEFAULT_CHURN_FRACTION,
+            relay_policy: RelayPolicy::default(),
+            tls: TlsConfig::default(),
+            timeouts: Timeouts::default(),
+            retry_policy: RetryPolicy::default(),
+            pool: PoolConfig::default(),
+            redirect_policy: RedirectPolicy::default(),
+            decompression: None,
+            isolation_policy: IsolationPolicy::default(),
+            limits: Limits::default(),
+            plain_http: false,
+            cookie_jar: None,
+            http_cache: None,
+            churn_refresh: None,
+            progress: ProgressSender::default(),
+        }
+    }
+
+    /// Directory where Tor keeps its state, e.g. guards. Defaults to the cache directory.
+    pub fn state_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_path = Some(path.into());
+        self
+    }
+
+    /// URL of the .tgz archive of the directory cache.
+    pub fn directory_cache_url(mut self, url: impl Into<String>) -> Self {
+        self.directory_cache_url = url.into();
+        self
+    }
+
+    /// URL of the churn file.
+    pub fn churn_url(mut self, url: impl Into<String>) -> Self {
+        self.churn_url = url.into();
+        self
+    }
+
+    /// When the files of the directory cache are downloaded again.
+    pub fn freshness(mut self, freshness: FreshnessPolicy) -> Self {
+        self.freshness = freshness;
+        self
+    }
+
+    /// Remove at most 1/`fraction` of the relays of the consensus because of the churn.
+    ///
+    /// If more relays are listed in the churn, a random subset of them is removed.
+    pub fn churn_fraction(mut self, fraction: usize) -> Self {
+        self.churn_fraction = fraction;
+        self
+    }
+
+    /// Restrictions on the relays used in circuits, e.g. the countries of the exits.
+    ///
+    /// The client fails to bootstrap if no relay of the consensus satisfies them.
+    pub fn relay_policy(mut self, policy: RelayPolicy) -> Self {
+        self.relay_policy = policy;
+        self
+    }
+
+    /// Trusted roots and pinned keys used to authenticate servers.
+    pub fn tls_config(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Timeouts of the requests which don't set their own.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Retry policy of the requests which don't set their own.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Configuration of the pool of idle connections.
+    pub fn pool_config(mut self, config: PoolConfig) -> Self {
+        self.pool = config;
+        self
+    }
+
+    /// Policy to follow redirects.
+    pub fn redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    /// Negotiate compressed responses in [`Client::send`], and decode them with the given limits.
+    pub fn decompression(mut self, decompression: Decompression) -> Self {
+        self.decompression = Some(decompression);
+        self
+    }
+
+    /// Policy to isolate requests on separate circuits.
+    pub fn isolation_policy(mut self, policy: IsolationPolicy) -> Self {
+        self.isolation_policy = policy;
+        self
+    }
+
+    /// Limits on the responses of the requests which don't set their own.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Allow sending requests to http:// URIs, which are not encrypted between the exit relay and
+    /// the server. They are refused by default, except for onion services, which are reached
+    /// without an exit relay.
+    pub fn plain_http(mut self, allow: bool) -> Self {
+        self.plain_http = allow;
+        self
+    }
+
+    /// Store the cookies set by servers in the given jar, e.g. one persisted in the cache
+    /// directory with [`CookieJar::persistent`] and [`crate::COOKIES_FILENAME`].
+    pub fn cookie_jar(mut self, jar: Arc<CookieJar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Cache the responses of [`Client::send`], e.g. in the [`crate::HTTP_CACHE_DIRNAME`]
+    /// directory of the cache directory.
+    pub fn http_cache(mut self, cache: HttpCache) -> Self {
+        self.http_cache = Some(cache);
+        self
+    }
+
+    /// Check the churn every `interval` while the client is alive, and when it is stale according
+    /// to the [`FreshnessPolicy`], download it again and reload the directory with it, so that a
+    /// long-lived client stops using the relays which left the network.
+    ///
+    /// The check runs on the runtime of the client, and stops when the client is dropped.
+    pub fn churn_refresh(mut self, interval: Duration) -> Self {
+        self.churn_refresh = Some(interval);
+        self
+    }
+
+    /// Stream of the steps of the bootstrap, starting with the current one, to be polled while
+    /// [`ClientBuilder::build`] runs, e.g. to show a progress bar.
+    ///
+    /// Steps reached faster than they are read are skipped. The stream continues with the
+    /// [`Client::bootstrap_progress`] of the client built.
+    ///
+    /// This is the only report of the loading of the directory cache: the bootstrap events of the
+    /// underlying Tor client don't include it.
+    pub fn bootstrap_progress(&self) -> impl Stream<Item = BootstrapProgress> + Send + 'static {
+        self.progress.subscribe()
+    }
+
+    /// Check the settings, update the directory cache if needed and bootstrap the client.
+    pub async fn build(self) -> Result<Client, crate::Error> {
+        self.validate()?;
+
+        self.update_cache().await.context("update cache")?;
+
+        let runtime = Runtime::current().context("get runtime")?;
+        let dirmgr = BuiltDirMgr::default();
+        let hsdir = HsDirValidity::default();
+
+        let tor_client = TorClient::with_runtime(runtime)
+            .config(self.tor_config().context("load config")?)
+            .dirmgr_builder::<FlatFileDirMgrBuilder>(Arc::new(FlatFileDirMgrBuilder {
+                churn_fraction: self.churn_fraction,
+                relay_policy: self.relay_policy.clone(),
+                progress: self.progress.clone(),
+                built: dirmgr.clone(),
+                hsdir: hsdir.clone(),
+            }))
+            .create_bootstrapped()
+            .await
+            .context("create tor client")
+            .map_err(crate::Error::bootstrap)?;
+
+        let dirmgr = dirmgr
+            .lock()
+            .expect("lock poisoned")
+            .take()
+            .context("directory manager not built")?;
+
+        let client = Client::from_tor_client(tor_client, dirmgr, self.progress, hsdir)
+            .with_tls_config(self.tls)
+            .with_timeouts(self.timeouts)
+            .with_retry_policy(self.retry_policy)
+            .with_pool_config(self.pool)
+            .with_redirect_policy(self.redirect_policy)
+            .with_isolation_policy(self.isolation_policy)
+            .with_limits(self.limits)
+            .with_plain_http(self.plain_http);
+        let client = match self.decompression {
+            Some(decompression) => client.with_decompression(decompression),
+            None => client,
+        };
+        let client = match self.cookie_jar {
+            Some(jar) => client.with_cookie_jar(jar),
+            None => client,
+        };
+        let client = match self.http_cache {
+            Some(cache) => client.with_http_cache(cache),
+            None => client,
+        };
+        Ok(match self.churn_refresh {
+            Some(interval) => client.with_churn_refresh(ChurnRefresh {
+                cache_path: self.cache_path,
+                churn_url: self.churn_url,
+                refresh: self.freshness.churn,
+                interval,
+            }),
+            None => client,
+        })
+    }
+
+    /// Check the settings before anything is downloaded.
+    fn validate(&self) -> Result<()> {
+        if !self.cache_path.is_dir() {
+            return Err(cache_dir_missing().into());
+        }
+        if let Some(state_path) = &self.state_path {
+            ensure!(
+                state_path.is_dir(),
+                "state directory {} doesn't exist",
+                state_path.display()
+            );
+        }
+        for url in [&self.directory_cache_url, &self.churn_url] {
+            let parsed = Url::parse(url).with_context(|| format!("invalid URL {}", url))?;
+            ensure!(
+                matches!(parsed.scheme(), "http" | "https"),
+                "unsupported URL {}",
+                url
+            );
+        }
+        ensure!(self.churn_fraction > 0, "churn fraction must be positive");
+        ensure!(
+            self.churn_refresh != Some(Duration::ZERO),
+            "churn refresh interval must be positive"
+        );
+        self.relay_policy.validate()?;
+        ensure!(
+            self.limits.max_headers > 0
+                && self.limits.max_head_size > 0
+                && self.limits.max_line_length > 0,
+            "limits of the response head must be positive"
+        );
+        if let Some(decompression) = &self.decompression {
+            ensure!(
+                decompression.max_decoded_size > 0,
+                "maximum decoded size must be positive"
+            );
+        }
+        ensure!(
+            self.pool.max_idle_per_host == 0 || !self.pool.idle_timeout.is_zero(),
+            "idle timeout of the pool must be positive"
+        );
+        for refresh in [self.freshness.directory, self.freshness.churn] {
+            ensure!(
+                refresh != Refresh::MaxAge(Duration::ZERO),
+                "maximum age of the cache must be positive"
+            );
+        }
+        Ok(())
+    }
+
+    /// Download the cache files which are missing or stale.
+    async fn update_cache(&self) -> Result<()> {
+        Client::update_cache(
+            &self.cache_path,
+            &self.directory_cache_url,
+            &self.churn_url,
+            &self.freshness,
+        )
+        .await
+    }
+
+    /// Build the configuration of the Tor client, using the authority of the cache.
+    fn tor_config(&self) -> Result<TorClientConfig> {
+        let cache_path = &self.cache_path;
+        let mut cfg_builder = TorClientConfig::builder();
+        check_authority(cache_path)?;
+        cfg_builder
+            .storage()
+            .cache_dir(CfgPath::new_literal(cache_path))
+            .state_dir(CfgPath::new_literal(
+                self.state_path.as_deref().unwrap_or(cache_path),
+            ));
+
+        let auth_path = cache_path.join(AUTHORITY_FILENAME);
+        let auth_raw = fs::read_to_string(auth_path.clone())
+            .context(format!("Failed to read {}", auth_path.to_string_lossy()))?;
+        let auth = serde_json::from_str(auth_raw.as_str())?;
+
+        cfg_builder.tor_network().set_authorities(vec![auth]);
+        // Overriding authorities requires also overriding fallback caches
+        cfg_builder.tor_network().set_fallback_caches(Vec::new());
+
+        cfg_builder.build().context("build config")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn day(year: i32, month: Month, day: u8, hour: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .and_then(|date| date.with_hms(hour, 0, 0))
+            .expect("valid date")
+            .assume_utc()
+    }
+
+    #[test]
+    fn test_refresh() {
+        let now = day(2023, Month::October, 25, 12);
+
+        assert!(!Refresh::Weekly.is_stale(day(2023, Month::October, 23, 0), now));
+        assert!(Refresh::Weekly.is_stale(day(2023, Month::October, 22, 23), now));
+        assert!(Refresh::Weekly.is_stale(day(2022, Month::October, 26, 12), now));
+        assert!(Refresh::Daily.is_stale(day(2023, Month::October, 24, 23), now));
+        assert!(!Refresh::Never.is_stale(day(2020, Month::January, 1, 0), now));
+        assert!(!Refresh::MaxAge(Duration::from_secs(3600))
+            .is_stale(day(2023, Month::October, 25, 11), now));
+        assert!(Refresh::MaxAge(Duration::from_secs(3600))
+            .is_stale(day(2023, Month::October, 25, 10), now));
+    }
+
+    #[test]
+    fn test_validate() {
+        let tmp = tempfile::tempdir().expect("Creating tempdir");
+
+        assert!(ClientBuilder::new(tmp.path().to_owned()).validate().is_ok());
+        assert!(ClientBuilder::new(tmp.path().join("missing"))
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .churn_url("ftp://example.com/churn.txt")
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .churn_fraction(0)
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .churn_refresh(Duration::ZERO)
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .limits(Limits {
+                max_head_size: 0,
+                ..Limits::default()
+            })
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .decompression(Decompression {
+                max_decoded_size: 0
+            })
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .pool_config(PoolConfig {
+                idle_timeout: Duration::ZERO,
+                ..PoolConfig::default()
+            })
+            .validate()
+            .is_err());
+        assert!(ClientBuilder::new(tmp.path().to_owned())
+            .pool_config(PoolConfig {
+                idle_timeout: Duration::ZERO,
+                max_idle_per_host: 0,
+            })
+            .validate()
+            .is_ok());
+    }
+}
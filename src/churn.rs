@@ -0,0 +1,91 @@
+//! Background refresh of the churn of a running client.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use time::OffsetDateTime;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tor_dirmgr::DirProvider;
+use tracing::{debug, info, warn};
+
+use crate::builder::{modified, Refresh};
+use crate::{Client, Error, CHURN_FILENAME};
+
+/// Settings of the refresh of the churn, see [`crate::ClientBuilder::churn_refresh`].
+#[derive(Clone, Debug)]
+pub(crate) struct ChurnRefresh {
+    /// Directory of the directory cache.
+    pub cache_path: PathBuf,
+    /// URL of the churn file.
+    pub churn_url: String,
+    /// When the churn is stale.
+    pub refresh: Refresh,
+    /// Time between two checks of the churn.
+    pub interval: Duration,
+}
+
+/// Check the churn regularly, and when it is stale, download it again and reload the directory
+/// with it, until the guard is dropped.
+pub(crate) fn spawn(settings: ChurnRefresh, dirmgr: Arc<dyn DirProvider>) -> DropGuard {
+    let stop = CancellationToken::new();
+    let stopped = stop.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = stopped.cancelled() => break,
+                _ = tokio::time::sleep(settings.interval) => {}
+            }
+            match refresh(&settings, dirmgr.as_ref()).await {
+                Ok(true) => info!("churn refreshed"),
+                Ok(false) => debug!("churn still fresh"),
+                // Retried at the next check, e.g. when the new churn isn't published yet.
+                Err(err) => warn!("failed to refresh the churn: {:#}", err),
+            }
+        }
+    });
+    stop.drop_guard()
+}
+
+/// Download the churn again if it is stale, and reload the directory to remove the relays listed
+/// in it. Returns whether it was stale.
+async fn refresh(settings: &ChurnRefresh, dirmgr: &dyn DirProvider) -> Result<bool> {
+    if !is_stale(&settings.cache_path, settings.refresh)? {
+        return Ok(false);
+    }
+    Client::download_churn_file(&settings.cache_path, &settings.churn_url)
+        .await
+        .map_err(|err| Error::Download(err.into()))?;
+    // Loading the directory again notifies the circuit manager of the relays removed.
+    dirmgr.bootstrap().await.context("reload directory")?;
+    Ok(true)
+}
+
+/// Whether the churn file of the cache must be downloaded again.
+fn is_stale(cache_path: &Path, refresh: Refresh) -> Result<bool> {
+    Ok(refresh.is_stale(
+        modified(cache_path, CHURN_FILENAME)?,
+        OffsetDateTime::now_utc(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stale() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        assert!(is_stale(tmp.path(), Refresh::Daily).is_err());
+
+        std::fs::write(tmp.path().join(CHURN_FILENAME), "")?;
+        assert!(!is_stale(tmp.path(), Refresh::Daily)?);
+        assert!(!is_stale(tmp.path(), Refresh::Never)?);
+        assert!(is_stale(
+            tmp.path(),
+            Refresh::MaxAge(Duration::from_nanos(1))
+        )?);
+        Ok(())
+    }
+}
@@ -1,38 +1,98 @@
 use std::fs::File;
 use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::SystemTime;
-use std::{convert::TryFrom, fs, io, path::Path, sync::Arc};
-
-use anyhow::{anyhow, bail, Context, Result};
-use arti_client::{DataStream, TorClient, TorClientConfig};
-use http::{Request, Response};
-use time::OffsetDateTime;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_rustls::{
-    client::TlsStream,
-    rustls::{self, ServerName},
-    TlsConnector,
-};
-use tor_config::CfgPath;
-use tor_dirmgr::Error;
+use std::{convert::TryFrom, path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use arti_client::{config::BoolOrAuto, DataStream, IsolationToken, StreamPrefs, TorClient};
+use futures::Stream;
+use http::{header, request, HeaderValue, Method, Request, Response, StatusCode, Version};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_rustls::{client::TlsStream, rustls::ServerName, TlsConnector};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tor_dirmgr::DirProvider;
 use tor_rtcompat::tokio::TokioRustlsRuntime as Runtime;
 use tracing::{debug, trace, warn};
 
-use crate::flatfiledirmgr::check_directory;
 use crate::{
-    flatfiledirmgr::FlatFileDirMgrBuilder,
-    http::{raw_to_response, request_to_raw},
-    CHURN_FILENAME, MICRODESCRIPTORS_FILENAME,
+    batch::{self, BatchLimits},
+    body::{Body, RequestBody},
+    builder::{ClientBuilder, FreshnessPolicy},
+    churn::{self, ChurnRefresh},
+    connection::{is_onion, Connection, Scheme},
+    cookies::{unix_now, CookieJar},
+    download::{self, ContentRange, DownloadError, DownloadOptions, DownloadState},
+    error::Error,
+    flatfiledirmgr::HsDirValidity,
+    http::{
+        complete_headers, is_persistent, read_head, request_to_raw, BodyReader, Decompression,
+        Framing, LimitExceeded, Limits, ACCEPT_ENCODING,
+    },
+    http_cache::{self, CacheStatus, HttpCache},
+    http_proxy::{self, HttpProxyConfig},
+    isolation::{IsolationPolicy, Isolator},
+    pool::{Pool, PoolConfig, PoolKey},
+    progress::{BootstrapProgress, ProgressSender},
+    proxy::Proxy,
+    redirect::{self, is_redirect, RedirectPolicy, Redirects},
+    retry::{self, RetryPolicy},
+    socks,
+    timeout::{self, limit, Abort, Timeout, Timeouts},
+    tls::TlsConfig,
+    CHURN_FILENAME,
 };
 
 /// Client using the Tor network
-pub struct Client(TorClient<Runtime>);
+pub struct Client {
+    tor_client: TorClient<Runtime>,
+    dirmgr: Arc<dyn DirProvider>,
+    progress: ProgressSender,
+    hsdir: HsDirValidity,
+    pool: Arc<Pool>,
+    redirect_policy: RedirectPolicy,
+    decompression: Option<Decompression>,
+    timeouts: Timeouts,
+    limits: Limits,
+    allow_plain_http: bool,
+    tls: TlsConfig,
+    isolation_policy: IsolationPolicy,
+    isolator: Isolator,
+    cookie_jar: Option<Arc<CookieJar>>,
+    http_cache: Option<HttpCache>,
+    retry_policy: RetryPolicy,
+    /// Stops the refresh of the churn when the client is dropped.
+    churn_refresh: Option<DropGuard>,
+}
+
+/// Settings applying to all the steps of a single request.
+#[derive(Clone, Copy)]
+struct RequestSettings {
+    timeouts: Timeouts,
+    limits: Limits,
+    /// Isolation token of the streams, `None` for the default isolation.
+    isolation: Option<IsolationToken>,
+    /// Additional isolation token of the streams, set by retries to get a new circuit.
+    circuit: Option<IsolationToken>,
+    retry: RetryPolicy,
+    /// Whether connections can be reused by other requests.
+    pooled: bool,
+}
 
 /// AUTHORITY_FILENAME is the name of the file containing the authorities.
 pub const AUTHORITY_FILENAME: &str = "authority.json";
 
+/// Size of the reads of a download.
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a redirect response body read to keep its connection.
+const MAX_DRAINED_BODY: u64 = 64 * 1024;
+
+/// Which files of the directory cache need to be downloaded.
 #[derive(PartialEq)]
-enum UpdateNeeded {
+pub(crate) enum UpdateNeeded {
     None,
     Churn,
     All,
@@ -46,62 +106,180 @@ pub const DIRECTORY_CHURN_C4DT: &str =
     "https://github.com/c4dt/lightarti-directory/releases/latest/download/churn.txt";
 
 impl Client {
-    /// Create a new client with the given cache directory and the default URLs for the tor caches.
-    pub async fn new(cache_path: &Path) -> Result<Self> {
-        Self::new_with_url(cache_path, DIRECTORY_CACHE_C4DT, DIRECTORY_CHURN_C4DT).await
+    /// Create a new client with the given cache directory and the default settings.
+    pub async fn new(cache_path: &Path) -> Result<Self, Error> {
+        Self::builder(cache_path).build().await
     }
 
     /// Create a new client with the given cache directory and URLs for the tor caches.
+    #[deprecated(note = "use Client::builder")]
     pub async fn new_with_url(
         cache_path: &Path,
         directory_cache: &str,
         churn_cache: &str,
-    ) -> Result<Self> {
-        Self::update_cache(cache_path, directory_cache, churn_cache).await?;
-
-        let runtime = Runtime::current().context("get runtime")?;
-
-        let tor_client = TorClient::with_runtime(runtime)
-            .config(Self::tor_config(cache_path).context("load config")?)
-            .dirmgr_builder::<FlatFileDirMgrBuilder>(Arc::new(FlatFileDirMgrBuilder {}))
-            .create_bootstrapped()
+    ) -> Result<Self, Error> {
+        Self::builder(cache_path)
+            .directory_cache_url(directory_cache)
+            .churn_url(churn_cache)
+            .build()
             .await
-            .context("create tor client")?;
+    }
 
-        Ok(Self(tor_client))
+    /// Configure a new client using the given cache directory.
+    pub fn builder(cache_path: impl Into<PathBuf>) -> ClientBuilder {
+        ClientBuilder::new(cache_path.into())
     }
 
-    /// Checks whether the AUTHORITY_FILENAME is present, which is needed to verify the
-    /// signatures of the other files.
-    fn check_directory(cache_path: &Path) -> Result<()> {
-        if !cache_path.is_dir() {
-            return Err(Error::CacheCorruption("directory cache does not exist").into());
+    /// Wrap a bootstrapped Tor client, with the default settings.
+    pub(crate) fn from_tor_client(
+        tor_client: TorClient<Runtime>,
+        dirmgr: Arc<dyn DirProvider>,
+        progress: ProgressSender,
+        hsdir: HsDirValidity,
+    ) -> Self {
+        Self {
+            tor_client,
+            dirmgr,
+            progress,
+            hsdir,
+            pool: Arc::new(Pool::new(PoolConfig::default())),
+            redirect_policy: RedirectPolicy::default(),
+            decompression: None,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            allow_plain_http: false,
+            tls: TlsConfig::default(),
+            isolation_policy: IsolationPolicy::default(),
+            isolator: Isolator::default(),
+            cookie_jar: None,
+            http_cache: None,
+            retry_policy: RetryPolicy::default(),
+            churn_refresh: None,
         }
-        if !cache_path.join(AUTHORITY_FILENAME).exists() {
-            debug!("required file missing: {}", AUTHORITY_FILENAME);
-            return Err(Error::CacheCorruption("required file(s) missing in cache").into());
-        }
-        Ok(())
     }
 
-    /// Returns which cache files need to be updated.
-    async fn update_cache(
+    /// Use the given configuration for the pool of idle connections.
+    pub(crate) fn with_pool_config(mut self, config: PoolConfig) -> Self {
+        self.pool = Arc::new(Pool::new(config));
+        self
+    }
+
+    /// Use the given policy to follow redirects.
+    pub(crate) fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    /// Negotiate compressed responses in [`Client::send`], and decode them with the given limits.
+    pub(crate) fn with_decompression(mut self, decompression: Decompression) -> Self {
+        self.decompression = Some(decompression);
+        self
+    }
+
+    /// Use the given policy to isolate requests on separate circuits.
+    pub(crate) fn with_isolation_policy(mut self, policy: IsolationPolicy) -> Self {
+        self.isolation_policy = policy;
+        self
+    }
+
+    /// Use the given timeouts for requests which don't set their own.
+    pub(crate) fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Use the given policy to retry requests which don't set their own.
+    pub(crate) fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Store the cookies set by servers in the given jar, and send them back with later requests.
+    pub(crate) fn with_cookie_jar(mut self, jar: Arc<CookieJar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// The cookie jar of the client, if it has one.
+    pub fn cookie_jar(&self) -> Option<&Arc<CookieJar>> {
+        self.cookie_jar.as_ref()
+    }
+
+    /// Cache the responses of [`Client::send`] in the given cache, see [`HttpCache`].
+    pub(crate) fn with_http_cache(mut self, cache: HttpCache) -> Self {
+        self.http_cache = Some(cache);
+        self
+    }
+
+    /// The HTTP cache of the client, if it has one.
+    pub fn http_cache(&self) -> Option<&HttpCache> {
+        self.http_cache.as_ref()
+    }
+
+    /// Stream of the steps of the bootstrap, starting with the current one, which is
+    /// [`BootstrapProgress::DirectoryReady`] once built. See
+    /// [`ClientBuilder::bootstrap_progress`] to follow the bootstrap.
+    pub fn bootstrap_progress(&self) -> impl Stream<Item = BootstrapProgress> + Send + 'static {
+        self.progress.subscribe()
+    }
+
+    /// Use the given limits on the responses of requests which don't set their own.
+    pub(crate) fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Allow sending requests to http:// URIs, which are not encrypted between the exit relay and
+    /// the server. They are refused by default, except for onion services, which are reached
+    /// without an exit relay.
+    pub(crate) fn with_plain_http(mut self, allow: bool) -> Self {
+        self.allow_plain_http = allow;
+        self
+    }
+
+    /// Use the given trusted roots and pinned keys to authenticate servers.
+    pub(crate) fn with_tls_config(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Refresh the churn in the background, see [`ClientBuilder::churn_refresh`].
+    pub(crate) fn with_churn_refresh(mut self, settings: ChurnRefresh) -> Self {
+        self.churn_refresh = Some(churn::spawn(settings, self.dirmgr.clone()));
+        self
+    }
+
+    /// Load the directory cache again, e.g. once a new one is downloaded, and use it for the new
+    /// circuits. Unlike building a new client, this keeps the open circuits.
+    ///
+    /// The directory in use is kept if the new one can't be loaded.
+    pub async fn reload_directory(&self) -> Result<(), Error> {
+        self.dirmgr
+            .bootstrap()
+            .await
+            .context("reload directory")
+            .map_err(Error::bootstrap)
+    }
+
+    /// Downloads the cache files which need to be updated according to the freshness policy.
+    pub(crate) async fn update_cache(
         cache_path: &Path,
         directory_cache: &str,
         churn_cache: &str,
+        freshness: &FreshnessPolicy,
     ) -> Result<()> {
-        match Self::get_cache_state(cache_path)? {
+        let downloaded = match freshness.cache_state(cache_path)? {
             UpdateNeeded::None => Ok(()),
             UpdateNeeded::Churn => Self::download_churn_file(cache_path, churn_cache).await,
-            UpdateNeeded::All => {
-                Self::download_churn_file(cache_path, churn_cache).await?;
-                Self::download_full_cache(cache_path, directory_cache)
-            }
-        }
+            UpdateNeeded::All => Self::download_churn_file(cache_path, churn_cache)
+                .await
+                .and_then(|()| Self::download_full_cache(cache_path, directory_cache)),
+        };
+        downloaded.map_err(|err| Error::Download(err.into()).into())
     }
 
     /// Downloads the churn file from the given URL.
-    async fn download_churn_file(cache_path: &Path, churn_cache: &str) -> Result<()> {
+    pub(crate) async fn download_churn_file(cache_path: &Path, churn_cache: &str) -> Result<()> {
         let churn = reqwest::get(churn_cache).await?.bytes().await?;
         let mut f = File::create(cache_path.join(CHURN_FILENAME))?;
         Ok(f.write_all(churn.as_ref())?)
@@ -113,130 +291,757 @@ impl Client {
         Ok(arkiv::Archive::download(directory_cache)?.unpack(cache_path)?)
     }
 
-    /// Returns the OffsetDateTime
-    fn get_offset_date_time(cache_path: &Path, file_name: &str) -> Result<OffsetDateTime> {
-        let sec = fs::metadata(cache_path.join(file_name))?
-            .modified()?
-            .duration_since(SystemTime::UNIX_EPOCH)?;
-        Ok(OffsetDateTime::from_unix_timestamp(sec.as_secs() as i64)?)
+    /// Send the request over Tor
+    ///
+    /// https URIs are sent over TLS, http URIs in plaintext if the client allows it, see
+    /// [`ClientBuilder::plain_http`]. Onion services (`.onion` hosts) are reached through their
+    /// descriptors, fetched from the hidden service directories of the consensus, and can be
+    /// sent plaintext requests. They fail with [`Error::OnionDirectory`] once the time period of
+    /// these directories is over, until a newer directory cache is loaded.
+    ///
+    /// Connections are kept open when the server allows it, and reused by later requests to the
+    /// same host.
+    ///
+    /// If decompression is enabled and the request doesn't set Accept-Encoding, compressed
+    /// responses are negotiated and transparently decoded.
+    ///
+    /// If the client has an [`HttpCache`], responses are served from it and stored in it, unless
+    /// the request is isolated with [`IsolationPolicy::PerRequest`] or a token. The
+    /// [`CacheStatus`] of the response is then added to its extensions.
+    pub async fn send<B: Into<RequestBody>>(
+        &self,
+        request: Request<B>,
+    ) -> Result<Response<Vec<u8>>, Error> {
+        let request = request.map(Into::into);
+        let isolation_policy = request
+            .extensions()
+            .get::<IsolationPolicy>()
+            .copied()
+            .unwrap_or(self.isolation_policy);
+        let response = match &self.http_cache {
+            Some(cache)
+                if matches!(
+                    isolation_policy,
+                    IsolationPolicy::None | IsolationPolicy::PerHost
+                ) =>
+            {
+                self.send_cached(cache, request).await
+            }
+            _ => self.fetch(request).await,
+        };
+        Ok(response?)
+    }
+
+    /// Send all the requests concurrently within the given limits, as with [`Client::send`],
+    /// and return their results in the same order.
+    ///
+    /// The requests share the circuits and the idle connections of the client as allowed by
+    /// their [`IsolationPolicy`], so that a batch of small requests to the same host only sets up
+    /// a few streams.
+    pub async fn send_all<B: Into<RequestBody>>(
+        &self,
+        requests: impl IntoIterator<Item = Request<B>>,
+        limits: BatchLimits,
+    ) -> Vec<Result<Response<Vec<u8>>, Error>> {
+        use futures::StreamExt;
+
+        let mut results = self
+            .send_all_unordered(requests, limits)
+            .collect::<Vec<_>>()
+            .await;
+        results.sort_unstable_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
     }
 
-    // Returns which files need to be updated by checking the dates of the files against
-    // the current date.
-    // This will probably fail for the first minutes of the day, when the churn is not yet
-    // available in the new version.
-    fn get_cache_state(cache_path: &Path) -> Result<UpdateNeeded> {
-        if !cache_path.is_dir() {
-            return Err(anyhow!("Corrupt cache: cache-directory doesn't exist"));
+    /// Send all the requests concurrently within the given limits, like [`Client::send_all`],
+    /// and yield their results as they complete, along with the index of their request.
+    pub fn send_all_unordered<'a, B: Into<RequestBody> + 'a>(
+        &'a self,
+        requests: impl IntoIterator<Item = Request<B>>,
+        limits: BatchLimits,
+    ) -> impl Stream<Item = (usize, Result<Response<Vec<u8>>, Error>)> + 'a {
+        let requests = requests
+            .into_iter()
+            .map(|request| {
+                let host = request.uri().host().unwrap_or_default().to_owned();
+                (host, request)
+            })
+            .collect();
+        batch::run_limited(requests, limits, move |request| self.send(request))
+    }
+
+    /// Send the request through the cache.
+    async fn send_cached(
+        &self,
+        cache: &HttpCache,
+        request: Request<RequestBody>,
+    ) -> Result<Response<Vec<u8>>> {
+        let (parts, body) = request.into_parts();
+        // Keep the request as sent by the caller, for the Vary headers and to send it again
+        // without validators.
+        let (head, _) = download::attempt_request(&parts, 0, None, false).into_parts();
+        let retry_policy = parts.extensions.get::<RetryPolicy>().copied();
+        let unconditional_body = body.try_clone();
+        let mut request = Request::from_parts(parts, body);
+
+        if !matches!(
+            head.method,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        ) {
+            let response = self.fetch(request).await?;
+            if response.status().is_success() || response.status().is_redirection() {
+                cache.invalidate(&head).await;
+            }
+            return Ok(response);
         }
-        if check_directory(cache_path).is_err() {
-            return Ok(UpdateNeeded::All);
+        let conditional = [
+            header::IF_MATCH,
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::IF_UNMODIFIED_SINCE,
+            header::IF_RANGE,
+        ]
+        .iter()
+        .any(|name| head.headers.contains_key(name));
+        if head.method != Method::GET || conditional || head.headers.contains_key(header::RANGE) {
+            return self.fetch(request).await;
         }
 
-        let now = OffsetDateTime::now_utc();
-        if Self::get_offset_date_time(cache_path, MICRODESCRIPTORS_FILENAME)?.monday_based_week()
-            != now.monday_based_week()
-        {
-            return Ok(UpdateNeeded::All);
+        let stored = match cache.lookup(&head).await {
+            Some((mut response, true)) => {
+                debug!("cache hit for {}", head.uri);
+                response.extensions_mut().insert(CacheStatus::Hit);
+                return Ok(response);
+            }
+            Some((response, false)) => {
+                let validators = http_cache::validators(&response);
+                let revalidating = !validators.is_empty();
+                request.headers_mut().extend(validators);
+                revalidating
+            }
+            None => false,
+        };
+
+        let mut request_time = unix_now();
+        let mut response = self.fetch(request).await?;
+        if response.status() == StatusCode::NOT_MODIFIED && stored {
+            if let Some(mut response) = cache.freshen(&head, &response, request_time).await {
+                debug!("revalidated cached response for {}", head.uri);
+                response.extensions_mut().insert(CacheStatus::Revalidated);
+                return Ok(response);
+            }
+            // The stored response was removed or is unreadable, so the 304 has nothing to
+            // confirm: ask for the whole response.
+            if let Some(body) = unconditional_body {
+                debug!("cached response of {} lost, fetching it again", head.uri);
+                let mut request = download::attempt_request(&head, 0, None, false).map(|_| body);
+                if let Some(policy) = retry_policy {
+                    request.extensions_mut().insert(policy);
+                }
+                request_time = unix_now();
+                response = self.fetch(request).await?;
+            }
+        }
+        // The response of a redirect is only valid for the URI which gave it.
+        let redirected = response
+            .extensions()
+            .get::<Redirects>()
+            .map_or(false, |redirects| !redirects.chain().is_empty());
+        if redirected || response.status() == StatusCode::NOT_MODIFIED {
+            cache.invalidate(&head).await;
+        } else {
+            response = cache.store(&head, response, request_time).await;
+        }
+        response.extensions_mut().insert(CacheStatus::Miss);
+        Ok(response)
+    }
+
+    /// Send the request and read the whole response, decompressing it if enabled.
+    async fn fetch(&self, mut request: Request<RequestBody>) -> Result<Response<Vec<u8>>> {
+        let decompression = match &self.decompression {
+            Some(decompression) if !request.headers().contains_key(header::ACCEPT_ENCODING) => {
+                request.headers_mut().insert(
+                    header::ACCEPT_ENCODING,
+                    HeaderValue::from_static(ACCEPT_ENCODING),
+                );
+                Some(decompression)
+            }
+            _ => None,
+        };
+
+        let (mut parts, mut body) = self.stream(request).await?.into_parts();
+
+        let mut data = Vec::new();
+        body.read_to_end(&mut data)
+            .await
+            .map_err(timeout::from_io)
+            .context("read body")?;
+        if body.is_chunked() {
+            complete_headers(&mut parts.headers, data.len(), body.trailers().clone());
+        }
+        if let Some(decompression) = decompression {
+            data = decompression
+                .decode(&mut parts, data)
+                .context("decompress body")?;
         }
+        let response = Response::from_parts(parts, data);
+
+        trace!(?response, "response");
 
-        let churn = Self::get_offset_date_time(cache_path, CHURN_FILENAME)?;
-        Ok(
-            if churn.monday_based_week() == now.monday_based_week()
-                && churn.weekday() == now.weekday()
+        Ok(response)
+    }
+
+    /// Start a SOCKS5 proxy on the given loopback address, forwarding connections over Tor with
+    /// the circuits of this client.
+    ///
+    /// Connections authenticated with a username and a password are isolated from the ones using
+    /// other credentials, as in Tor's `IsolateSOCKSAuth`.
+    pub async fn start_socks_proxy(&self, addr: SocketAddr) -> Result<Proxy, Error> {
+        Ok(socks::start(self.tor_client.clone(), addr).await?)
+    }
+
+    /// Start an HTTP proxy on the given loopback address, forwarding CONNECT tunnels and
+    /// plain-HTTP requests in absolute form over Tor with the circuits of this client.
+    pub async fn start_http_proxy(
+        &self,
+        addr: SocketAddr,
+        config: HttpProxyConfig,
+    ) -> Result<Proxy, Error> {
+        Ok(http_proxy::start(self.tor_client.clone(), addr, config).await?)
+    }
+
+    /// Download the response to the request into the file at the given path, and return its size.
+    ///
+    /// The body is written to a partial file next to it, with a `.part` suffix, which is moved to
+    /// the path once complete. If the download fails in a way that may be temporary, see
+    /// [`Error::is_retryable`], e.g. because its circuit collapsed, it is resumed on a new circuit
+    /// up to [`DownloadOptions::max_attempts`] times, with a Range request conditioned by If-Range
+    /// on the validator of the response. Responses without a strong entity tag or Last-Modified
+    /// date are downloaded again from the start. The delay between the attempts follows the
+    /// [`RetryPolicy`] of the client or the request, whose number of attempts is ignored.
+    ///
+    /// When the download still fails in a way that may be temporary, the partial file is kept
+    /// with its validator, and resumed by a later download to the same path.
+    ///
+    /// The size of the file is checked against the one announced by the server, and its SHA-256
+    /// digest against the one of the options, failing with an [`Error::Download`] of the matching
+    /// [`DownloadError`].
+    pub async fn download_to_path(
+        &self,
+        request: Request<()>,
+        path: impl AsRef<Path>,
+        options: DownloadOptions,
+    ) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let partial = download::partial_path(path);
+        let (head, ()) = request.into_parts();
+        let (mut file, mut state) = download::open_partial(&partial).await?;
+
+        // The attempts of the download are its retries, with the backoff of the retry policy.
+        let retry_policy = head
+            .extensions
+            .get::<RetryPolicy>()
+            .copied()
+            .unwrap_or(self.retry_policy);
+        let mut attempt = 1;
+        let result = loop {
+            match self
+                .download_attempt(&head, &partial, &mut file, &mut state, attempt > 1)
+                .await
             {
-                UpdateNeeded::None
-            } else {
-                UpdateNeeded::Churn
-            },
-        )
+                Ok(()) => {
+                    break self
+                        .finish_download(&partial, &state, &options)
+                        .await
+                        .map_err(Error::from)
+                }
+                Err(err) => {
+                    let err = Error::from(err);
+                    if attempt >= options.max_attempts || !err.is_retryable() {
+                        break Err(err);
+                    }
+                    let backoff = retry_policy.backoff(attempt);
+                    warn!(
+                        "download attempt {} / {} failed after {} bytes, retrying in {:?}: {:#}",
+                        attempt, options.max_attempts, state.offset, backoff, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+            }
+        };
+        drop(file);
+
+        match result {
+            Ok(()) => {
+                tokio::fs::rename(&partial, path)
+                    .await
+                    .with_context(|| format!("move download to {}", path.display()))?;
+                download::remove_partial(&partial).await;
+                Ok(state.offset)
+            }
+            // A later download may resume it.
+            Err(err) if err.is_retryable() => Err(err),
+            Err(err) => {
+                download::remove_partial(&partial).await;
+                Err(err)
+            }
+        }
     }
 
-    fn tor_config(cache_path: &Path) -> Result<TorClientConfig> {
-        let mut cfg_builder = TorClientConfig::builder();
-        Self::check_directory(cache_path)?;
-        cfg_builder
-            .storage()
-            .cache_dir(CfgPath::new_literal(cache_path))
-            .state_dir(CfgPath::new_literal(cache_path));
+    /// Send one request of a download, and append its body to the partial file.
+    async fn download_attempt(
+        &self,
+        head: &request::Parts,
+        partial: &Path,
+        file: &mut tokio::fs::File,
+        state: &mut DownloadState,
+        retry: bool,
+    ) -> Result<()> {
+        let mut request =
+            download::attempt_request(head, state.offset, state.validator.as_ref(), retry);
+        request.extensions_mut().insert(RetryPolicy::never());
+        let resuming = request.headers().contains_key(header::RANGE);
+        let mut response = self.stream(request.map(Into::into)).await?;
 
-        let auth_path = cache_path.join(AUTHORITY_FILENAME);
-        let auth_raw = fs::read_to_string(auth_path.clone())
-            .context(format!("Failed to read {}", auth_path.to_string_lossy()))?;
-        let auth = serde_json::from_str(auth_raw.as_str())?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if resuming => {
+                let range =
+                    ContentRange::of(response.headers()).context("invalid Content-Range")?;
+                if range.start != Some(state.offset) {
+                    // Start again from the beginning rather than trusting this server's ranges.
+                    state.validator = None;
+                    download::save_validator(partial, None).await?;
+                    return Err(DownloadError::Resume.into());
+                }
+                state.total = range.total.or(state.total);
+            }
+            StatusCode::OK => {
+                if state.offset > 0 {
+                    debug!("restarting download of {}", head.uri);
+                    file.set_len(0).await.context("truncate partial file")?;
+                    file.rewind().await.context("rewind partial file")?;
+                    state.offset = 0;
+                }
+                state.total = response.body().content_length();
+                state.validator = download::validator(response.headers());
+                download::save_validator(partial, state.validator.as_ref()).await?;
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE
+                if resuming
+                    && ContentRange::of(response.headers())
+                        .map_or(false, |range| range.total == Some(state.offset)) =>
+            {
+                // The partial file was already complete.
+                return Ok(());
+            }
+            status => return Err(DownloadError::Status(status).into()),
+        }
+
+        let body = response.body_mut();
+        let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+        loop {
+            let read = body
+                .read(&mut buf)
+                .await
+                .map_err(timeout::from_io)
+                .context("read body")?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read])
+                .await
+                .context("write partial file")?;
+            state.offset += read as u64;
+        }
+        file.flush().await.context("write partial file")
+    }
 
-        cfg_builder.tor_network().set_authorities(vec![auth]);
-        // Overriding authorities requires also overriding fallback caches
-        cfg_builder.tor_network().set_fallback_caches(Vec::new());
+    /// Check the size and the digest of a complete download.
+    async fn finish_download(
+        &self,
+        partial: &Path,
+        state: &DownloadState,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        if let Some(expected) = state.total {
+            if expected != state.offset {
+                return Err(DownloadError::Size {
+                    expected,
+                    actual: state.offset,
+                }
+                .into());
+            }
+        }
+        if let Some(expected) = options.sha256 {
+            let mut file = tokio::fs::File::open(partial)
+                .await
+                .context("open partial file")?;
+            let mut hasher = Sha256::new();
+            let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+            loop {
+                let read = file.read(&mut buf).await.context("read partial file")?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            if hasher.finalize().as_slice() != expected {
+                return Err(DownloadError::Digest.into());
+            }
+        }
+        Ok(())
+    }
 
-        cfg_builder.build().context("build config")
+    /// Send the request over Tor, and return the response as soon as its head was read.
+    ///
+    /// The body is read from the connection while it is consumed, so that large responses don't
+    /// have to fit in memory. Likewise, the request body can be streamed from a [`RequestBody`].
+    ///
+    /// Redirects are followed according to the [`RedirectPolicy`] of the client, or the one in
+    /// the request's extensions. The [`Redirects`] followed are added to the response's
+    /// extensions.
+    ///
+    /// Each step of the request is limited by the [`Timeouts`] of the client, or the ones in the
+    /// request's extensions, and fails with the matching [`Error::Timeout`]. A
+    /// [`CancellationToken`] in the request's extensions aborts the request with
+    /// [`Error::Cancelled`] once cancelled, closing its connection. The total timeout and the
+    /// cancellation also cover reading the body, which then fails with an I/O error wrapping a
+    /// [`Timeout`] or [`Cancelled`].
+    ///
+    /// Streams are isolated according to the [`IsolationPolicy`] of the client, or the one in the
+    /// request's extensions. So are the cookies of the client's [`CookieJar`], if it has one.
+    ///
+    /// Requests failing because of the Tor network are retried on new circuits according to the
+    /// [`RetryPolicy`] of the client, or the one in the request's extensions.
+    ///
+    /// The response is checked against the [`Limits`] of the client, or the ones in the request's
+    /// extensions, while it is read, and fails with the matching [`LimitExceeded`] error.
+    ///
+    /// [`Cancelled`]: crate::Cancelled
+    pub async fn send_streaming<B: Into<RequestBody>>(
+        &self,
+        request: Request<B>,
+    ) -> Result<Response<Body>, Error> {
+        Ok(self.stream(request.map(Into::into)).await?)
     }
 
-    /// Send the request over Tor
-    pub async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>> {
+    /// Send the request, and return the response as soon as its head was read, see
+    /// [`Client::send_streaming`].
+    async fn stream(&self, request: Request<RequestBody>) -> Result<Response<Body>> {
         trace!(?request, "request");
 
-        // TODO drop check
-        if request.version() != http::Version::HTTP_10 {
-            bail!("only supports HTTP version 1.0")
+        let (mut parts, body) = request.into_parts();
+        let timeouts = parts
+            .extensions
+            .get::<Timeouts>()
+            .copied()
+            .unwrap_or(self.timeouts);
+        let abort = Abort::new(
+            timeouts.total,
+            parts.extensions.get::<CancellationToken>().cloned(),
+        );
+        let isolation_policy = parts
+            .extensions
+            .get::<IsolationPolicy>()
+            .copied()
+            .unwrap_or(self.isolation_policy);
+        let settings = RequestSettings {
+            timeouts,
+            limits: parts
+                .extensions
+                .get::<Limits>()
+                .copied()
+                .unwrap_or(self.limits),
+            isolation: self
+                .isolator
+                .token(isolation_policy, parts.uri.host().unwrap_or_default()),
+            circuit: None,
+            retry: parts
+                .extensions
+                .get::<RetryPolicy>()
+                .copied()
+                .unwrap_or(self.retry_policy),
+            pooled: self.pool.is_enabled() && isolation_policy != IsolationPolicy::PerRequest,
+        };
+
+        let response = abort
+            .guard(self.follow_redirects(&mut parts, body, &settings))
+            .await;
+        if let (Some(jar), IsolationPolicy::PerRequest, Some(token)) =
+            (&self.cookie_jar, isolation_policy, settings.isolation)
+        {
+            // No other request uses the token of this one.
+            jar.forget(token);
+        }
+        let mut response = response?;
+        response.body_mut().set_abort(abort);
+        Ok(response)
+    }
+
+    /// Send the request, following redirects according to its policy.
+    async fn follow_redirects(
+        &self,
+        parts: &mut request::Parts,
+        mut body: RequestBody,
+        settings: &RequestSettings,
+    ) -> Result<Response<Body>> {
+        let policy = parts
+            .extensions
+            .get::<RedirectPolicy>()
+            .copied()
+            .unwrap_or(self.redirect_policy);
+        let mut redirects = Redirects::new(parts.uri.clone());
+        let first_party = parts.uri.host().unwrap_or_default().to_owned();
+        let mut jar_cookies = false;
+
+        loop {
+            if let Some(jar) = &self.cookie_jar {
+                // The cookies of the jar are chosen again for each URI of the redirects.
+                if jar_cookies {
+                    parts.headers.remove(header::COOKIE);
+                }
+                jar_cookies = false;
+                if !parts.headers.contains_key(header::COOKIE) {
+                    if let Some(cookies) =
+                        jar.header(settings.isolation, &parts.uri, &parts.method, &first_party)
+                    {
+                        parts.headers.insert(header::COOKIE, cookies);
+                        jar_cookies = true;
+                    }
+                }
+            }
+
+            let mut response = self.send_once(parts, &mut body, settings).await?;
+            if let Some(jar) = &self.cookie_jar {
+                jar.store(settings.isolation, &parts.uri, response.headers());
+            }
+
+            let location = match response.headers().get(header::LOCATION) {
+                Some(location)
+                    if is_redirect(response.status())
+                        && redirects.chain().len() < policy.max_redirects() =>
+                {
+                    location.clone()
+                }
+                _ => {
+                    response.extensions_mut().insert(redirects);
+                    return Ok(response);
+                }
+            };
+            if !redirect::follow(
+                parts,
+                &mut body,
+                &mut redirects,
+                response.status(),
+                &location,
+            )? {
+                response.extensions_mut().insert(redirects);
+                return Ok(response);
+            }
+
+            // Reading a short body lets the connection go back to the pool.
+            let mut redirect_body = response.into_body().take(MAX_DRAINED_BODY);
+            let _ = tokio::io::copy(&mut redirect_body, &mut tokio::io::sink()).await;
         }
+    }
 
-        let raw_host = request.uri().host().context("no host found")?;
-        let tls_host = rustls::ServerName::try_from(raw_host).context("invalid host")?;
+    /// Send the request once, without following redirects.
+    async fn send_once(
+        &self,
+        parts: &mut request::Parts,
+        body: &mut RequestBody,
+        settings: &RequestSettings,
+    ) -> Result<Response<Body>> {
+        if parts.version != Version::HTTP_10 && parts.version != Version::HTTP_11 {
+            bail!("only supports HTTP versions 1.0 and 1.1")
+        }
 
-        let tor_stream = self
-            .0
-            .connect((raw_host, request.uri().port_u16().unwrap_or(443)))
-            .await
-            .context("tor connect")?;
+        let scheme = Scheme::of(&parts.uri)?;
+        let raw_host = parts.uri.host().context("no host found")?.to_owned();
+        let port = parts
+            .uri
+            .port_u16()
+            .unwrap_or_else(|| scheme.default_port());
+        if !scheme.is_tls() && !self.allow_plain_http && !is_onion(&raw_host) {
+            bail!(
+                "refusing plaintext request to {}, it would be visible to the exit relay",
+                raw_host
+            );
+        }
+        let key = PoolKey::new(scheme, &raw_host, port, settings.isolation);
 
-        let mut tls_stream = Self::with_tls_stream(tls_host, tor_stream)
-            .await
-            .context("wrap in TLS")?;
+        if !parts.headers.contains_key(header::HOST) {
+            let host = match parts.uri.port() {
+                Some(port) => format!("{}:{}", raw_host, port),
+                None => raw_host.clone(),
+            };
+            parts.headers.insert(
+                header::HOST,
+                HeaderValue::try_from(host).context("invalid host header")?,
+            );
+        }
 
-        let raw_request = request_to_raw(request).context("serialize request")?;
+        if settings.pooled && !parts.headers.contains_key(header::CONNECTION) {
+            parts
+                .headers
+                .insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+        }
+        let raw_head = request_to_raw(parts, body.length()).context("serialize request")?;
 
-        tls_stream
-            .write_all(&raw_request)
+        if let Some(connection) = settings.pooled.then(|| self.pool.checkout(&key)).flatten() {
+            debug!("reusing connection to {}", raw_host);
+            match self
+                .exchange(connection, &raw_head, body, parts, &key, settings)
+                .await
+            {
+                Ok(response) => return Ok(response),
+                // The server may have closed the connection while we were sending the request.
+                Err(err)
+                    if parts.method.is_idempotent()
+                        && body.is_replayable()
+                        && !err.is::<Timeout>() =>
+                {
+                    debug!("reused connection failed, retrying: {:#}", err)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let replayable = body.is_replayable()
+            && (parts.method.is_idempotent() || settings.retry.retry_non_idempotent);
+        let mut settings = *settings;
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let connection = self.connect(scheme, &raw_host, port, &settings).await?;
+                self.exchange(connection, &raw_head, body, parts, &key, &settings)
+                    .await
+            }
+            .await;
+            match result {
+                Err(err)
+                    if replayable
+                        && attempt < settings.retry.max_attempts
+                        && retry::is_retryable(&err) =>
+                {
+                    let backoff = settings.retry.backoff(attempt);
+                    debug!(
+                        "attempt {} / {} failed, retrying on a new circuit in {:?}: {:#}",
+                        attempt, settings.retry.max_attempts, backoff, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    // The connection of a new circuit is not worth keeping for other requests.
+                    settings.circuit = Some(IsolationToken::new());
+                    settings.pooled = false;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Open a new connection to the given host over Tor, wrapped in TLS if the scheme requires it.
+    async fn connect(
+        &self,
+        scheme: Scheme,
+        raw_host: &str,
+        port: u16,
+        settings: &RequestSettings,
+    ) -> Result<Connection> {
+        let tls_host = if scheme.is_tls() {
+            Some(ServerName::try_from(raw_host).context("invalid host")?)
+        } else {
+            None
+        };
+
+        let mut prefs = StreamPrefs::new();
+        match (settings.isolation, settings.circuit) {
+            (Some(token), Some(circuit)) => prefs.set_isolation((token, circuit)),
+            (Some(token), None) | (None, Some(token)) => prefs.set_isolation(token),
+            (None, None) => &mut prefs,
+        };
+        if is_onion(raw_host) {
+            self.hsdir.check(SystemTime::now())?;
+            prefs.connect_to_onion_services(BoolOrAuto::Explicit(true));
+        }
+        let tor_stream = limit(settings.timeouts.connect, Timeout::Connect, async {
+            self.tor_client
+                .connect_with_prefs((raw_host, port), &prefs)
+                .await
+                .context("tor connect")
+                .map_err(|err| Error::tor_connect(err).into())
+        })
+        .await?;
+
+        let tls_host = match tls_host {
+            Some(tls_host) => tls_host,
+            None => return Ok(Connection::Plain(tor_stream)),
+        };
+        let tls_stream = limit(settings.timeouts.handshake, Timeout::Handshake, async {
+            self.with_tls_stream(raw_host, tls_host, tor_stream)
+                .await
+                .context("wrap in TLS")
+                .map_err(|err| Error::tls(err).into())
+        })
+        .await?;
+        Ok(Connection::Tls(Box::new(tls_stream)))
+    }
+
+    /// Write the request to the stream and read the head of its response.
+    ///
+    /// The body of the response takes over the stream, and returns it to the pool once read if
+    /// the server allows it.
+    async fn exchange(
+        &self,
+        mut connection: Connection,
+        raw_head: &[u8],
+        body: &mut RequestBody,
+        request: &request::Parts,
+        key: &PoolKey,
+        settings: &RequestSettings,
+    ) -> Result<Response<Body>> {
+        connection
+            .write_all(raw_head)
             .await
             .context("write request")?;
-        tls_stream.flush().await.context("flush")?;
+        body.write_to(&mut connection).await?;
+        connection.flush().await.context("flush")?;
 
-        let mut raw_response = Vec::new();
-        let read_response = tls_stream.read_to_end(&mut raw_response).await;
-
-        if let Err(ref err) = read_response {
-            if err.kind() == io::ErrorKind::UnexpectedEof {
-                // see rustls/rustls#b84721ef0d72e7f2747105f6b76a6bcbb8aa0ea4
-                warn!("server didn't close TLS stream")
-            } else {
-                read_response.context("read response")?;
+        let mut raw = Vec::new();
+        let head = limit(
+            settings.timeouts.first_byte,
+            Timeout::FirstByte,
+            read_head(&mut connection, &mut raw, &settings.limits),
+        )
+        .await?;
+        let framing =
+            Framing::of(&request.method, &head).map_err(|err| Error::HttpParse(err.into()))?;
+        if let (Framing::Length(length), Some(max)) = (framing, settings.limits.max_body_size) {
+            if length > max {
+                return Err(LimitExceeded::BodySize.into());
             }
         }
-        let response = raw_to_response(raw_response)?;
+        let release = (settings.pooled && is_persistent(request.version, &head))
+            .then(|| (self.pool.clone(), key.clone()));
 
-        trace!(?response, "response");
+        let (parts, _) = head.into_parts();
+        trace!(?parts, "response head");
 
-        Ok(response)
+        let body = Body::new(
+            BodyReader::new(connection, framing, raw, settings.limits),
+            release,
+        );
+        Ok(Response::from_parts(parts, body))
     }
 
     async fn with_tls_stream(
+        &self,
+        raw_host: &str,
         host: ServerName,
         tor_stream: DataStream,
     ) -> Result<TlsStream<DataStream>> {
-        let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-
-        let tls_config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+        let tls_config = self.tls.client_config(raw_host)?;
 
         TlsConnector::from(Arc::new(tls_config))
             .connect(host, tor_stream)
@@ -248,6 +1053,7 @@ impl Client {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::check_directory;
 
     fn setup_tracing() {
         // dropping error as many tests can setup_tracing
@@ -272,9 +1078,16 @@ mod tests {
         setup_tracing();
 
         let tmp = tempfile::tempdir().expect("Creating tempdir");
-        assert!(Client::get_cache_state(tmp.path())? == UpdateNeeded::All);
-        Client::update_cache(tmp.path(), DIRECTORY_CACHE_C4DT, DIRECTORY_CHURN_C4DT).await?;
-        assert!(Client::get_cache_state(tmp.path())? == UpdateNeeded::None);
+        let freshness = FreshnessPolicy::default();
+        assert!(freshness.cache_state(tmp.path())? == UpdateNeeded::All);
+        Client::update_cache(
+            tmp.path(),
+            DIRECTORY_CACHE_C4DT,
+            DIRECTORY_CHURN_C4DT,
+            &freshness,
+        )
+        .await?;
+        assert!(freshness.cache_state(tmp.path())? == UpdateNeeded::None);
         Ok(())
     }
 }
@@ -0,0 +1,120 @@
+//! Connections to servers over Tor, with or without TLS depending on the URI scheme.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Result};
+use arti_client::DataStream;
+use http::Uri;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::client::TlsStream;
+
+/// Scheme of a request URI, deciding whether the connection uses TLS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    /// Scheme of the URI, which defaults to https when it has none.
+    pub fn of(uri: &Uri) -> Result<Self> {
+        match uri.scheme_str() {
+            None => Ok(Self::Https),
+            Some(scheme) if scheme.eq_ignore_ascii_case("https") => Ok(Self::Https),
+            Some(scheme) if scheme.eq_ignore_ascii_case("http") => Ok(Self::Http),
+            Some(scheme) => bail!("unsupported scheme {}", scheme),
+        }
+    }
+
+    /// Port used when the URI doesn't give one.
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::Http => 80,
+            Self::Https => 443,
+        }
+    }
+
+    /// Whether the connection is wrapped in TLS.
+    pub fn is_tls(self) -> bool {
+        self == Self::Https
+    }
+}
+
+/// Whether the host is an onion service, reached through Tor without an exit relay.
+pub(crate) fn is_onion(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+    host.len() > ".onion".len() && host.ends_with(".onion")
+}
+
+/// A stream to a server, either plaintext or wrapped in TLS.
+pub(crate) enum Connection {
+    Plain(DataStream),
+    Tls(Box<TlsStream<DataStream>>),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheme() -> Result<()> {
+        let http: Uri = "http://example.com:8080/".parse()?;
+        assert_eq!(Scheme::of(&http)?, Scheme::Http);
+        assert_eq!(Scheme::of(&"HTTPS://example.com".parse()?)?, Scheme::Https);
+        assert_eq!(Scheme::Http.default_port(), 80);
+        assert!(Scheme::of(&"ftp://example.com".parse()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_onion() {
+        assert!(is_onion(
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+        ));
+        assert!(is_onion("www.example.ONION."));
+        assert!(!is_onion(".onion"));
+        assert!(!is_onion("onion.example.com"));
+    }
+}
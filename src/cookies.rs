@@ -0,0 +1,830 @@
+//! Storage of the cookies set by servers, and their sending with later requests, see RFC 6265.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use arti_client::IsolationToken;
+use http::{header, HeaderMap, HeaderValue, Method, Uri};
+use serde::{Deserialize, Serialize};
+use time::{Date, Month};
+use tracing::{debug, warn};
+
+use crate::connection::{is_onion, Scheme};
+use crate::Error;
+
+/// COOKIES_FILENAME is the name of the file where a persistent [`CookieJar`] is conventionally
+/// kept in the cache directory.
+pub const COOKIES_FILENAME: &str = "cookies.json";
+
+/// Whether a cookie is sent with requests initiated from another site, see RFC 6265bis section
+/// 4.1.2.7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie set by a server, see RFC 6265 section 5.3.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    /// Whether the cookie is only sent to the host which set it, and not to its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    /// Expiry in seconds since the Unix epoch, `None` for a cookie lasting as long as the client.
+    expires: Option<u64>,
+    /// Order of creation, used to sort the cookies sent.
+    created: u64,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+
+    fn matches(&self, host: &str, path: &str, secure: bool) -> bool {
+        let domain_matches = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(host, &self.domain)
+        };
+        domain_matches && path_match(path, &self.path) && (secure || !self.secure)
+    }
+}
+
+/// Cookies of the requests using one isolation token.
+type Partition = Vec<Cookie>;
+
+#[derive(Debug, Default)]
+struct Store {
+    /// Cookies by isolation token of the requests, `None` for the default isolation.
+    partitions: BTreeMap<Option<IsolationToken>, Partition>,
+    next_created: u64,
+    /// Number of changes of the persistent cookies, to save only the latest ones.
+    generation: u64,
+}
+
+/// File where the persistent cookies of the default isolation are saved.
+#[derive(Debug)]
+struct CookieFile {
+    path: PathBuf,
+    /// Generation of the cookies last written, which also serializes the writes.
+    written: Mutex<u64>,
+}
+
+impl CookieFile {
+    /// Write the cookies, unless newer ones were already written.
+    fn write(&self, generation: u64, cookies: &Partition) {
+        let mut written = self.written.lock().expect("cookie file lock poisoned");
+        if *written >= generation {
+            return;
+        }
+        let tmp = self.path.with_extension("tmp");
+        match serde_json::to_vec(cookies)
+            .context("serialize cookies")
+            .and_then(|raw| fs::write(&tmp, raw).context("write cookies"))
+            .and_then(|_| fs::rename(&tmp, &self.path).context("replace cookies"))
+        {
+            Ok(()) => *written = generation,
+            Err(err) => warn!(
+                "unable to save cookies to {}: {:#}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+/// Cookies received by a [`crate::Client`], sent back with its later requests.
+///
+/// Cookies follow RFC 6265: they are scoped by domain and path, and honor the Secure, HttpOnly,
+/// SameSite, Expires and Max-Age attributes. The site of a request is approximated by the last
+/// two labels of its host, as no public suffix list is available. For the same reason, a Domain
+/// attribute of less than three labels is ignored: the cookie is only sent back to the host which
+/// set it, never to the other hosts under a possible public suffix.
+///
+/// Cookies are kept apart by the isolation token of the requests, see
+/// [`crate::IsolationPolicy`], so that they never link requests made for different identities.
+/// Only the cookies of requests using the default isolation can be persisted, as isolation tokens
+/// don't outlive the client. The cookies of a request isolated with
+/// [`crate::IsolationPolicy::PerRequest`] only last until its redirects are followed.
+///
+/// A request which already has a Cookie header is sent as is.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    store: Mutex<Store>,
+    file: Option<Arc<CookieFile>>,
+}
+
+impl CookieJar {
+    /// Create an empty cookie jar, kept in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a cookie jar saved to the given file, loading the cookies it already contains.
+    ///
+    /// The cookies without an expiry date are not saved, as they only last as long as the
+    /// client. Within a Tokio runtime, the file is written in the background, on its blocking
+    /// threads.
+    pub fn persistent(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let mut store = Store::default();
+        if path.exists() {
+            let raw = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            let mut cookies: Partition = serde_json::from_slice(&raw)
+                .with_context(|| format!("parse cookies of {}", path.display()))?;
+            let now = unix_now();
+            cookies.retain(|cookie| !cookie.is_expired(now));
+            store.next_created = cookies.iter().map(|c| c.created + 1).max().unwrap_or(0);
+            store.partitions.insert(None, cookies);
+        }
+
+        Ok(Self {
+            store: Mutex::new(store),
+            file: Some(Arc::new(CookieFile {
+                path,
+                written: Mutex::new(0),
+            })),
+        })
+    }
+
+    /// Names and values of the cookies of the default isolation that would be sent to the given
+    /// URI, except the HttpOnly ones.
+    pub fn cookies(&self, uri: &Uri) -> Vec<(String, String)> {
+        self.matching(None, uri, None)
+            .into_iter()
+            .filter(|cookie| !cookie.http_only)
+            .map(|cookie| (cookie.name, cookie.value))
+            .collect()
+    }
+
+    /// Remove all the cookies.
+    pub fn clear(&self) {
+        self.lock().partitions.clear();
+        self.save();
+    }
+
+    /// Value of the Cookie header of a request, which is part of a navigation started at
+    /// `first_party`.
+    pub(crate) fn header(
+        &self,
+        isolation: Option<IsolationToken>,
+        uri: &Uri,
+        method: &Method,
+        first_party: &str,
+    ) -> Option<HeaderValue> {
+        let cookies = self.matching(isolation, uri, Some((method, first_party)));
+        if cookies.is_empty() {
+            return None;
+        }
+        let value = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        HeaderValue::try_from(value)
+            .map_err(|err| warn!("invalid Cookie header: {}", err))
+            .ok()
+    }
+
+    /// Store the cookies set by the response to a request.
+    pub(crate) fn store(&self, isolation: Option<IsolationToken>, uri: &Uri, headers: &HeaderMap) {
+        let host = match uri.host() {
+            Some(host) => canonical_host(host),
+            None => return,
+        };
+        let secure_origin = is_secure(uri, &host);
+        let now = unix_now();
+
+        let mut changed = false;
+        {
+            let mut store = self.lock();
+            for value in headers.get_all(header::SET_COOKIE) {
+                let cookie = match value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| parse_set_cookie(value, &host, uri.path(), now))
+                {
+                    Some(cookie) => cookie,
+                    None => {
+                        debug!("ignoring invalid Set-Cookie from {}", host);
+                        continue;
+                    }
+                };
+                if cookie.secure && !secure_origin {
+                    debug!("ignoring secure cookie {} set over plaintext", cookie.name);
+                    continue;
+                }
+                if cookie.same_site == Some(SameSite::None) && !cookie.secure {
+                    debug!(
+                        "ignoring cookie {} with SameSite=None but not Secure",
+                        cookie.name
+                    );
+                    continue;
+                }
+
+                let created = store.next_created;
+                store.next_created += 1;
+                let partition = store.partitions.entry(isolation).or_default();
+                insert(partition, cookie, created, now);
+                changed |= isolation.is_none();
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    /// Remove the cookies of the requests using the isolation token, once no more request can use
+    /// it.
+    pub(crate) fn forget(&self, isolation: IsolationToken) {
+        self.lock().partitions.remove(&Some(isolation));
+    }
+
+    /// Cookies to send to the URI, sorted as they appear in the Cookie header. SameSite is
+    /// checked if the method and first party of the request are given.
+    fn matching(
+        &self,
+        isolation: Option<IsolationToken>,
+        uri: &Uri,
+        navigation: Option<(&Method, &str)>,
+    ) -> Vec<Cookie> {
+        let host = match uri.host() {
+            Some(host) => canonical_host(host),
+            None => return Vec::new(),
+        };
+        let secure = is_secure(uri, &host);
+        let path = if uri.path().is_empty() {
+            "/"
+        } else {
+            uri.path()
+        };
+        let cross_site = navigation.map(|(method, first_party)| {
+            (method, site(&host) != site(&canonical_host(first_party)))
+        });
+        let now = unix_now();
+
+        let mut store = self.lock();
+        let partition = match store.partitions.get_mut(&isolation) {
+            Some(partition) => partition,
+            None => return Vec::new(),
+        };
+        partition.retain(|cookie| !cookie.is_expired(now));
+
+        let mut cookies = partition
+            .iter()
+            .filter(|cookie| cookie.matches(&host, path, secure))
+            .filter(|cookie| match (cookie.same_site, cross_site) {
+                (Some(SameSite::Strict), Some((_, true))) => false,
+                (Some(SameSite::Lax), Some((method, true))) => method.is_safe(),
+                _ => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        cookies.sort_by(|a, b| {
+            b.path
+                .len()
+                .cmp(&a.path.len())
+                .then(a.created.cmp(&b.created))
+        });
+        cookies
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().expect("cookie jar lock poisoned")
+    }
+
+    /// Save the persistent cookies of the default isolation, if the jar has a file.
+    fn save(&self) {
+        let file = match &self.file {
+            Some(file) => Arc::clone(file),
+            None => return,
+        };
+        let (generation, cookies) = {
+            let mut store = self.lock();
+            store.generation += 1;
+            let cookies: Partition = store
+                .partitions
+                .get(&None)
+                .map(|cookies| {
+                    cookies
+                        .iter()
+                        .filter(|cookie| cookie.expires.is_some())
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            (store.generation, cookies)
+        };
+
+        // Don't block the async tasks storing the cookies of their responses.
+        let write = move || file.write(generation, &cookies);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Add the cookie to the partition, replacing the one with the same name, domain and path.
+fn insert(partition: &mut Partition, mut cookie: Cookie, created: u64, now: u64) {
+    let old = partition.iter().position(|old| {
+        old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path
+    });
+    cookie.created = match old {
+        Some(index) => partition.remove(index).created,
+        None => created,
+    };
+    if !cookie.is_expired(now) {
+        partition.push(cookie);
+    }
+}
+
+/// Parse a Set-Cookie header received from the host for the given request path, see RFC 6265
+/// section 5.2.
+fn parse_set_cookie(value: &str, host: &str, request_path: &str, now: u64) -> Option<Cookie> {
+    let mut parts = value.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie = Cookie {
+        name: name.to_owned(),
+        value: value.trim().to_owned(),
+        domain: host.to_owned(),
+        host_only: true,
+        path: default_path(request_path),
+        secure: false,
+        http_only: false,
+        same_site: None,
+        expires: None,
+        created: 0,
+    };
+    let mut max_age = None;
+    let mut expires = None;
+
+    for attribute in parts {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "expires" => expires = parse_cookie_date(value).or(expires),
+            "max-age" => max_age = parse_max_age(value, now).or(max_age),
+            "domain" => {
+                let domain = value
+                    .strip_prefix('.')
+                    .unwrap_or(value)
+                    .to_ascii_lowercase();
+                if domain.is_empty() {
+                    continue;
+                }
+                if !domain_match(host, &domain) {
+                    return None;
+                }
+                // Without a public suffix list, a domain of less than three labels may be a
+                // public suffix such as co.uk or github.io: keep the cookie for this host only.
+                if domain.split('.').count() < 3 {
+                    continue;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" => {
+                cookie.path = if value.starts_with('/') {
+                    value.to_owned()
+                } else {
+                    default_path(request_path)
+                }
+            }
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "samesite" => {
+                cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                    "strict" => Some(SameSite::Strict),
+                    "lax" => Some(SameSite::Lax),
+                    "none" => Some(SameSite::None),
+                    _ => cookie.same_site,
+                }
+            }
+            _ => {}
+        }
+    }
+    // Max-Age takes precedence over Expires.
+    cookie.expires = max_age.or(expires);
+
+    Some(cookie)
+}
+
+/// Expiry of a Max-Age attribute, see RFC 6265 section 5.2.2.
+fn parse_max_age(value: &str, now: u64) -> Option<u64> {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(match value.parse::<i64>() {
+        Ok(delta) if delta > 0 => now.saturating_add(delta as u64),
+        // Too large to be parsed, or not positive.
+        Ok(_) => 0,
+        Err(_) if value.starts_with('-') => 0,
+        Err(_) => u64::MAX,
+    })
+}
+
+/// Parse a date of an Expires attribute, in seconds since the Unix epoch, see RFC 6265 section
+/// 5.1.1.
+///
+/// This also accepts the three HTTP-date formats of RFC 9110 section 5.6.7.
+pub(crate) fn parse_cookie_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let is_delimiter = |c: char| matches!(c, '\t' | ' '..='/' | ';'..='@' | '['..='`' | '{'..='~');
+
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    for token in value.split(is_delimiter).filter(|token| !token.is_empty()) {
+        if time.is_none() {
+            if let Some(parsed) = parse_time(token) {
+                time = Some(parsed);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some(parsed) = leading_number(token, 1, 2) {
+                day = Some(parsed);
+                continue;
+            }
+        }
+        if month.is_none() {
+            let prefix = token.get(..3).unwrap_or_default().to_ascii_lowercase();
+            if let Some(index) = MONTHS.iter().position(|month| *month == prefix) {
+                month = Some(index as u8 + 1);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some(parsed) = leading_number(token, 2, 4) {
+                year = Some(parsed);
+            }
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let year = match year? {
+        year @ 70..=99 => year + 1900,
+        year @ 0..=69 => year + 2000,
+        year => year,
+    };
+    if year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let date = Date::from_calendar_date(
+        year as i32,
+        Month::try_from(month?).ok()?,
+        u8::try_from(day?).ok()?,
+    )
+    .ok()?;
+    let timestamp = date
+        .with_hms(hour as u8, minute as u8, second as u8)
+        .ok()?
+        .assume_utc()
+        .unix_timestamp();
+    Some(timestamp.max(0) as u64)
+}
+
+/// Parse a `hh:mm:ss` time token, whose seconds can be followed by anything but a digit.
+fn parse_time(token: &str) -> Option<(u32, u32, u32)> {
+    let mut fields = token.splitn(3, ':');
+    let hour = fields.next()?;
+    let minute = fields.next()?;
+    let second = fields.next()?;
+    if leading_digits(hour) != hour.len() || leading_digits(minute) != minute.len() {
+        return None;
+    }
+    Some((
+        leading_number(hour, 1, 2)?,
+        leading_number(minute, 1, 2)?,
+        leading_number(second, 1, 2)?,
+    ))
+}
+
+/// The number at the start of the token, if it has between `min` and `max` digits.
+fn leading_number(token: &str, min: usize, max: usize) -> Option<u32> {
+    let digits = leading_digits(token);
+    if digits < min || digits > max {
+        return None;
+    }
+    token[..digits].parse().ok()
+}
+
+fn leading_digits(token: &str) -> usize {
+    token.bytes().take_while(u8::is_ascii_digit).count()
+}
+
+/// Default path of a cookie, the directory of the request path, see RFC 6265 section 5.1.4.
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(index) if request_path.starts_with('/') && index > 0 => {
+            request_path[..index].to_owned()
+        }
+        _ => "/".to_owned(),
+    }
+}
+
+/// Whether the host is matched by the domain of a cookie, see RFC 6265 section 5.1.3.
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<IpAddr>().is_err())
+}
+
+/// Whether the request path is matched by the path of a cookie, see RFC 6265 section 5.1.4.
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Whether the request is protected from the network, so that it can carry Secure cookies.
+fn is_secure(uri: &Uri, host: &str) -> bool {
+    Scheme::of(uri).map_or(false, Scheme::is_tls) || is_onion(host)
+}
+
+/// Site of a host, approximated by its last two labels.
+fn site(host: &str) -> &str {
+    if host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    match host.rmatch_indices('.').nth(1) {
+        Some((index, _)) => &host[index + 1..],
+        None => host,
+    }
+}
+
+fn canonical_host(host: &str) -> String {
+    host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_cookies(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn uri(uri: &str) -> Uri {
+        uri.parse().expect("valid URI")
+    }
+
+    fn header_of(jar: &CookieJar, isolation: Option<IsolationToken>, to: &str) -> Option<String> {
+        let to = uri(to);
+        let host = to.host().unwrap_or_default().to_owned();
+        jar.header(isolation, &to, &Method::GET, &host)
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn test_parse_cookie_date() {
+        let expected = Some(1_445_412_480);
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 07:28:00 GMT"), expected);
+        assert_eq!(
+            parse_cookie_date("Wednesday, 21-Oct-15 07:28:00 GMT"),
+            expected
+        );
+        assert_eq!(parse_cookie_date("Wed Oct 21 07:28:00 2015"), expected);
+        assert_eq!(parse_cookie_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_cookie_date("Wed, 31 Feb 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 25:28:00 GMT"), None);
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+    }
+
+    #[test]
+    fn test_parse_set_cookie() {
+        let cookie = parse_set_cookie(
+            "id=a3fWa; Domain=.Example.co.uk; Path=/docs; Secure; HttpOnly; SameSite=Lax; \
+             Expires=Wed, 21 Oct 2015 07:28:00 GMT; Max-Age=60",
+            "www.example.co.uk",
+            "/",
+            1000,
+        )
+        .expect("valid cookie");
+        assert_eq!(
+            (cookie.name.as_str(), cookie.value.as_str()),
+            ("id", "a3fWa")
+        );
+        assert_eq!(cookie.domain, "example.co.uk");
+        assert!(!cookie.host_only);
+        assert_eq!(cookie.path, "/docs");
+        assert!(cookie.secure && cookie.http_only);
+        assert_eq!(cookie.same_site, Some(SameSite::Lax));
+        assert_eq!(cookie.expires, Some(1060));
+
+        let cookie = parse_set_cookie("id=1; Path=docs", "example.com", "/a/b/c", 0).unwrap();
+        assert!(cookie.host_only);
+        assert_eq!(cookie.path, "/a/b");
+        assert_eq!(cookie.expires, None);
+
+        assert!(parse_set_cookie("id=1; Domain=example.org", "example.com", "/", 0).is_none());
+        for (domain, host) in [
+            ("com", "example.com"),
+            ("co.uk", "www.example.co.uk"),
+            ("github.io", "user.github.io"),
+            ("example.com", "www.example.com"),
+        ] {
+            let cookie = parse_set_cookie(&format!("id=1; Domain={}", domain), host, "/", 0)
+                .expect("host-only cookie");
+            assert!(cookie.host_only);
+            assert_eq!(cookie.domain, host);
+        }
+        assert!(parse_set_cookie("=1", "example.com", "/", 0).is_none());
+        assert!(parse_set_cookie("no-value", "example.com", "/", 0).is_none());
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(domain_match("www.example.com", "example.com"));
+        assert!(!domain_match("wwwexample.com", "example.com"));
+        assert!(path_match("/docs/web", "/docs"));
+        assert!(path_match("/docs/", "/docs/"));
+        assert!(!path_match("/docsets", "/docs"));
+        assert_eq!(site("www.example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_jar() {
+        let jar = CookieJar::new();
+        let token = IsolationToken::new();
+        jar.store(
+            None,
+            &uri("https://www.shop.example.com/login"),
+            &set_cookies(&[
+                "session=1; Secure; HttpOnly",
+                "lang=en; Domain=shop.example.com; Path=/",
+                "old=1; Max-Age=0",
+            ]),
+        );
+        jar.store(
+            Some(token),
+            &uri("https://www.shop.example.com/"),
+            &set_cookies(&["session=2"]),
+        );
+
+        assert_eq!(
+            header_of(&jar, None, "https://www.shop.example.com/account").as_deref(),
+            Some("session=1; lang=en")
+        );
+        // Host-only and Secure cookies.
+        assert_eq!(
+            header_of(&jar, None, "https://api.shop.example.com/").as_deref(),
+            Some("lang=en")
+        );
+        assert_eq!(
+            header_of(&jar, None, "http://www.shop.example.com/").as_deref(),
+            Some("lang=en")
+        );
+        // Isolated requests only see their own cookies.
+        assert_eq!(
+            header_of(&jar, Some(token), "https://www.shop.example.com/").as_deref(),
+            Some("session=2")
+        );
+        assert_eq!(
+            header_of(
+                &jar,
+                Some(IsolationToken::new()),
+                "https://www.shop.example.com/"
+            ),
+            None
+        );
+        // HttpOnly cookies are not exposed.
+        assert_eq!(
+            jar.cookies(&uri("https://www.shop.example.com/")),
+            vec![("lang".to_owned(), "en".to_owned())]
+        );
+
+        jar.store(
+            None,
+            &uri("http://www.shop.example.com/"),
+            &set_cookies(&["plain=1; Secure", "lang=fr; Domain=shop.example.com"]),
+        );
+        assert_eq!(
+            header_of(&jar, None, "https://www.shop.example.com/").as_deref(),
+            Some("session=1; lang=fr")
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let jar = CookieJar::new();
+        let token = IsolationToken::new();
+        for isolation in [None, Some(token)] {
+            jar.store(
+                isolation,
+                &uri("https://example.com/"),
+                &set_cookies(&["id=1"]),
+            );
+        }
+
+        jar.forget(token);
+        assert_eq!(header_of(&jar, Some(token), "https://example.com/"), None);
+        assert_eq!(
+            header_of(&jar, None, "https://example.com/").as_deref(),
+            Some("id=1")
+        );
+        assert_eq!(jar.lock().partitions.len(), 1);
+    }
+
+    #[test]
+    fn test_same_site() {
+        let jar = CookieJar::new();
+        jar.store(
+            None,
+            &uri("https://example.com/"),
+            &set_cookies(&["strict=1; SameSite=Strict", "lax=1; SameSite=Lax", "none=1"]),
+        );
+        let cross_site = |method: &Method| {
+            jar.header(None, &uri("https://example.com/"), method, "example.org")
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        assert_eq!(cross_site(&Method::GET).as_deref(), Some("lax=1; none=1"));
+        assert_eq!(cross_site(&Method::POST).as_deref(), Some("none=1"));
+    }
+
+    #[test]
+    fn test_persistent() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join(COOKIES_FILENAME);
+
+        let jar = CookieJar::persistent(&path)?;
+        jar.store(
+            None,
+            &uri("https://example.com/"),
+            &set_cookies(&["kept=1; Max-Age=3600", "session=1"]),
+        );
+        jar.store(
+            Some(IsolationToken::new()),
+            &uri("https://example.com/"),
+            &set_cookies(&["isolated=1; Max-Age=3600"]),
+        );
+
+        let jar = CookieJar::persistent(&path)?;
+        assert_eq!(
+            header_of(&jar, None, "https://example.com/").as_deref(),
+            Some("kept=1")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_persistent_background() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join(COOKIES_FILENAME);
+
+        let jar = CookieJar::persistent(&path)?;
+        for value in ["1", "2", "3"] {
+            jar.store(
+                None,
+                &uri("https://example.com/"),
+                &set_cookies(&[&format!("kept={}; Max-Age=3600", value)]),
+            );
+        }
+
+        // The last cookies are saved, whatever the order of the writes.
+        for _ in 0..100 {
+            let saved = CookieJar::persistent(&path)?;
+            if header_of(&saved, None, "https://example.com/").as_deref() == Some("kept=3") {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("cookies not saved");
+    }
+}
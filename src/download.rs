@@ -0,0 +1,352 @@
+//! Downloads to a file, resumed with range requests when they fail.
+
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use http::{header, request, HeaderMap, HeaderValue, Request};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncSeekExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::{IsolationPolicy, IsolationToken, Limits, RedirectPolicy, Timeouts};
+
+/// Default number of attempts of a download.
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Settings of [`crate::Client::download_to_path`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// Number of requests made before giving up, including the first one. Only the failures for
+    /// which [`crate::Error::is_retryable`] holds are retried, after the backoff of the
+    /// [`crate::RetryPolicy`] of the client or the request.
+    pub max_attempts: u32,
+    /// Expected SHA-256 digest of the downloaded file, checked before it is moved to its path.
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            sha256: None,
+        }
+    }
+}
+
+/// Error returned when a download can't complete, apart from the errors of its requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownloadError {
+    /// The server answered with a status which is neither 200 (OK) nor 206 (Partial Content).
+    Status(http::StatusCode),
+    /// The downloaded file doesn't have the size announced by the server.
+    Size {
+        /// Size announced by the server.
+        expected: u64,
+        /// Size of the downloaded file.
+        actual: u64,
+    },
+    /// The downloaded file doesn't have the expected SHA-256 digest.
+    Digest,
+    /// The server didn't resume the download where it stopped. The next attempt starts again from
+    /// the beginning.
+    Resume,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "unexpected download status: {}", status),
+            Self::Size { expected, actual } => write!(
+                f,
+                "downloaded {} bytes instead of {} bytes",
+                actual, expected
+            ),
+            Self::Digest => f.write_str("downloaded file doesn't match its SHA-256 digest"),
+            Self::Resume => f.write_str("partial content doesn't resume the download"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// Progress of a download, kept across its attempts.
+#[derive(Debug, Default)]
+pub(crate) struct DownloadState {
+    /// Number of bytes written to the partial file.
+    pub offset: u64,
+    /// Size of the complete file, if announced by the server.
+    pub total: Option<u64>,
+    /// Validator of the representation being downloaded.
+    pub validator: Option<HeaderValue>,
+}
+
+/// Range of a 206 (Partial Content) response, see RFC 9110 section 14.4.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ContentRange {
+    /// First byte of the range, `None` for an unsatisfied range.
+    pub start: Option<u64>,
+    /// Size of the complete representation, if known.
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+        let (unit, range) = value.trim().split_once(' ')?;
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let (range, total) = range.trim().split_once('/')?;
+        let total = match total {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        let start = match range {
+            "*" => None,
+            range => {
+                let (start, end) = range.split_once('-')?;
+                let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+                if end < start || total.map_or(false, |total| end >= total) {
+                    return None;
+                }
+                Some(start)
+            }
+        };
+        Some(Self { start, total })
+    }
+}
+
+/// Validator of the downloaded representation, to resume it only if it didn't change.
+///
+/// Weak entity tags can't be used in If-Range, see RFC 9110 section 13.1.5.
+pub(crate) fn validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers
+        .get(header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(header::LAST_MODIFIED))
+        .cloned()
+}
+
+/// Path of the partial file of a download.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part")
+}
+
+/// Path of the file keeping the validator of a partial file, to resume it in a later download.
+fn validator_path(partial: &Path) -> PathBuf {
+    with_suffix(partial, ".validator")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Open the partial file of a download, positioned at its end if it can be resumed with its
+/// validator, or emptied otherwise.
+pub(crate) async fn open_partial(partial: &Path) -> Result<(File, DownloadState)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(partial)
+        .await
+        .with_context(|| format!("open {}", partial.display()))?;
+    let validator = tokio::fs::read(validator_path(partial))
+        .await
+        .ok()
+        .and_then(|raw| HeaderValue::from_bytes(&raw).ok());
+
+    let mut state = DownloadState::default();
+    match validator {
+        Some(validator) => {
+            state.offset = file
+                .seek(SeekFrom::End(0))
+                .await
+                .context("seek partial file")?;
+            state.validator = Some(validator);
+        }
+        None => file.set_len(0).await.context("truncate partial file")?,
+    }
+    Ok((file, state))
+}
+
+/// Keep the validator of the partial file, or forget it.
+pub(crate) async fn save_validator(partial: &Path, validator: Option<&HeaderValue>) -> Result<()> {
+    let path = validator_path(partial);
+    match validator {
+        Some(validator) => tokio::fs::write(&path, validator.as_bytes())
+            .await
+            .context("write validator"),
+        None => match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).context("remove validator")
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Remove the partial file of a download, and its validator.
+pub(crate) async fn remove_partial(partial: &Path) {
+    tokio::fs::remove_file(partial).await.ok();
+    tokio::fs::remove_file(validator_path(partial)).await.ok();
+}
+
+/// Request for an attempt of a download, resuming it from `offset` if it has a validator.
+///
+/// The overrides in the extensions of the original request are kept. Attempts after the first one
+/// are isolated with a new token, so that they don't use the circuit which failed.
+pub(crate) fn attempt_request(
+    head: &request::Parts,
+    offset: u64,
+    validator: Option<&HeaderValue>,
+    retry: bool,
+) -> Request<Vec<u8>> {
+    let mut request = Request::new(Vec::new());
+    *request.method_mut() = head.method.clone();
+    *request.uri_mut() = head.uri.clone();
+    *request.version_mut() = head.version;
+    *request.headers_mut() = head.headers.clone();
+    if let (true, Some(validator)) = (offset > 0, validator) {
+        request.headers_mut().insert(
+            header::RANGE,
+            HeaderValue::try_from(format!("bytes={}-", offset)).expect("valid header value"),
+        );
+        request
+            .headers_mut()
+            .insert(header::IF_RANGE, validator.clone());
+    }
+
+    let extensions = request.extensions_mut();
+    if let Some(timeouts) = head.extensions.get::<Timeouts>() {
+        extensions.insert(*timeouts);
+    }
+    if let Some(limits) = head.extensions.get::<Limits>() {
+        extensions.insert(*limits);
+    }
+    if let Some(policy) = head.extensions.get::<RedirectPolicy>() {
+        extensions.insert(*policy);
+    }
+    if let Some(cancel) = head.extensions.get::<CancellationToken>() {
+        extensions.insert(cancel.clone());
+    }
+    if retry {
+        extensions.insert(IsolationPolicy::Token(IsolationToken::new()));
+    } else if let Some(policy) = head.extensions.get::<IsolationPolicy>() {
+        extensions.insert(*policy);
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_range(value: &str) -> Option<ContentRange> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_RANGE, value.parse().unwrap());
+        ContentRange::of(&headers)
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(
+            content_range("bytes 100-199/1000"),
+            Some(ContentRange {
+                start: Some(100),
+                total: Some(1000)
+            })
+        );
+        assert_eq!(
+            content_range("bytes 100-199/*"),
+            Some(ContentRange {
+                start: Some(100),
+                total: None
+            })
+        );
+        assert_eq!(
+            content_range("bytes */1000"),
+            Some(ContentRange {
+                start: None,
+                total: Some(1000)
+            })
+        );
+        assert_eq!(content_range("bytes 100-1000/1000"), None);
+        assert_eq!(content_range("bytes 200-100/1000"), None);
+        assert_eq!(content_range("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn test_attempt_request() {
+        let mut head = Request::get("https://example.com/file")
+            .header("x-custom", "1")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        head.extensions.insert(IsolationPolicy::PerHost);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("W/\"weak\""));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_static("Thu, 01 Jan 2015 00:00:00 GMT"),
+        );
+        let validator = validator(&headers);
+        assert_eq!(validator.as_ref().unwrap(), "Thu, 01 Jan 2015 00:00:00 GMT");
+
+        let first = attempt_request(&head, 0, validator.as_ref(), false);
+        assert!(first.headers().get(header::RANGE).is_none());
+        assert_eq!(first.headers()["x-custom"], "1");
+        assert_eq!(
+            first.extensions().get::<IsolationPolicy>(),
+            Some(&IsolationPolicy::PerHost)
+        );
+
+        let retry = attempt_request(&head, 100, validator.as_ref(), true);
+        assert_eq!(retry.headers()[header::RANGE], "bytes=100-");
+        assert_eq!(retry.headers()[header::IF_RANGE], validator.unwrap());
+        assert!(matches!(
+            retry.extensions().get::<IsolationPolicy>(),
+            Some(IsolationPolicy::Token(_))
+        ));
+
+        let unvalidated = attempt_request(&head, 100, None, true);
+        assert!(unvalidated.headers().get(header::RANGE).is_none());
+    }
+
+    #[test]
+    fn test_partial_path() {
+        assert_eq!(
+            partial_path(Path::new("/tmp/file.tgz")),
+            Path::new("/tmp/file.tgz.part")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_partial() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let partial = partial_path(&tmp.path().join("file"));
+        let validator = HeaderValue::from_static("\"1\"");
+
+        tokio::fs::write(&partial, b"0123").await?;
+        let (_, state) = open_partial(&partial).await?;
+        assert_eq!(state.offset, 0);
+        assert_eq!(tokio::fs::read(&partial).await?, b"");
+
+        tokio::fs::write(&partial, b"0123").await?;
+        save_validator(&partial, Some(&validator)).await?;
+        let (_, state) = open_partial(&partial).await?;
+        assert_eq!(state.offset, 4);
+        assert_eq!(state.validator, Some(validator));
+
+        save_validator(&partial, None).await?;
+        save_validator(&partial, None).await?;
+        remove_partial(&partial).await;
+        assert!(!partial.exists());
+        Ok(())
+    }
+}
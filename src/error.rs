@@ -0,0 +1,291 @@
+//! Errors of the public API.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use arti_client::HasKind;
+use tor_cell::relaycell::msg::EndReason;
+
+use crate::{retry, Cancelled, DownloadError, LimitExceeded, Timeout};
+
+/// Underlying error of an [`Error`], with the context of the failure.
+type Source = Box<dyn StdError + Send + Sync>;
+
+/// Error returned by [`crate::Client`] and the other types of this crate.
+///
+/// The variants are stable, so that callers can handle each kind of failure without looking at
+/// the messages, which may change. The underlying error carries the details, and is displayed in
+/// place of the variant: use the alternate format, `{:#}`, to display all of its causes.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The cache directory or some of the files of the directory cache don't exist.
+    CacheMissing(Source),
+    /// The directory cache is outdated, e.g. its consensus expired.
+    CacheStale(Source),
+    /// The directory cache can't be read or verified.
+    CacheCorrupt(Source),
+    /// A download failed: the directory cache, the churn, or a
+    /// [`crate::Client::download_to_path`], in which case the underlying error may be a
+    /// [`DownloadError`].
+    Download(Source),
+    /// The Tor client couldn't bootstrap from the directory cache.
+    Bootstrap(Source),
+    /// Tor couldn't open the stream to the server.
+    TorConnect {
+        /// Reason given by the exit relay or the onion service for closing the stream, if it did.
+        end_reason: Option<EndReason>,
+        /// Underlying error.
+        source: Source,
+    },
+    /// The TLS handshake with the server failed, e.g. because its certificate is not trusted.
+    Tls(Source),
+    /// The connection to the server failed once established, e.g. it closed unexpectedly.
+    Connection(Source),
+    /// The response is not valid HTTP.
+    HttpParse(Source),
+    /// The directory in use can't find onion services, e.g. because its consensus is from an
+    /// earlier time period of the onion service directories. A newer directory cache is needed.
+    OnionDirectory(Source),
+    /// A step of the request timed out.
+    Timeout(Timeout),
+    /// The request was cancelled.
+    Cancelled,
+    /// The response exceeded its [`crate::Limits`].
+    LimitExceeded(LimitExceeded),
+    /// Any other failure, e.g. an invalid request or configuration, or a local I/O error.
+    Other(Source),
+}
+
+impl Error {
+    /// Whether the failure is likely to be temporary, so that trying again, possibly on other
+    /// circuits, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::CacheMissing(_)
+            | Self::CacheStale(_)
+            | Self::CacheCorrupt(_)
+            | Self::Tls(_)
+            | Self::HttpParse(_)
+            | Self::OnionDirectory(_)
+            | Self::Cancelled
+            | Self::LimitExceeded(_)
+            | Self::Other(_) => false,
+            Self::Download(source) => match source.downcast_ref::<DownloadError>() {
+                Some(DownloadError::Status(status)) => status.is_server_error(),
+                Some(DownloadError::Size { .. } | DownloadError::Resume) => true,
+                Some(DownloadError::Digest) => false,
+                None => true,
+            },
+            Self::TorConnect { source, .. } => chain(source.as_ref())
+                .find_map(|cause| cause.downcast_ref::<arti_client::Error>())
+                .map_or(true, |err| retry::is_retryable_kind(err.kind())),
+            Self::Bootstrap(_) | Self::Connection(_) | Self::Timeout(_) => true,
+        }
+    }
+
+    /// Error of a failure to bootstrap the Tor client, telling apart the problems of the
+    /// directory cache.
+    pub(crate) fn bootstrap(err: anyhow::Error) -> Self {
+        let dirmgr_err = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<tor_dirmgr::Error>());
+        match dirmgr_err {
+            Some(tor_dirmgr::Error::UntimelyObject(_)) => Self::CacheStale(err.into()),
+            Some(
+                tor_dirmgr::Error::CacheCorruption(_)
+                | tor_dirmgr::Error::BadHexInCache(_)
+                | tor_dirmgr::Error::UnrecognizedAuthorities
+                | tor_dirmgr::Error::DirectoryNotPresent,
+            ) => Self::CacheCorrupt(err.into()),
+            _ => Self::Bootstrap(err.into()),
+        }
+    }
+
+    /// Error of a failure to open a stream through Tor, with the END reason of the stream.
+    pub(crate) fn tor_connect(err: anyhow::Error) -> Self {
+        let end_reason =
+            err.chain()
+                .find_map(|cause| match cause.downcast_ref::<tor_proto::Error>() {
+                    Some(tor_proto::Error::EndReceived(reason)) => Some(*reason),
+                    _ => None,
+                });
+        Self::TorConnect {
+            end_reason,
+            source: err.into(),
+        }
+    }
+
+    /// Error of a failure to set up TLS, which can also be the stream closing during the
+    /// handshake.
+    pub(crate) fn tls(err: anyhow::Error) -> Self {
+        if err
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(is_connection_error)
+        {
+            Self::Connection(err.into())
+        } else {
+            Self::Tls(err.into())
+        }
+    }
+
+    fn source_ref(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::CacheMissing(source)
+            | Self::CacheStale(source)
+            | Self::CacheCorrupt(source)
+            | Self::Download(source)
+            | Self::Bootstrap(source)
+            | Self::TorConnect { source, .. }
+            | Self::Tls(source)
+            | Self::Connection(source)
+            | Self::HttpParse(source)
+            | Self::OnionDirectory(source)
+            | Self::Other(source) => Some(source.as_ref()),
+            Self::Timeout(_) | Self::Cancelled | Self::LimitExceeded(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(timeout) => fmt::Display::fmt(timeout, f),
+            Self::Cancelled => fmt::Display::fmt(&Cancelled, f),
+            Self::LimitExceeded(limit) => fmt::Display::fmt(limit, f),
+            _ => {
+                let source = self.source_ref().expect("has a source");
+                write!(f, "{}", source)?;
+                if f.alternate() {
+                    for cause in chain(source).skip(1) {
+                        write!(f, ": {}", cause)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        // The underlying error is displayed in place of this one.
+        self.source_ref().and_then(StdError::source)
+    }
+}
+
+/// Classify the errors which weren't given a variant where they happened.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        if err.chain().any(|cause| cause.is::<Cancelled>()) {
+            return Self::Cancelled;
+        }
+        if let Some(timeout) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Timeout>())
+        {
+            return Self::Timeout(*timeout);
+        }
+        if let Some(limit) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<LimitExceeded>())
+        {
+            return Self::LimitExceeded(*limit);
+        }
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DownloadError>() {
+            Ok(download) => return Self::Download(Box::new(download)),
+            Err(err) => err,
+        };
+
+        if err.chain().any(|cause| cause.is::<arti_client::Error>()) {
+            Self::tor_connect(err)
+        } else if err
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(is_connection_error)
+        {
+            Self::Connection(err.into())
+        } else {
+            Self::Other(err.into())
+        }
+    }
+}
+
+/// Whether the I/O error comes from the connection closing or failing.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// The error and its causes.
+fn chain<'a>(
+    err: &'a (dyn StdError + 'static),
+) -> impl Iterator<Item = &'a (dyn StdError + 'static)> {
+    std::iter::successors(Some(err), |err| (*err).source())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn test_from_anyhow() {
+        let timeout = Err::<(), _>(anyhow::Error::from(Timeout::Connect)).context("connect");
+        assert!(matches!(
+            Error::from(timeout.unwrap_err()),
+            Error::Timeout(Timeout::Connect)
+        ));
+
+        let tagged = anyhow::Error::from(Error::HttpParse("no status".into())).context("exchange");
+        let err = Error::from(tagged);
+        assert!(matches!(err, Error::HttpParse(_)));
+        assert_eq!(err.to_string(), "no status");
+
+        let eof = anyhow::Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let err = Error::from(eof.context("write request"));
+        assert!(matches!(err, Error::Connection(_)));
+        assert!(err.is_retryable());
+
+        let err = Error::from(anyhow!("invalid host"));
+        assert!(matches!(err, Error::Other(_)));
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_display() -> Result<()> {
+        let err = Error::tls(anyhow!("invalid certificate").context("wrap in TLS"));
+        assert!(matches!(err, Error::Tls(_)));
+        assert_eq!(err.to_string(), "wrap in TLS");
+        assert_eq!(format!("{:#}", err), "wrap in TLS: invalid certificate");
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(err).context("send")),
+            "send: wrap in TLS: invalid certificate"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let download = |err: DownloadError| Error::Download(Box::new(err));
+        assert!(download(DownloadError::Status(http::StatusCode::BAD_GATEWAY)).is_retryable());
+        assert!(!download(DownloadError::Status(http::StatusCode::NOT_FOUND)).is_retryable());
+        assert!(!download(DownloadError::Digest).is_retryable());
+        assert!(download(DownloadError::Resume).is_retryable());
+
+        assert!(Error::Timeout(Timeout::Total).is_retryable());
+        assert!(!Error::Cancelled.is_retryable());
+        assert!(!Error::CacheStale("expired consensus".into()).is_retryable());
+    }
+}
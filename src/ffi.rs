@@ -5,5 +5,7 @@ mod ios;
 
 #[cfg(any(target_os = "android", target_os = "ios"))]
 mod structs;
+#[cfg(target_os = "ios")]
+pub(self) use structs::ErrorKind;
 #[cfg(any(target_os = "android", target_os = "ios"))]
-pub(self) use structs::{Request, Response, RuntimeAndClient};
+pub(self) use structs::{FfiError, ProxyAndThread, Request, Response, RuntimeAndClient};
@@ -2,13 +2,14 @@ use std::{mem::ManuallyDrop, ops::Deref, path::Path, ptr};
 
 use anyhow::{Context, Result};
 use jni::{
-    objects::{JClass, JObject, JString},
-    sys::{jbyteArray, jlong, jobject},
+    objects::{JClass, JObject, JString, JThrowable, JValue},
+    sys::{jbyteArray, jint, jlong, jobject},
     JNIEnv,
 };
 use tracing::{info, log::Level};
 
-use super::{Request, Response, RuntimeAndClient};
+use super::{FfiError, ProxyAndThread, Request, Response, RuntimeAndClient};
+use crate::Client;
 
 mod conv;
 
@@ -73,6 +74,99 @@ pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_create(
     })
 }
 
+/// Create a Client with pinned keys and additional root certificates
+///
+/// `pins_j` is a `Map<String, List<byte[]>>` of SHA-256 hashes of the SubjectPublicKeyInfo, by
+/// hostname. `roots_j` is a `List<byte[]>` of DER-encoded certificates. Either can be null, for
+/// none.
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_createWithTls(
+    env: JNIEnv,
+    _: JClass,
+    cache_dir_j: JString,
+    pins_j: JObject,
+    roots_j: JObject,
+) -> jlong {
+    throw_on_err(env, 0, || {
+        let cache_dir_javastr = env
+            .get_string(cache_dir_j)
+            .context("create rust string for `cache_dir_j`")?;
+        let cache_dir = cache_dir_javastr
+            .deref()
+            .to_str()
+            .context("rust string from java")
+            .map(Path::new)?;
+        let tls = conv::tls_config(env, pins_j, roots_j).context("TLS config from java")?;
+
+        RuntimeAndClient::new_with_tls(cache_dir, tls)
+            .context("create runtime and client")
+            .map(Into::into)
+    })
+}
+
+/// Create a new Client with a cookie jar persisted in the cache directory
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_createWithCookies(
+    env: JNIEnv,
+    _: JClass,
+    cache_dir_j: JString,
+) -> jlong {
+    throw_on_err(env, 0, || {
+        let cache_dir_javastr = env
+            .get_string(cache_dir_j)
+            .context("create rust string for `cache_dir_j`")?;
+        let cache_dir = cache_dir_javastr
+            .deref()
+            .to_str()
+            .context("rust string from java")
+            .map(Path::new)?;
+
+        RuntimeAndClient::new_with_cookies(cache_dir)
+            .context("create runtime and client")
+            .map(Into::into)
+    })
+}
+
+/// Create a new Client, calling `listener_j.onProgress(float fraction, String step)` with the
+/// steps of the bootstrap, on the calling thread
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_createWithProgress(
+    env: JNIEnv,
+    _: JClass,
+    cache_dir_j: JString,
+    listener_j: JObject,
+) -> jlong {
+    throw_on_err(env, 0, || {
+        let cache_dir_javastr = env
+            .get_string(cache_dir_j)
+            .context("create rust string for `cache_dir_j`")?;
+        let cache_dir = cache_dir_javastr
+            .deref()
+            .to_str()
+            .context("rust string from java")
+            .map(Path::new)?;
+
+        RuntimeAndClient::with_progress(Client::builder(cache_dir), |progress| {
+            let step = env
+                .new_string(progress.to_string())
+                .context("create java string")?;
+            env.call_method(
+                listener_j,
+                "onProgress",
+                "(FLjava/lang/String;)V",
+                &[
+                    JValue::Float(progress.fraction()),
+                    JValue::Object(step.into()),
+                ],
+            )
+            .context("call onProgress")?;
+            Ok(())
+        })
+        .context("create runtime and client")
+        .map(Into::into)
+    })
+}
+
 /// Send a request with the given Client
 #[no_mangle]
 pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_send(
@@ -102,6 +196,68 @@ pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_send(
     })
 }
 
+/// Start a SOCKS5 proxy forwarding connections with the given Client, on the given loopback
+/// port, 0 for any. The Client must not be freed before the proxy is stopped.
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_startSocksProxy(
+    env: JNIEnv,
+    _: JClass,
+    java_client: jlong,
+    port_j: jint,
+) -> jlong {
+    throw_on_err(env, 0, || {
+        let rt_and_client = RuntimeAndClient::from(java_client);
+        let port = u16::try_from(port_j).context("invalid port")?;
+
+        ProxyAndThread::start(&rt_and_client, port).map(Into::into)
+    })
+}
+
+/// Start an HTTP proxy forwarding CONNECT tunnels and plain-HTTP requests with the given Client,
+/// on the given loopback port, 0 for any. Only the hosts of the given list are allowed, all of
+/// them if it is null. The Client must not be freed before the proxy is stopped.
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_startHttpProxy(
+    env: JNIEnv,
+    _: JClass,
+    java_client: jlong,
+    port_j: jint,
+    allowed_hosts_j: JObject,
+) -> jlong {
+    throw_on_err(env, 0, || {
+        let rt_and_client = RuntimeAndClient::from(java_client);
+        let port = u16::try_from(port_j).context("invalid port")?;
+        let allowed_hosts = if allowed_hosts_j.is_null() {
+            None
+        } else {
+            Some(conv::string_list(env, allowed_hosts_j)?)
+        };
+
+        ProxyAndThread::start_http(&rt_and_client, port, allowed_hosts).map(Into::into)
+    })
+}
+
+/// Return the port the given proxy listens on
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_proxyPort(
+    _: JNIEnv,
+    _: JClass,
+    java_proxy: jlong,
+) -> jint {
+    ProxyAndThread::from(java_proxy).port().into()
+}
+
+/// Stop the given proxy of the given Client, and free it
+#[no_mangle]
+pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_stopProxy(
+    _: JNIEnv,
+    _: JClass,
+    java_client: jlong,
+    java_proxy: jlong,
+) {
+    ProxyAndThread::from(java_proxy).stop(&RuntimeAndClient::from(java_client));
+}
+
 /// Free the given Client
 #[no_mangle]
 pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_free(
@@ -114,7 +270,28 @@ pub unsafe extern "system" fn Java_org_c4dt_artiwrapper_Client_free(
 
 fn throw_on_err<T>(env: JNIEnv, default: T, act: impl FnOnce() -> Result<T>) -> T {
     act().unwrap_or_else(|e| {
-        let _ = env.throw((TOR_LIB_EXCEPTION, format!("{:#}", e)));
+        throw(env, FfiError::from(e));
         default
     })
 }
+
+/// Throw a `TorLibException(String message, int kind, boolean retryable)`, where `kind` is the
+/// value of the [`ErrorKind`](super::ErrorKind), or only its message if the exception has no such constructor.
+fn throw(env: JNIEnv, err: FfiError) {
+    let thrown = env.new_string(&err.message).and_then(|message| {
+        let exception = env.new_object(
+            TOR_LIB_EXCEPTION,
+            "(Ljava/lang/String;IZ)V",
+            &[
+                JValue::Object(message.into()),
+                JValue::Int(err.kind as jint),
+                JValue::Bool(err.retryable.into()),
+            ],
+        )?;
+        env.throw(JThrowable::from(exception))
+    });
+    if thrown.is_err() {
+        let _ = env.exception_clear();
+        let _ = env.throw((TOR_LIB_EXCEPTION, err.message));
+    }
+}
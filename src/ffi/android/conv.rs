@@ -1,6 +1,6 @@
-use std::mem::ManuallyDrop;
+use std::{convert::TryFrom, mem::ManuallyDrop, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use http::{Uri, Version};
 use jni::{
     objects::{JList, JMap, JObject, JString, JValue},
@@ -10,13 +10,13 @@ use jni::{
 use tokio::runtime::Runtime;
 use tracing::trace;
 
-use super::{Request, Response, RuntimeAndClient};
-use crate::Client;
+use super::{ProxyAndThread, Request, Response, RuntimeAndClient};
+use crate::{Client, Proxy, TlsConfig};
 
 impl From<jlong> for RuntimeAndClient {
     fn from(java_ptr: jlong) -> Self {
         Self(ManuallyDrop::new(unsafe {
-            Box::from_raw(java_ptr as *mut (Runtime, Client))
+            Box::from_raw(java_ptr as *mut (Arc<Runtime>, Client))
         }))
     }
 }
@@ -27,6 +27,83 @@ impl From<RuntimeAndClient> for jlong {
     }
 }
 
+impl From<jlong> for ProxyAndThread {
+    fn from(java_ptr: jlong) -> Self {
+        Self(ManuallyDrop::new(unsafe {
+            Box::from_raw(java_ptr as *mut (Proxy, std::thread::JoinHandle<()>))
+        }))
+    }
+}
+
+impl From<ProxyAndThread> for jlong {
+    fn from(proxy: ProxyAndThread) -> Self {
+        Box::into_raw(ManuallyDrop::into_inner(proxy.0)) as jlong
+    }
+}
+
+/// Build a [`TlsConfig`] from pinned SPKI hashes by hostname, as a `Map<String, List<byte[]>>`,
+/// and DER-encoded root certificates, as a `List<byte[]>`. A null map or list is empty.
+pub fn tls_config(env: JNIEnv, pins_j: JObject, roots_j: JObject) -> Result<TlsConfig> {
+    let mut tls = TlsConfig::default();
+
+    if !pins_j.is_null() {
+        add_pins(env, pins_j, &mut tls)?;
+    }
+    if !roots_j.is_null() {
+        add_roots(env, roots_j, &mut tls)?;
+    }
+
+    Ok(tls)
+}
+
+/// Add the pinned SPKI hashes by hostname of a `Map<String, List<byte[]>>`.
+fn add_pins(env: JNIEnv, pins_j: JObject, tls: &mut TlsConfig) -> Result<()> {
+    let pins_jmap: JMap = env.get_map(pins_j).context("create JMap")?;
+    for (host, hash_list) in pins_jmap.iter().context("create JMap iterator")? {
+        let host: String = env
+            .get_string(JString::from(host))
+            .context("create rust string for host")?
+            .into();
+
+        let hash_jlist: JList = env.get_list(hash_list).context("create JList")?;
+        for hash in hash_jlist.iter().context("create JList iterator")? {
+            let hash = env
+                .convert_byte_array(hash.into_raw())
+                .context("create byte array")?;
+            let hash =
+                <[u8; 32]>::try_from(hash).map_err(|_| anyhow!("SPKI hash is not 32 bytes"))?;
+            tls.add_pin(&host, hash);
+        }
+    }
+    Ok(())
+}
+
+/// Add the DER-encoded root certificates of a `List<byte[]>`.
+fn add_roots(env: JNIEnv, roots_j: JObject, tls: &mut TlsConfig) -> Result<()> {
+    let roots_jlist: JList = env.get_list(roots_j).context("create JList")?;
+    for root in roots_jlist.iter().context("create JList iterator")? {
+        let root = env
+            .convert_byte_array(root.into_raw())
+            .context("create byte array")?;
+        tls.add_root_certificate(&root)?;
+    }
+    Ok(())
+}
+
+/// Build a list of strings from a `List<String>`.
+pub fn string_list(env: JNIEnv, list_j: JObject) -> Result<Vec<String>> {
+    let jlist: JList = env.get_list(list_j).context("create JList")?;
+    jlist
+        .iter()
+        .context("create JList iterator")?
+        .map(|string| {
+            env.get_string(JString::from(string))
+                .context("create rust string")
+                .map(Into::into)
+        })
+        .collect()
+}
+
 impl Request {
     /// Deserialize a request coming from Java
     pub fn from_java(
@@ -57,7 +134,7 @@ impl Request {
             .method(method.as_bytes())
             .header("Host", host)
             .uri(uri)
-            .version(Version::HTTP_10);
+            .version(Version::HTTP_11);
 
         let headers_jmap: JMap = env.get_map(headers_j).context("create JMap")?;
 
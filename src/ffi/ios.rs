@@ -1,15 +1,18 @@
-use std::{borrow::Cow, convert::TryInto, mem::ManuallyDrop, path::Path};
+use std::{borrow::Cow, convert::TryInto, ffi::c_void, mem::ManuallyDrop, path::Path};
 
 use anyhow::{Context, Result};
 use core_foundation::{
+    array::CFArrayRef,
     base::TCFType,
+    dictionary::CFDictionaryRef,
     string::{CFString, CFStringRef},
 };
 
 mod conv;
 mod structs;
 
-use super::{Request, Response, RuntimeAndClient};
+use super::{ProxyAndThread, Request, Response, RuntimeAndClient};
+use crate::Client;
 
 /// Setup the logger
 #[no_mangle]
@@ -37,6 +40,76 @@ pub unsafe extern "C" fn client_new(cache_dir_ref: CFStringRef) -> structs::Resu
     .into()
 }
 
+/// Create a new [`RuntimeAndClient`] with pinned keys and additional root certificates, returns
+/// its address
+///
+/// `pins` is a CFDictionary<CFString, CFArray<CFData>> of SHA-256 hashes of the
+/// SubjectPublicKeyInfo, by hostname. `roots` is a CFArray<CFData> of DER-encoded certificates.
+/// Either can be null, for none.
+#[no_mangle]
+pub unsafe extern "C" fn client_new_with_tls(
+    cache_dir_ref: CFStringRef,
+    pins: CFDictionaryRef,
+    roots: CFArrayRef,
+) -> structs::Result<isize> {
+    {
+        let cache_dir_ios = CFString::wrap_under_get_rule(cache_dir_ref);
+        let cache_dir_raw: Cow<_> = (&cache_dir_ios).into();
+        let cache_dir = Path::new(cache_dir_raw.as_ref());
+
+        conv::tls_config(pins, roots)
+            .context("TLS config from iOS")
+            .and_then(|tls| RuntimeAndClient::new_with_tls(cache_dir, tls))
+            .context("create runtime and client")
+            .map(Into::into)
+    }
+    .into()
+}
+
+/// Create a new [`RuntimeAndClient`] with a cookie jar persisted in the cache directory, returns
+/// its address
+#[no_mangle]
+pub unsafe extern "C" fn client_new_with_cookies(
+    cache_dir_ref: CFStringRef,
+) -> structs::Result<isize> {
+    {
+        let cache_dir_ios = CFString::wrap_under_get_rule(cache_dir_ref);
+        let cache_dir_raw: Cow<_> = (&cache_dir_ios).into();
+        let cache_dir = Path::new(cache_dir_raw.as_ref());
+
+        RuntimeAndClient::new_with_cookies(cache_dir)
+            .context("create runtime and client")
+            .map(Into::into)
+    }
+    .into()
+}
+
+/// Create a new [`RuntimeAndClient`], calling `on_progress(context, fraction, step)` with the
+/// steps of the bootstrap, on the calling thread, returns its address
+///
+/// `step` is only valid during the call.
+#[no_mangle]
+pub unsafe extern "C" fn client_new_with_progress(
+    cache_dir_ref: CFStringRef,
+    context: *mut c_void,
+    on_progress: extern "C" fn(*mut c_void, f32, CFStringRef),
+) -> structs::Result<isize> {
+    {
+        let cache_dir_ios = CFString::wrap_under_get_rule(cache_dir_ref);
+        let cache_dir_raw: Cow<_> = (&cache_dir_ios).into();
+        let cache_dir = Path::new(cache_dir_raw.as_ref());
+
+        RuntimeAndClient::with_progress(Client::builder(cache_dir), |progress| {
+            let step = CFString::new(&progress.to_string());
+            on_progress(context, progress.fraction(), step.as_concrete_TypeRef());
+            Ok(())
+        })
+        .context("create runtime and client")
+        .map(Into::into)
+    }
+    .into()
+}
+
 /// Send a request using the given [`RuntimeAndClient`]
 #[no_mangle]
 pub unsafe extern "C" fn client_send(
@@ -61,6 +134,53 @@ pub unsafe extern "C" fn client_send(
     .into()
 }
 
+/// Start a SOCKS5 proxy forwarding connections with the given [`RuntimeAndClient`], on the given
+/// loopback port, 0 for any, returns its address. The client must not be freed before the proxy
+/// is stopped.
+#[no_mangle]
+pub unsafe extern "C" fn client_socks_proxy_start(
+    ios_client: isize,
+    port: u16,
+) -> structs::Result<isize> {
+    {
+        let rt_and_client = RuntimeAndClient::from(ios_client);
+
+        ProxyAndThread::start(&rt_and_client, port).map(Into::into)
+    }
+    .into()
+}
+
+/// Start an HTTP proxy forwarding CONNECT tunnels and plain-HTTP requests with the given
+/// [`RuntimeAndClient`], on the given loopback port, 0 for any, returns its address. Only the
+/// hosts of `allowed_hosts`, a CFArray<CFString>, are allowed, all of them if it is null. The
+/// client must not be freed before the proxy is stopped.
+#[no_mangle]
+pub unsafe extern "C" fn client_http_proxy_start(
+    ios_client: isize,
+    port: u16,
+    allowed_hosts: CFArrayRef,
+) -> structs::Result<isize> {
+    {
+        let rt_and_client = RuntimeAndClient::from(ios_client);
+        let allowed_hosts = (!allowed_hosts.is_null()).then(|| conv::string_list(allowed_hosts));
+
+        ProxyAndThread::start_http(&rt_and_client, port, allowed_hosts).map(Into::into)
+    }
+    .into()
+}
+
+/// Return the port the given proxy listens on
+#[no_mangle]
+pub unsafe extern "C" fn proxy_port(ios_proxy: isize) -> u16 {
+    ProxyAndThread::from(ios_proxy).port()
+}
+
+/// Stop the given proxy of the given [`RuntimeAndClient`], and free it
+#[no_mangle]
+pub unsafe extern "C" fn client_proxy_stop(ios_client: isize, ios_proxy: isize) {
+    ProxyAndThread::from(ios_proxy).stop(&RuntimeAndClient::from(ios_client));
+}
+
 /// Free a [`RuntimeAndClient`]
 #[no_mangle]
 pub unsafe extern "C" fn client_free(ios_client: isize) {
@@ -1,9 +1,17 @@
-use std::mem::ManuallyDrop;
+use std::{borrow::Cow, convert::TryFrom, mem::ManuallyDrop, sync::Arc};
 
+use anyhow::{Context, Result};
+use core_foundation::{
+    array::{CFArray, CFArrayRef},
+    base::{FromVoid, TCFType},
+    data::CFData,
+    dictionary::{CFDictionary, CFDictionaryRef},
+    string::CFString,
+};
 use tokio::runtime::Runtime;
 
-use super::RuntimeAndClient;
-use crate::Client;
+use super::{ProxyAndThread, RuntimeAndClient};
+use crate::{Client, Proxy, TlsConfig};
 
 impl From<RuntimeAndClient> for isize {
     fn from(rt_and_client: RuntimeAndClient) -> Self {
@@ -14,7 +22,76 @@ impl From<RuntimeAndClient> for isize {
 impl From<isize> for RuntimeAndClient {
     fn from(rt_and_client: isize) -> Self {
         Self(ManuallyDrop::new(unsafe {
-            Box::from_raw(rt_and_client as *mut (Runtime, Client))
+            Box::from_raw(rt_and_client as *mut (Arc<Runtime>, Client))
         }))
     }
 }
+
+impl From<ProxyAndThread> for isize {
+    fn from(proxy: ProxyAndThread) -> Self {
+        Box::into_raw(ManuallyDrop::into_inner(proxy.0)) as isize
+    }
+}
+
+impl From<isize> for ProxyAndThread {
+    fn from(proxy: isize) -> Self {
+        Self(ManuallyDrop::new(unsafe {
+            Box::from_raw(proxy as *mut (Proxy, std::thread::JoinHandle<()>))
+        }))
+    }
+}
+
+/// Build a [`TlsConfig`] from pinned SPKI hashes by hostname, as a
+/// CFDictionary<CFString, CFArray<CFData>>, and DER-encoded root certificates, as a
+/// CFArray<CFData>. A null dictionary or array is empty.
+pub fn tls_config(pins_ref: CFDictionaryRef, roots_ref: CFArrayRef) -> Result<TlsConfig> {
+    let mut tls = TlsConfig::default();
+
+    if !pins_ref.is_null() {
+        add_pins(pins_ref, &mut tls)?;
+    }
+    if !roots_ref.is_null() {
+        add_roots(roots_ref, &mut tls)?;
+    }
+
+    Ok(tls)
+}
+
+/// Add the pinned SPKI hashes by hostname of a CFDictionary<CFString, CFArray<CFData>>.
+fn add_pins(pins_ref: CFDictionaryRef, tls: &mut TlsConfig) -> Result<()> {
+    let pins_ios =
+        unsafe { CFDictionary::<CFString, CFArray<CFData>>::wrap_under_get_rule(pins_ref) };
+
+    let (hosts, hashes) = pins_ios.get_keys_and_values();
+    for (host, hashes) in hosts
+        .into_iter()
+        .map(|k| unsafe { CFString::from_void(k) })
+        .zip(
+            hashes
+                .into_iter()
+                .map(|v| unsafe { CFArray::<CFData>::from_void(v) }),
+        )
+    {
+        let host: Cow<_> = (&(*host)).into();
+        for hash in hashes.iter() {
+            let hash = <[u8; 32]>::try_from(hash.bytes()).context("SPKI hash is not 32 bytes")?;
+            tls.add_pin(host.as_ref(), hash);
+        }
+    }
+    Ok(())
+}
+
+/// Add the DER-encoded root certificates of a CFArray<CFData>.
+fn add_roots(roots_ref: CFArrayRef, tls: &mut TlsConfig) -> Result<()> {
+    let roots_ios = unsafe { CFArray::<CFData>::wrap_under_get_rule(roots_ref) };
+    for root in roots_ios.iter() {
+        tls.add_root_certificate(root.bytes())?;
+    }
+    Ok(())
+}
+
+/// Build a list of strings from a CFArray<CFString>.
+pub fn string_list(list_ref: CFArrayRef) -> Vec<String> {
+    let list_ios = unsafe { CFArray::<CFString>::wrap_under_get_rule(list_ref) };
+    list_ios.iter().map(|string| string.to_string()).collect()
+}
@@ -19,6 +19,8 @@ use http::{
     Uri,
 };
 
+use crate::ffi::{ErrorKind, FfiError};
+
 /// HTTP methods available
 #[repr(C)]
 pub enum Method {
@@ -80,7 +82,7 @@ impl TryFrom<Request> for super::Request {
         let mut ret = http::Request::builder()
             .method::<http::Method>(request.method.into())
             .header("Host", url.host().context("no host in request")?)
-            .version(http::Version::HTTP_10)
+            .version(http::Version::HTTP_11)
             .uri(url)
             .body(body_ios.bytes().to_vec())
             .context("invalid request")?;
@@ -188,21 +190,34 @@ pub struct Result<T> {
     is_ok: bool,
     /// Contained value
     value: ResultUnion<T>,
+    /// Kind of the error, if not `is_ok`
+    error_kind: ErrorKind,
+    /// Whether trying again may succeed, if not `is_ok`
+    is_retryable: bool,
 }
 
 impl<T> From<anyhow::Result<T>> for Result<T> {
     fn from(res: anyhow::Result<T>) -> Self {
-        let is_ok = res.is_ok();
-
-        let value = match res {
-            Ok(ok) => ResultUnion {
-                ok: ManuallyDrop::new(ok),
+        match res {
+            Ok(ok) => Self {
+                is_ok: true,
+                value: ResultUnion {
+                    ok: ManuallyDrop::new(ok),
+                },
+                error_kind: ErrorKind::Other,
+                is_retryable: false,
             },
-            Err(err) => ResultUnion {
-                err: ManuallyDrop::new(CFString::new(&format!("{:#}", err))).as_concrete_TypeRef(),
-            },
-        };
-
-        Self { is_ok, value }
+            Err(err) => {
+                let err = FfiError::from(err);
+                Self {
+                    is_ok: false,
+                    value: ResultUnion {
+                        err: ManuallyDrop::new(CFString::new(&err.message)).as_concrete_TypeRef(),
+                    },
+                    error_kind: err.kind,
+                    is_retryable: err.retryable,
+                }
+            }
+        }
     }
 }
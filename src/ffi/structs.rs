@@ -1,38 +1,83 @@
 //! FFI structs
 
-use std::{mem::ManuallyDrop, path::Path};
+use std::{
+    mem::ManuallyDrop,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
 
 use anyhow::{Context, Result};
+use futures::{FutureExt, StreamExt};
 use tokio::runtime::Runtime;
 
-use crate::client::{DIRECTORY_CACHE_C4DT, DIRECTORY_CHURN_C4DT};
-use crate::Client;
+use crate::{
+    BootstrapProgress, Client, ClientBuilder, CookieJar, Error, HttpProxyConfig, Proxy, TlsConfig,
+    COOKIES_FILENAME,
+};
 
 /// Wrap a [`Runtime`] and a [`Client`], useful for crossing FFI boundaries
-pub(super) struct RuntimeAndClient(pub ManuallyDrop<Box<(Runtime, Client)>>);
+///
+/// The runtime is shared with the threads running the proxies of the client.
+pub(super) struct RuntimeAndClient(pub ManuallyDrop<Box<(Arc<Runtime>, Client)>>);
 
 impl RuntimeAndClient {
     /// Create a new [`RuntimeAndClient`] using the given cache directory
     pub fn new(cache_dir: &Path) -> Result<Self> {
-        Self::new_with_url(cache_dir, DIRECTORY_CACHE_C4DT, DIRECTORY_CHURN_C4DT)
+        Self::with_builder(Client::builder(cache_dir))
     }
 
-    /// Create a new [`RuntimeAndClient`] by giving another URL for the cache files.
-    pub fn new_with_url(
-        cache_dir: &Path,
-        directory_cache: &str,
-        churn_cache: &str,
+    /// Create a new [`RuntimeAndClient`] using the given cache directory and TLS settings.
+    pub fn new_with_tls(cache_dir: &Path, tls: TlsConfig) -> Result<Self> {
+        Self::with_builder(Client::builder(cache_dir).tls_config(tls))
+    }
+
+    /// Create a new [`RuntimeAndClient`] using the given cache directory, with a cookie jar
+    /// persisted in it.
+    pub fn new_with_cookies(cache_dir: &Path) -> Result<Self> {
+        let jar =
+            CookieJar::persistent(cache_dir.join(COOKIES_FILENAME)).context("load cookies")?;
+        Self::with_builder(Client::builder(cache_dir).cookie_jar(Arc::new(jar)))
+    }
+
+    /// Create a new [`RuntimeAndClient`] from the settings of the builder.
+    pub fn with_builder(builder: ClientBuilder) -> Result<Self> {
+        Self::with_progress(builder, |_| Ok(()))
+    }
+
+    /// Create a new [`RuntimeAndClient`] from the settings of the builder, calling `on_progress`
+    /// with the steps of the bootstrap. The creation fails if `on_progress` does.
+    ///
+    /// `on_progress` is called on the calling thread, as the runtime only runs while blocked on.
+    pub fn with_progress(
+        builder: ClientBuilder,
+        mut on_progress: impl FnMut(BootstrapProgress) -> Result<()>,
     ) -> Result<Self> {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .context("build tokio runtime")?;
 
-        let client = rt
-            .block_on(async { Client::new_with_url(cache_dir, directory_cache, churn_cache).await })
-            .context("create client")?;
+        let mut progress = builder.bootstrap_progress().boxed();
+        let client = rt.block_on(async {
+            let build = builder.build();
+            tokio::pin!(build);
+            let client = loop {
+                tokio::select! {
+                    biased;
+                    Some(step) = progress.next() => on_progress(step).context("report progress")?,
+                    client = &mut build => break client.context("create client")?,
+                }
+            };
+            // The last step is reached while the client is built.
+            if let Some(Some(step)) = progress.next().now_or_never() {
+                on_progress(step).context("report progress")?;
+            }
+            Ok::<_, anyhow::Error>(client)
+        })?;
 
-        Ok(Self(ManuallyDrop::new(Box::new((rt, client)))))
+        Ok(Self(ManuallyDrop::new(Box::new((Arc::new(rt), client)))))
     }
 
     /// Return the constructed [`Runtime`]
@@ -46,6 +91,155 @@ impl RuntimeAndClient {
     }
 }
 
+/// Wrap a [`Proxy`] and the thread running it, useful for crossing FFI boundaries
+pub(super) struct ProxyAndThread(pub ManuallyDrop<Box<(Proxy, JoinHandle<()>)>>);
+
+impl ProxyAndThread {
+    /// Start a SOCKS proxy with the given client, on the given loopback port, 0 for any.
+    ///
+    /// The runtime only runs while blocked on, so a thread blocks on it until the proxy is
+    /// stopped. The thread shares the runtime, so that the runtime outlives it. The client must not
+    /// be freed before, as it is needed to stop the proxy.
+    pub fn start(rt_and_client: &RuntimeAndClient, port: u16) -> Result<Self> {
+        let proxy = rt_and_client
+            .runtime()
+            .block_on(
+                rt_and_client
+                    .client()
+                    .start_socks_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+            )
+            .context("start SOCKS proxy")?;
+
+        Self::spawn(rt_and_client, proxy)
+    }
+
+    /// Start an HTTP proxy with the given client, on the given loopback port, 0 for any, only
+    /// allowing the given hosts if any. See [`ProxyAndThread::start`].
+    pub fn start_http(
+        rt_and_client: &RuntimeAndClient,
+        port: u16,
+        allowed_hosts: Option<Vec<String>>,
+    ) -> Result<Self> {
+        let config = HttpProxyConfig {
+            allowed_hosts,
+            ..HttpProxyConfig::default()
+        };
+        let proxy = rt_and_client
+            .runtime()
+            .block_on(
+                rt_and_client
+                    .client()
+                    .start_http_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), config),
+            )
+            .context("start HTTP proxy")?;
+
+        Self::spawn(rt_and_client, proxy)
+    }
+
+    /// Run the runtime in a thread until the proxy is stopped.
+    fn spawn(rt_and_client: &RuntimeAndClient, proxy: Proxy) -> Result<Self> {
+        let runtime = Arc::clone(&rt_and_client.0 .0);
+        let stopped = proxy.stopped();
+        let thread = thread::Builder::new()
+            .name("proxy".to_owned())
+            .spawn(move || runtime.block_on(stopped))
+            .context("spawn proxy thread")?;
+
+        Ok(Self(ManuallyDrop::new(Box::new((proxy, thread)))))
+    }
+
+    /// Return the port the proxy listens on
+    pub fn port(&self) -> u16 {
+        self.0 .0.local_addr().port()
+    }
+
+    /// Stop the proxy, and wait for its thread to end
+    pub fn stop(self, rt_and_client: &RuntimeAndClient) {
+        let (proxy, thread) = *ManuallyDrop::into_inner(self.0);
+        rt_and_client.runtime().block_on(proxy.stop());
+        let _ = thread.join();
+    }
+}
+
+/// Kind of an [`Error`], given to the apps with its message so that they can handle each kind of
+/// failure. The values never change.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// See [`Error::Other`], and any failure of the FFI itself.
+    Other = 0,
+    /// See [`Error::CacheMissing`].
+    CacheMissing = 1,
+    /// See [`Error::CacheStale`].
+    CacheStale = 2,
+    /// See [`Error::CacheCorrupt`].
+    CacheCorrupt = 3,
+    /// See [`Error::Download`].
+    Download = 4,
+    /// See [`Error::Bootstrap`].
+    Bootstrap = 5,
+    /// See [`Error::TorConnect`].
+    TorConnect = 6,
+    /// See [`Error::Tls`].
+    Tls = 7,
+    /// See [`Error::Connection`].
+    Connection = 8,
+    /// See [`Error::HttpParse`].
+    HttpParse = 9,
+    /// See [`Error::OnionDirectory`].
+    OnionDirectory = 10,
+    /// See [`Error::Timeout`].
+    Timeout = 11,
+    /// See [`Error::Cancelled`].
+    Cancelled = 12,
+    /// See [`Error::LimitExceeded`].
+    LimitExceeded = 13,
+}
+
+impl From<&Error> for ErrorKind {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::CacheMissing(_) => Self::CacheMissing,
+            Error::CacheStale(_) => Self::CacheStale,
+            Error::CacheCorrupt(_) => Self::CacheCorrupt,
+            Error::Download(_) => Self::Download,
+            Error::Bootstrap(_) => Self::Bootstrap,
+            Error::TorConnect { .. } => Self::TorConnect,
+            Error::Tls(_) => Self::Tls,
+            Error::Connection(_) => Self::Connection,
+            Error::HttpParse(_) => Self::HttpParse,
+            Error::OnionDirectory(_) => Self::OnionDirectory,
+            Error::Timeout(_) => Self::Timeout,
+            Error::Cancelled => Self::Cancelled,
+            Error::LimitExceeded(_) => Self::LimitExceeded,
+            Error::Other(_) => Self::Other,
+        }
+    }
+}
+
+/// Error crossing FFI boundaries
+pub(super) struct FfiError {
+    /// Message of the error, with all its causes
+    pub message: String,
+    /// Kind of the error
+    pub kind: ErrorKind,
+    /// See [`Error::is_retryable`]
+    pub retryable: bool,
+}
+
+impl From<anyhow::Error> for FfiError {
+    fn from(err: anyhow::Error) -> Self {
+        // The context added on this side is only kept in the message.
+        let message = format!("{:#}", err);
+        let err = Error::from(err);
+        Self {
+            message,
+            kind: ErrorKind::from(&err),
+            retryable: err.is_retryable(),
+        }
+    }
+}
+
 /// Deserializable HTTP Request
 pub(super) struct Request(pub http::Request<Vec<u8>>);
 
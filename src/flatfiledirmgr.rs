@@ -1,7 +1,7 @@
 //! Simple flat-file implementation of the DirProvider trait.
 //! Used for 'lightarti'.
 
-use arti_client::DirProviderBuilder;
+use arti_client::{DirProviderBuilder, ErrorKind};
 use tor_checkable::{ExternallySigned, SelfSigned, TimeValidityError, Timebound};
 use tor_circmgr::CircMgr;
 use tor_dirmgr::config::DirMgrConfig;
@@ -11,26 +11,32 @@ use tor_netdir::{DirEvent, MdReceiver, NetDir, NetDirProvider, PartialNetDir, Ti
 use tor_netdoc::doc::authcert::AuthCert;
 use tor_netdoc::doc::microdesc::{Microdesc, MicrodescReader};
 use tor_netdoc::doc::netstatus::{
-    MdConsensus, MdConsensusRouterStatus, RouterStatus, UnvalidatedConsensus,
+    Lifetime, MdConsensus, MdConsensusRouterStatus, RouterStatus, UnvalidatedConsensus,
 };
 use tor_netdoc::AllowAnnotations;
 use tor_rtcompat::Runtime;
 
 use async_trait::async_trait;
 use futures::stream::BoxStream;
-use postage::{broadcast, sink::Sink, watch};
+use futures::task::SpawnExt;
+use postage::{broadcast, sink::Sink};
 use tracing::{debug, info, warn};
 
-use crate::AUTHORITY_FILENAME;
+use crate::progress::ProgressSender;
+use crate::relays::RelayPolicyError;
+use crate::{BootstrapProgress, RelayPolicy, AUTHORITY_FILENAME};
 use rand::seq::SliceRandom;
 use std::collections::HashSet;
 use std::fs;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tor_netdir::params::NetParameters;
 
-/// 1/CHURN_FRACTION is the threshold of the consensus relays that we can remove with the churn
-const CHURN_FRACTION: usize = 6;
+/// 1/DEFAULT_CHURN_FRACTION is the default threshold of the consensus relays that we can remove
+/// with the churn
+pub(crate) const DEFAULT_CHURN_FRACTION: usize = 6;
 
 /// Contents of the directory cache.
 /// CONSENSUS_FILENAME is the name of the file containing the consensus.
@@ -45,6 +51,9 @@ pub const CHURN_FILENAME: &str = "churn.txt";
 /// A directory manager that loads the directory information from flat files read from the cache
 /// directory.
 pub struct FlatFileDirMgr<R: Runtime> {
+    /// Runtime used to notify the changes of directory.
+    runtime: R,
+
     /// Configuration information: where to find directories, how to
     /// validate them, and so on.
     config: tor_config::MutCfg<DirMgrConfig>,
@@ -55,17 +64,123 @@ pub struct FlatFileDirMgr<R: Runtime> {
     /// A sender handle that we notify whenever the consensus changes.
     tx_events: broadcast::Sender<DirEvent>,
 
-    /// A receiver handle that gets notified whenever our bootstrapping status changes.
-    ///
-    /// Unused for now.
-    bootstrap_rx_events: watch::Receiver<DirBootstrapStatus>,
+    /// Publisher of the steps reached while loading the directory.
+    progress: ProgressSender,
+
+    /// Validity of the ring of onion service directories of the directory in use.
+    hsdir: HsDirValidity,
+
+    /// Modification times of the files the directory was last read from.
+    loaded_files: Mutex<Option<Vec<SystemTime>>>,
 
     /// A circuit manager.
     circmgr: Option<Arc<CircMgr<R>>>,
+
+    /// 1/churn_fraction is the threshold of the consensus relays that we can remove with the churn
+    churn_fraction: usize,
+
+    /// Restrictions on the relays of the consensus.
+    relay_policy: RelayPolicy,
+}
+
+/// Number of voting periods by which the time periods of the onion service directories are
+/// offset, see rend-spec-v3 section 2.2.1.
+const VOTING_PERIODS_IN_OFFSET: u32 = 12;
+
+/// Ring of onion service directories of a directory.
+#[derive(Clone, Debug)]
+enum HsDirRing {
+    /// No directory was loaded yet.
+    Unknown,
+    /// The consensus has no shared random value, so tor-netdir uses the disaster one, which onion
+    /// services don't use.
+    NoSharedRandom,
+    /// The ring is only valid during the time period of the consensus.
+    Valid(Range<SystemTime>),
+}
+
+/// Validity of the ring of onion service directories of the directory in use, shared with the
+/// client to check it before reaching onion services.
+///
+/// The directory cache is only published once a week, but the ring changes with every time
+/// period, once a day by default: the ring computed from the consensus doesn't find the
+/// descriptors of onion services afterwards.
+#[derive(Clone, Debug)]
+pub(crate) struct HsDirValidity(Arc<Mutex<HsDirRing>>);
+
+impl Default for HsDirValidity {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(HsDirRing::Unknown)))
+    }
 }
 
-/// Check cache directory content.
-pub fn check_directory(cache_path: &Path) -> Result<()> {
+impl HsDirValidity {
+    fn set(&self, ring: HsDirRing) {
+        *self.0.lock().expect("lock poisoned") = ring;
+    }
+
+    /// Check that onion services can be reached with the directory in use at the given time,
+    /// failing with [`crate::Error::OnionDirectory`] otherwise.
+    pub(crate) fn check(&self, now: SystemTime) -> std::result::Result<(), crate::Error> {
+        self.0.lock().expect("lock poisoned").check(now)
+    }
+}
+
+impl HsDirRing {
+    /// Check that onion services can be reached with the ring at the given time.
+    fn check(&self, now: SystemTime) -> std::result::Result<(), crate::Error> {
+        match self {
+            HsDirRing::Unknown => Ok(()),
+            HsDirRing::NoSharedRandom => Err(crate::Error::OnionDirectory(
+                "consensus has no shared random value for the onion service directories".into(),
+            )),
+            HsDirRing::Valid(period) if period.contains(&now) => Ok(()),
+            HsDirRing::Valid(_) => Err(crate::Error::OnionDirectory(
+                "onion service directories of the consensus are from another time period".into(),
+            )),
+        }
+    }
+}
+
+/// Ring of onion service directories computed by tor-netdir from a consensus with the given
+/// lifetime, see rend-spec-v3 section 2.2.1.
+///
+/// This only checks whether the consensus has a shared random value, not whether it is the one
+/// of the time period.
+fn hsdir_ring(lifetime: &Lifetime, has_shared_random: bool, period_length: Duration) -> HsDirRing {
+    if !has_shared_random {
+        return HsDirRing::NoSharedRandom;
+    }
+    let offset = lifetime.voting_period() * VOTING_PERIODS_IN_OFFSET;
+    let since_epoch = lifetime
+        .valid_after()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .and_then(|elapsed| elapsed.checked_sub(offset));
+    match since_epoch {
+        Some(elapsed) if period_length.as_secs() > 0 => {
+            let periods = elapsed.as_secs() / period_length.as_secs();
+            let start = SystemTime::UNIX_EPOCH
+                + offset
+                + Duration::from_secs(periods * period_length.as_secs());
+            HsDirRing::Valid(start..start + period_length)
+        }
+        _ => HsDirRing::NoSharedRandom,
+    }
+}
+
+/// Slot where a [`FlatFileDirMgrBuilder`] keeps the directory manager it built, so that the
+/// directory can be loaded again once the Tor client is bootstrapped.
+pub(crate) type BuiltDirMgr = Arc<Mutex<Option<Arc<dyn DirProvider>>>>;
+
+/// Check cache directory content, failing with [`crate::Error::CacheMissing`] if some files are
+/// missing.
+pub fn check_directory(cache_path: &Path) -> std::result::Result<(), crate::Error> {
+    check_files(cache_path).map_err(|err| crate::Error::CacheMissing(Box::new(err)))
+}
+
+/// Check that all the files of the directory cache exist.
+fn check_files(cache_path: &Path) -> Result<()> {
     let missing_files: Vec<&&str> = [
         AUTHORITY_FILENAME,
         CONSENSUS_FILENAME,
@@ -83,20 +198,51 @@ pub fn check_directory(cache_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Modification times of the files the directory is read from, to know whether they changed
+/// since it was loaded. `None` if one of them can't be read.
+fn files_modified(cache_path: &Path) -> Option<Vec<SystemTime>> {
+    [
+        CONSENSUS_FILENAME,
+        MICRODESCRIPTORS_FILENAME,
+        CERTIFICATE_FILENAME,
+        CHURN_FILENAME,
+    ]
+    .iter()
+    .map(|filename| {
+        fs::metadata(cache_path.join(filename))
+            .ok()?
+            .modified()
+            .ok()
+    })
+    .collect()
+}
+
 impl<R: Runtime> FlatFileDirMgr<R> {
     /// Create a new FlatFileDirMgr from a given configuration.
-    pub fn from_config(config: DirMgrConfig, circmgr: Arc<CircMgr<R>>) -> Result<Arc<Self>> {
+    pub fn from_config(
+        runtime: R,
+        config: DirMgrConfig,
+        circmgr: Arc<CircMgr<R>>,
+        churn_fraction: usize,
+        relay_policy: RelayPolicy,
+        progress: ProgressSender,
+        hsdir: HsDirValidity,
+    ) -> Result<Arc<Self>> {
         let netdir = SharedMutArc::new();
         let (tx_events, _) = broadcast::channel(1);
-        let (_, bootstrap_rx_events) = watch::channel();
         let circmgr = Some(circmgr);
 
         Ok(Arc::new(FlatFileDirMgr {
+            runtime,
             config: config.into(),
             netdir,
             tx_events,
-            bootstrap_rx_events,
             circmgr,
+            churn_fraction,
+            relay_policy,
+            progress,
+            hsdir,
+            loaded_files: Mutex::new(None),
         }))
     }
 
@@ -104,10 +250,50 @@ impl<R: Runtime> FlatFileDirMgr<R> {
     ///
     /// This is strongly inspired by the add_from_cache() methods from the various states in
     /// DirMgr, combined and simplified to directly use the data from the loaded files.
+    ///
+    /// The directory replaces the one in use, if any, and the circuit manager is notified.
     pub async fn load_directory(&self) -> Result<bool> {
-        let config = self.config.get();
+        let loaded = self.load_netdir(&self.config.get())?;
+        if loaded {
+            notify_new_directory(self.tx_events.clone()).await?;
+        }
+        Ok(loaded)
+    }
+
+    /// Load the directory from the files of the configuration, and use it if it is sufficient.
+    ///
+    /// Returns whether a directory is in use, which is the previous one if the new one is not
+    /// sufficient.
+    fn load_netdir(&self, config: &DirMgrConfig) -> Result<bool> {
+        // Taken before reading, so that files replaced meanwhile are read again.
+        let modified = files_modified(&config.cache_path);
+        let loaded = self.read_netdir(config).map(|netdir| {
+            *self.loaded_files.lock().expect("lock poisoned") = modified;
+            if let Some((netdir, ring)) = netdir {
+                if let Err(err) = ring.check(SystemTime::now()) {
+                    warn!(
+                        "onion services can't be reached with this directory: {}",
+                        err
+                    );
+                }
+                self.hsdir.set(ring);
+                self.netdir.replace(netdir);
+            }
+        });
+        let in_use = self.netdir.get().is_some();
+        if in_use {
+            // This is the previous directory if the new one can't be used.
+            self.progress.publish(BootstrapProgress::DirectoryReady);
+        }
+        loaded.map(|()| in_use)
+    }
+
+    /// Read and verify the directory from the files of the configuration, returning it with its
+    /// ring of onion service directories if it is sufficient to build circuits.
+    fn read_netdir(&self, config: &DirMgrConfig) -> Result<Option<(NetDir, HsDirRing)>> {
         let cache_path = &config.cache_path;
-        check_directory(cache_path)?;
+        check_files(cache_path)?;
+        self.progress.publish(BootstrapProgress::CacheChecked);
 
         // Consensus
         let unvalidated = self.load_consensus(cache_path)?;
@@ -133,44 +319,49 @@ impl<R: Runtime> FlatFileDirMgr<R> {
         let consensus = unvalidated
             .check_signature(&[certificate])
             .map_err(|_| Error::CacheCorruption("Failed to validate consensus signature"))?;
+        self.progress.publish(BootstrapProgress::ConsensusVerified);
 
         // Microdescriptors
         let udesc = self.load_microdesc(cache_path)?;
+        self.progress
+            .publish(BootstrapProgress::MicrodescriptorsLoaded);
 
         // Build directory
         let params = &config.override_net_params;
+        let lifetime = consensus.lifetime().clone();
+        let has_shared_random =
+            consensus.shared_rand_cur().is_some() || consensus.shared_rand_prev().is_some();
         let mut partial = PartialNetDir::new(consensus, Some(params));
 
         for md in udesc {
             partial.add_microdesc(md);
         }
 
-        if let Ok(netdir) = partial.unwrap_if_sufficient() {
-            if match &self.circmgr {
-                Some(circmgr) => circmgr.netdir_is_sufficient(&netdir),
-                None => true,
-            } {
-                self.netdir.replace(netdir);
-            } else {
-                warn!("circmgr says netdir is not sufficient");
-            }
+        let netdir = match partial.unwrap_if_sufficient() {
+            Ok(netdir) => netdir,
+            Err(_) => return Ok(None),
+        };
+        if !self.relay_policy.is_empty() && !netdir.relays().any(|r| r.policies_allow_some_port()) {
+            return Err(relay_policy_error(
+                RelayPolicyError::NoExit,
+                ErrorKind::NoExit,
+            ));
+        }
+        if match &self.circmgr {
+            Some(circmgr) => circmgr.netdir_is_sufficient(&netdir),
+            None => true,
+        } {
+            let period_length = netdir
+                .params()
+                .hsdir_timeperiod_length
+                .try_into()
+                .unwrap_or(Duration::ZERO);
+            let ring = hsdir_ring(&lifetime, has_shared_random, period_length);
+            Ok(Some((netdir, ring)))
+        } else {
+            warn!("circmgr says netdir is not sufficient");
+            Ok(None)
         }
-
-        Ok(match self.netdir.get() {
-            Some(_) => {
-                let mut tx = self.tx_events.clone();
-
-                tx.send(DirEvent::NewConsensus)
-                    .await
-                    .map_err(|_| Error::DirectoryNotPresent)?;
-                tx.send(DirEvent::NewDescriptors)
-                    .await
-                    .map_err(|_| Error::DirectoryNotPresent)?;
-
-                true
-            }
-            None => false,
-        })
     }
 
     /// Load the consensus from a flat file.
@@ -197,7 +388,7 @@ impl<R: Runtime> FlatFileDirMgr<R> {
 
         // If the churn is above a threshold, we only consider a random subset
         // of the churned routers.
-        let churn_threshold = unvalidated.n_relays() / CHURN_FRACTION;
+        let churn_threshold = unvalidated.n_relays() / self.churn_fraction;
         let churn_set: HashSet<&RsaIdentity> = if churn.len() > churn_threshold {
             warn!("Churn larger than threshold limit!");
             let number_to_remove = churn.len() - churn_threshold;
@@ -221,6 +412,23 @@ impl<R: Runtime> FlatFileDirMgr<R> {
                 .modify_relays(|relays| relays.retain(|r| !churn_set.contains(r.rsa_identity())));
         }
 
+        if !self.relay_policy.is_empty() {
+            let filter = self
+                .relay_policy
+                .load()
+                .map_err(|err| Error::ExternalDirProvider {
+                    cause: Arc::from(Box::<dyn std::error::Error + Send + Sync>::from(err)),
+                    kind: ErrorKind::InvalidConfig,
+                })?;
+            unvalidated.modify_relays(|relays| filter.apply(relays));
+            if unvalidated.n_relays() == 0 {
+                return Err(relay_policy_error(
+                    RelayPolicyError::NoRelay,
+                    ErrorKind::NoPath,
+                ));
+            }
+        }
+
         Ok(unvalidated)
     }
 
@@ -263,11 +471,25 @@ impl<R: Runtime> FlatFileDirMgr<R> {
     fn opt_netdir(&self) -> Option<Arc<NetDir>> {
         self.netdir.get()
     }
+}
+
+/// Tell the subscribers, e.g. the circuit manager, that a new directory is in use.
+async fn notify_new_directory(mut tx: broadcast::Sender<DirEvent>) -> Result<()> {
+    tx.send(DirEvent::NewConsensus)
+        .await
+        .map_err(|_| Error::DirectoryNotPresent)?;
+    tx.send(DirEvent::NewDescriptors)
+        .await
+        .map_err(|_| Error::DirectoryNotPresent)?;
+    Ok(())
+}
 
-    /// Return a stream of [`DirBootstrapStatus`] events to tell us about changes in the latest
-    /// directory's bootstrap status.
-    fn bootstrap_events(&self) -> watch::Receiver<DirBootstrapStatus> {
-        self.bootstrap_rx_events.clone()
+/// Error returned when no circuit can be built with the relay policy.
+fn relay_policy_error(err: RelayPolicyError, kind: ErrorKind) -> Error {
+    warn!("{}", err);
+    Error::ExternalDirProvider {
+        cause: Arc::new(err),
+        kind,
     }
 }
 
@@ -302,15 +524,55 @@ impl<R: Runtime> NetDirProvider for FlatFileDirMgr<R> {
 
 #[async_trait]
 impl<R: Runtime> DirProvider for FlatFileDirMgr<R> {
+    /// Load the directory again from the files of the new configuration and use it in place of
+    /// the current one, if the cache directory, the network or the files changed. Reloading the
+    /// same files is done with [`crate::Client::reload_directory`].
+    ///
+    /// The configuration is kept if the directory can't be loaded. Only the files are checked
+    /// with [`tor_config::Reconfigure::CheckAllOrNothing`].
     fn reconfigure(
         &self,
-        _new_config: &DirMgrConfig,
-        _how: tor_config::Reconfigure,
+        new_config: &DirMgrConfig,
+        how: tor_config::Reconfigure,
     ) -> std::result::Result<(), tor_config::ReconfigureError> {
-        // Not implemented
-        Err(tor_config::ReconfigureError::CannotChange {
-            field: "all".to_string(),
-        })
+        let unsupported =
+            |err: Error| tor_config::ReconfigureError::UnsupportedSituation(err.to_string());
+        if how == tor_config::Reconfigure::CheckAllOrNothing {
+            return check_files(&new_config.cache_path).map_err(unsupported);
+        }
+
+        let current = self.config.get();
+        let modified = files_modified(&new_config.cache_path);
+        if current.cache_path == new_config.cache_path
+            && current.network == new_config.network
+            && current.override_net_params == new_config.override_net_params
+            && modified.is_some()
+            && *self.loaded_files.lock().expect("lock poisoned") == modified
+        {
+            debug!("directory unchanged, not reloading it");
+            self.config.replace(new_config.clone());
+            return Ok(());
+        }
+
+        let loaded = self.load_netdir(new_config).map_err(unsupported)?;
+        self.config.replace(new_config.clone());
+        if loaded {
+            // The events can only be sent asynchronously.
+            let notify = notify_new_directory(self.tx_events.clone());
+            self.runtime
+                .spawn(async move {
+                    if let Err(err) = notify.await {
+                        warn!("failed to notify the new directory: {}", err);
+                    }
+                })
+                .map_err(|err| {
+                    tor_config::ReconfigureError::UnsupportedSituation(format!(
+                        "failed to notify the new directory: {}",
+                        err
+                    ))
+                })?;
+        }
+        Ok(())
     }
 
     async fn bootstrap(&self) -> Result<()> {
@@ -319,23 +581,108 @@ impl<R: Runtime> DirProvider for FlatFileDirMgr<R> {
         Ok(())
     }
 
+    /// No status is reported, as a [`DirBootstrapStatus`] with some progress can only be built by
+    /// tor_dirmgr: the progress is only published as [`BootstrapProgress`], see
+    /// [`crate::Client::bootstrap_progress`].
     fn bootstrap_events(&self) -> BoxStream<'static, DirBootstrapStatus> {
-        Box::pin(self.bootstrap_events())
+        Box::pin(futures::stream::empty())
     }
 }
 
-pub struct FlatFileDirMgrBuilder {}
+pub struct FlatFileDirMgrBuilder {
+    /// 1/churn_fraction is the threshold of the consensus relays that we can remove with the churn
+    pub churn_fraction: usize,
+    /// Restrictions on the relays of the consensus
+    pub relay_policy: RelayPolicy,
+    /// Publisher of the steps reached while loading the directory
+    pub(crate) progress: ProgressSender,
+    /// Slot where the directory manager is kept once built
+    pub(crate) built: BuiltDirMgr,
+    /// Validity of the ring of onion service directories of the directory in use
+    pub(crate) hsdir: HsDirValidity,
+}
 
 impl<R: Runtime> DirProviderBuilder<R> for FlatFileDirMgrBuilder {
     fn build(
         &self,
-        _runtime: R,
+        runtime: R,
         _store: DirMgrStore<R>,
         circmgr: Arc<tor_circmgr::CircMgr<R>>,
         config: DirMgrConfig,
     ) -> arti_client::Result<Arc<dyn tor_dirmgr::DirProvider + 'static>> {
-        let dm = FlatFileDirMgr::from_config(config, circmgr)
-            .map_err(arti_client::ErrorDetail::DirMgrSetup)?;
+        let dm = FlatFileDirMgr::from_config(
+            runtime,
+            config,
+            circmgr,
+            self.churn_fraction,
+            self.relay_policy.clone(),
+            self.progress.clone(),
+            self.hsdir.clone(),
+        )
+        .map_err(arti_client::ErrorDetail::DirMgrSetup)?;
+        *self.built.lock().expect("lock poisoned") = Some(dm.clone());
         Ok(dm)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    #[test]
+    fn test_hsdir_ring() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // 2023-10-23 00:00 UTC, with hourly consensuses.
+        let valid_after = SystemTime::UNIX_EPOCH + Duration::from_secs(1_698_019_200);
+        let lifetime = Lifetime::new(valid_after, valid_after + HOUR, valid_after + 3 * HOUR)?;
+
+        let ring = hsdir_ring(&lifetime, true, DAY);
+        // The time periods start at 12:00 UTC, 12 voting periods after midnight.
+        let start = valid_after - 12 * HOUR;
+        assert!(matches!(&ring, HsDirRing::Valid(period) if *period == (start..start + DAY)));
+        assert!(ring.check(valid_after + 11 * HOUR).is_ok());
+        assert!(matches!(
+            ring.check(valid_after + 12 * HOUR),
+            Err(crate::Error::OnionDirectory(_))
+        ));
+        assert!(matches!(
+            ring.check(valid_after + 7 * DAY),
+            Err(crate::Error::OnionDirectory(_))
+        ));
+
+        let ring = hsdir_ring(&lifetime, false, DAY);
+        assert!(matches!(
+            ring.check(valid_after),
+            Err(crate::Error::OnionDirectory(_))
+        ));
+
+        assert!(HsDirValidity::default().check(valid_after).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_modified() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        for filename in [
+            CONSENSUS_FILENAME,
+            MICRODESCRIPTORS_FILENAME,
+            CERTIFICATE_FILENAME,
+        ] {
+            fs::write(tmp.path().join(filename), "")?;
+        }
+        assert_eq!(files_modified(tmp.path()), None);
+
+        fs::write(tmp.path().join(CHURN_FILENAME), "")?;
+        let loaded = files_modified(tmp.path());
+        assert!(loaded.is_some());
+        assert_eq!(files_modified(tmp.path()), loaded);
+
+        // Keeps the modification times apart.
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(tmp.path().join(CHURN_FILENAME), "")?;
+        assert_ne!(files_modified(tmp.path()), loaded);
+        Ok(())
+    }
+}
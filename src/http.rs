@@ -1,13 +1,50 @@
-use std::io::Write;
+use std::io::{self, Write};
 
 use anyhow::{bail, Context, Result};
-use http::{Request, Response};
+use http::{header, request, HeaderMap, HeaderValue, Method, Response, StatusCode, Version};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::trace;
 
-/// Serialize a [`Request`] as an raw HTTP request
-pub fn request_to_raw(req: Request<Vec<u8>>) -> Result<Vec<u8>> {
+mod encoding;
+mod framing;
+mod limits;
+mod reader;
+
+pub use encoding::{Decompression, ACCEPT_ENCODING};
+pub use framing::Framing;
+pub use limits::{LimitExceeded, Limits};
+pub use reader::BodyReader;
+
+/// Number of header fields parsed at first, grown up to [`Limits::max_headers`] if needed.
+const INITIAL_HEADERS: usize = 64;
+/// Size by which the read buffer grows when more data is needed.
+const READ_SIZE: usize = 4096;
+
+/// Serialize the head of a request as a raw HTTP request.
+///
+/// The headers delimiting the body are set from its length: Content-Length if it is known,
+/// otherwise the chunked transfer coding, which needs HTTP/1.1.
+pub fn request_to_raw(parts: &mut request::Parts, body_length: Option<u64>) -> Result<Vec<u8>> {
     const EOL: &str = "\r\n";
 
-    let (parts, mut body) = req.into_parts();
+    match body_length {
+        Some(length) => {
+            parts.headers.remove(header::TRANSFER_ENCODING);
+            if length > 0 || expects_body(&parts.method) {
+                parts.headers.insert(header::CONTENT_LENGTH, length.into());
+            }
+        }
+        None => {
+            if parts.version != Version::HTTP_11 {
+                bail!("request body of unknown length needs HTTP/1.1");
+            }
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(
+                header::TRANSFER_ENCODING,
+                HeaderValue::from_static("chunked"),
+            );
+        }
+    }
 
     let mut ret = Vec::new();
 
@@ -37,36 +74,279 @@ pub fn request_to_raw(req: Request<Vec<u8>>) -> Result<Vec<u8>> {
 
     write!(&mut ret, "{}", EOL).context("write last EOL")?;
 
-    ret.append(&mut body);
-
     Ok(ret)
 }
 
-/// Deserialize an raw HTTP response to an [`Response`]
-pub fn raw_to_response(mut raw_resp: Vec<u8>) -> Result<Response<Vec<u8>>> {
-    const MAX_HEADERS: usize = 64;
+/// Whether requests using this method are expected to carry a body, even an empty one.
+fn expects_body(method: &Method) -> bool {
+    method == Method::POST || method == Method::PUT || method == Method::PATCH
+}
+
+/// Parse the status line and headers of a raw HTTP response.
+///
+/// Returns the response without its body and the length of the parsed head, or `None` if the
+/// head is not complete yet.
+fn parse_head(raw_resp: &[u8], limits: &Limits) -> Result<Option<(Response<()>, usize)>> {
+    let mut max_headers = INITIAL_HEADERS.min(limits.max_headers);
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut http_resp = httparse::Response::new(&mut headers);
+        let head_len = match http_resp.parse(raw_resp) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => {
+                limits.check_head(raw_resp)?;
+                return Ok(None);
+            }
+            Err(httparse::Error::TooManyHeaders) if max_headers < limits.max_headers => {
+                max_headers = max_headers.saturating_mul(2).min(limits.max_headers);
+                continue;
+            }
+            Err(httparse::Error::TooManyHeaders) => return Err(LimitExceeded::Headers.into()),
+            Err(err) => return Err(parse_error(err.into())).context("parse response"),
+        };
+        limits.check_head(&raw_resp[..head_len])?;
+
+        let head = (|| {
+            let mut builder = Response::builder()
+                .status(http_resp.code.context("no status")?)
+                .version(if http_resp.version.context("no version")? == 0 {
+                    Version::HTTP_10
+                } else {
+                    Version::HTTP_11
+                });
+            for header in http_resp.headers.iter() {
+                builder = builder.header(header.name, header.value)
+            }
+            builder.body(()).context("create response")
+        })()
+        .map_err(parse_error)?;
+
+        return Ok(Some((head, head_len)));
+    }
+}
+
+/// Error of a response head which is not valid HTTP.
+fn parse_error(err: anyhow::Error) -> crate::Error {
+    crate::Error::HttpParse(err.into())
+}
 
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+/// Read the head of the response from the stream, skipping interim (1xx) responses.
+///
+/// Bytes read past the head are left in `raw`, they are the start of the body. The head is
+/// checked against the limits as it arrives.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    raw: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Response<()>> {
+    loop {
+        match parse_head(raw, limits)? {
+            Some((head, head_len)) => {
+                raw.drain(..head_len);
+                if head.status().is_informational()
+                    && head.status() != StatusCode::SWITCHING_PROTOCOLS
+                {
+                    trace!(status = ?head.status(), "skipping interim response");
+                    continue;
+                }
+                return Ok(head);
+            }
+            None => {
+                raw.reserve(READ_SIZE);
+                if stream.read_buf(raw).await.context("read head")? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "unfinished response",
+                    ))
+                    .context("read head");
+                }
+            }
+        }
+    }
+}
 
-    let mut http_resp = httparse::Response::new(&mut headers);
-    let parsed = http_resp
-        .parse(raw_resp.as_slice())
-        .context("parse response")?;
-    if parsed.is_partial() {
-        bail!("unfinished response");
+/// Replace the transfer coding of a fully read response by its decoded length, and append its
+/// trailer fields to the headers.
+pub fn complete_headers(headers: &mut HeaderMap, body_len: usize, trailers: HeaderMap) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, body_len.into());
+    for (name, value) in trailers {
+        if let Some(name) = name {
+            headers.append(name, value);
+        }
     }
+}
+
+/// Whether the connection can be used for another request once the response was read.
+pub fn is_persistent<T>(request_version: Version, response: &Response<T>) -> bool {
+    let connection = response
+        .headers()
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let has_token = |token: &str| connection.iter().any(|t| t.eq_ignore_ascii_case(token));
+
+    if has_token("close") {
+        return false;
+    }
+    if request_version == Version::HTTP_11 && response.version() == Version::HTTP_11 {
+        return true;
+    }
+    has_token("keep-alive")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_head_after_continue() -> Result<()> {
+        let raw_resp = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n";
+        let mut raw = Vec::new();
+        let head = read_head(&mut raw_resp.as_slice(), &mut raw, &Limits::default()).await?;
+
+        assert_eq!(head.status(), StatusCode::OK);
+        assert_eq!(raw, b"5\r\nhello\r\n0\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_head_truncated() {
+        let raw_resp = b"HTTP/1.1 200 OK\r\nContent-Le";
+        let mut raw = Vec::new();
+        assert!(
+            read_head(&mut raw_resp.as_slice(), &mut raw, &Limits::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_head_limits() -> Result<()> {
+        let mut raw_resp = b"HTTP/1.1 200 OK\r\n".to_vec();
+        for i in 0..100 {
+            raw_resp.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw_resp.extend_from_slice(b"\r\n");
+
+        let read = |limits: Limits| {
+            let raw_resp = raw_resp.clone();
+            async move {
+                let mut raw = Vec::new();
+                read_head(&mut raw_resp.as_slice(), &mut raw, &limits).await
+            }
+        };
+        let limit_of = |err: anyhow::Error| err.downcast_ref::<LimitExceeded>().copied();
+
+        // The header array grows past its initial size.
+        let head = read(Limits::default()).await?;
+        assert_eq!(head.headers().len(), 100);
+
+        let err = read(Limits {
+            max_headers: 99,
+            ..Default::default()
+        })
+        .await
+        .expect_err("too many headers");
+        assert_eq!(limit_of(err), Some(LimitExceeded::Headers));
+
+        let err = read(Limits {
+            max_head_size: 1024,
+            ..Default::default()
+        })
+        .await
+        .expect_err("head too large");
+        assert_eq!(limit_of(err), Some(LimitExceeded::HeadSize));
+
+        let err = read(Limits {
+            max_line_length: 12,
+            ..Default::default()
+        })
+        .await
+        .expect_err("line too long");
+        assert_eq!(limit_of(err), Some(LimitExceeded::LineLength));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_head_unbounded_line() {
+        // The limit applies before the line is complete.
+        let mut stream = tokio::io::repeat(b'a');
+        let mut raw = b"HTTP/1.1 200 OK\r\nX-Long: ".to_vec();
+
+        let err = read_head(&mut stream, &mut raw, &Limits::default())
+            .await
+            .expect_err("line too long");
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::LineLength)
+        );
+    }
+
+    #[test]
+    fn test_request_to_raw() -> Result<()> {
+        let upload = || {
+            http::Request::post("https://example.com/upload?id=1")
+                .version(Version::HTTP_11)
+                .header(header::CONTENT_LENGTH, "3")
+                .body(())
+                .map(|request| request.into_parts().0)
+        };
+
+        let raw = request_to_raw(&mut upload()?, Some(12))?;
+        assert_eq!(
+            raw,
+            b"POST /upload?id=1 HTTP/1.1\r\ncontent-length: 12\r\n\r\n"
+        );
+
+        let raw = request_to_raw(&mut upload()?, None)?;
+        assert_eq!(
+            raw,
+            b"POST /upload?id=1 HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n"
+        );
+
+        let (mut parts, _) = http::Request::get("https://example.com/")
+            .version(Version::HTTP_10)
+            .body(())?
+            .into_parts();
+        assert_eq!(
+            request_to_raw(&mut parts, Some(0))?,
+            b"GET / HTTP/1.0\r\n\r\n"
+        );
+        assert!(request_to_raw(&mut parts, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_persistent() {
+        let response = |version, connection: Option<&str>| {
+            let mut builder = Response::builder().version(version);
+            if let Some(connection) = connection {
+                builder = builder.header(header::CONNECTION, connection);
+            }
+            builder.body(()).unwrap()
+        };
 
-    let mut builder = Response::builder()
-        .status(http_resp.code.context("no status")?)
-        .version(if http_resp.version.context("no version")? == 0 {
-            http::Version::HTTP_10
-        } else {
-            http::Version::HTTP_11
-        });
-    for header in http_resp.headers {
-        builder = builder.header(header.name, header.value)
+        assert!(is_persistent(
+            Version::HTTP_11,
+            &response(Version::HTTP_11, None)
+        ));
+        assert!(!is_persistent(
+            Version::HTTP_11,
+            &response(Version::HTTP_11, Some("close"))
+        ));
+        assert!(!is_persistent(
+            Version::HTTP_10,
+            &response(Version::HTTP_11, None)
+        ));
+        assert!(is_persistent(
+            Version::HTTP_10,
+            &response(Version::HTTP_10, Some("Keep-Alive"))
+        ));
     }
-    builder
-        .body(raw_resp.split_off(parsed.unwrap()))
-        .context("create response")
 }
@@ -0,0 +1,172 @@
+//! Transparent decoding of compressed response bodies, see RFC 9110 section 8.4.
+
+use std::io::Read;
+
+use anyhow::{bail, Context, Result};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use http::{header, response, HeaderValue};
+use tracing::debug;
+
+use crate::{Error, LimitExceeded};
+
+/// Default maximum size of a decompressed body.
+const DEFAULT_MAX_DECODED_SIZE: usize = 32 * 1024 * 1024;
+/// Size of the buffer used by the brotli decoder.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Content codings advertised when decompression is enabled.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Settings for the transparent decompression of response bodies.
+#[derive(Clone, Debug)]
+pub struct Decompression {
+    /// Maximum size of a decompressed body, protecting against compression bombs. A larger body
+    /// fails with [`LimitExceeded::DecodedSize`].
+    pub max_decoded_size: usize,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Self {
+            max_decoded_size: DEFAULT_MAX_DECODED_SIZE,
+        }
+    }
+}
+
+impl Decompression {
+    /// Decode the body according to the Content-Encoding of the response.
+    ///
+    /// Once decoded, Content-Encoding is removed and Content-Length is set to the decoded size.
+    /// A body using an unknown coding is returned as is.
+    pub fn decode(&self, parts: &mut response::Parts, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let codings = parts
+            .headers
+            .get_all(header::CONTENT_ENCODING)
+            .iter()
+            .map(|value| value.to_str().context("invalid Content-Encoding"))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect::<Vec<_>>();
+        if codings.is_empty() || body.is_empty() {
+            return Ok(body);
+        }
+        if let Some(unknown) = codings
+            .iter()
+            .find(|coding| !matches!(coding.as_str(), "gzip" | "x-gzip" | "deflate" | "br"))
+        {
+            debug!("not decoding body with unknown coding {}", unknown);
+            return Ok(body);
+        }
+
+        // Codings are listed in the order they were applied.
+        let mut body = body;
+        for coding in codings.iter().rev() {
+            body = self.decode_one(coding, &body)?;
+        }
+
+        parts.headers.remove(header::CONTENT_ENCODING);
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        Ok(body)
+    }
+
+    fn decode_one(&self, coding: &str, encoded: &[u8]) -> Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match coding {
+            "gzip" | "x-gzip" => Box::new(GzDecoder::new(encoded)),
+            // Some servers wrongly send raw deflate data, without the zlib wrapper.
+            "deflate" if is_zlib(encoded) => Box::new(ZlibDecoder::new(encoded)),
+            "deflate" => Box::new(DeflateDecoder::new(encoded)),
+            "br" => Box::new(brotli_decompressor::Decompressor::new(
+                encoded,
+                BROTLI_BUFFER_SIZE,
+            )),
+            _ => bail!("unsupported content coding {}", coding),
+        };
+
+        let mut decoded = Vec::new();
+        decoder
+            .take(self.max_decoded_size as u64 + 1)
+            .read_to_end(&mut decoded)
+            .with_context(|| format!("decode {} body", coding))?;
+        if decoded.len() > self.max_decoded_size {
+            return Err(LimitExceeded::DecodedSize.into());
+        }
+        Ok(decoded)
+    }
+}
+
+/// Whether the data starts with a zlib header, see RFC 1950 section 2.2.
+fn is_zlib(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn parts(encoding: &str, length: usize) -> response::Parts {
+        http::Response::builder()
+            .header(header::CONTENT_ENCODING, encoding)
+            .header(header::CONTENT_LENGTH, length)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn test_decode_gzip() -> Result<()> {
+        let encoded = gzip(b"hello hello hello");
+        let mut parts = parts("gzip", encoded.len());
+
+        let decoded = Decompression::default().decode(&mut parts, encoded)?;
+
+        assert_eq!(decoded, b"hello hello hello");
+        assert!(!parts.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(parts.headers[header::CONTENT_LENGTH], "17");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_bomb() {
+        let encoded = gzip(&[0; 1024 * 1024]);
+        let mut parts = parts("gzip", encoded.len());
+        let decompression = Decompression {
+            max_decoded_size: 1024,
+        };
+
+        let err = decompression.decode(&mut parts, encoded).unwrap_err();
+        assert!(
+            matches!(err, Error::LimitExceeded(LimitExceeded::DecodedSize)),
+            "{:?}",
+            err
+        );
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_unknown_coding() -> Result<()> {
+        let mut parts = parts("zstd", 3);
+
+        let body = Decompression::default().decode(&mut parts, b"abc".to_vec())?;
+
+        assert_eq!(body, b"abc");
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "zstd");
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+//! Delimitation and decoding of HTTP/1.1 response bodies, see RFC 9112 section 6.
+
+use std::cmp::min;
+
+use anyhow::{bail, Context, Result};
+use bytes::BufMut;
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
+
+use super::{LimitExceeded, Limits, INITIAL_HEADERS};
+
+/// How the end of a response body is found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// The response has no body.
+    Empty,
+    /// The body has the given length.
+    Length(u64),
+    /// The body uses the chunked transfer coding.
+    Chunked,
+    /// The body ends when the server closes the connection.
+    Close,
+}
+
+impl Framing {
+    /// Find out the framing of the response to a request using the given method.
+    pub fn of<T>(method: &Method, response: &Response<T>) -> Result<Self> {
+        let status = response.status();
+        if method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(Self::Empty);
+        }
+
+        if response.headers().contains_key(header::TRANSFER_ENCODING) {
+            // Only chunked as the final coding delimits the body, anything else is read until
+            // the connection closes.
+            return Ok(if is_chunked(response.headers()) {
+                Self::Chunked
+            } else {
+                Self::Close
+            });
+        }
+
+        let mut lengths = response
+            .headers()
+            .get_all(header::CONTENT_LENGTH)
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .context("invalid Content-Length")
+            });
+        match lengths.next().transpose()? {
+            None => Ok(Self::Close),
+            Some(length) => {
+                for other in lengths {
+                    if other? != length {
+                        bail!("conflicting Content-Length values");
+                    }
+                }
+                Ok(Self::Length(length))
+            }
+        }
+    }
+}
+
+/// Whether the final transfer coding of a message is chunked.
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .last()
+        .map_or(false, |coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Where the decoder is within the body.
+#[derive(Debug)]
+enum State {
+    /// Reading a body of known length, with the given number of bytes left.
+    Length(u64),
+    /// Expecting a chunk-size line.
+    ChunkSize,
+    /// Reading chunk data, with the given number of bytes left.
+    ChunkData(u64),
+    /// Expecting the CRLF closing a chunk.
+    ChunkEnd,
+    /// Expecting the trailer section following the last chunk.
+    Trailers,
+    /// Reading everything until the connection closes.
+    Close,
+    /// The body is complete.
+    Done,
+}
+
+/// Incremental decoder of a response body, fed with the bytes received after the head.
+#[derive(Debug)]
+pub struct BodyDecoder {
+    state: State,
+    trailers: HeaderMap,
+    limits: Limits,
+    /// Number of body bytes decoded so far.
+    body_len: u64,
+}
+
+impl BodyDecoder {
+    pub fn new(framing: Framing, limits: Limits) -> Self {
+        let state = match framing {
+            Framing::Empty | Framing::Length(0) => State::Done,
+            Framing::Length(length) => State::Length(length),
+            Framing::Chunked => State::ChunkSize,
+            Framing::Close => State::Close,
+        };
+
+        Self {
+            state,
+            trailers: HeaderMap::new(),
+            limits,
+            body_len: 0,
+        }
+    }
+
+    /// Decode as much of the input as possible, appending the body data to `body`.
+    ///
+    /// Returns the number of bytes consumed from the input. Unconsumed bytes are either the start
+    /// of an incomplete line, to be given again with more data, or follow the end of the body.
+    pub fn decode(&mut self, input: &[u8], body: &mut impl BufMut) -> Result<usize> {
+        let mut pos = 0;
+
+        loop {
+            let rest = &input[pos..];
+            match self.state {
+                State::Length(remaining) | State::ChunkData(remaining) => {
+                    let take = min(remaining, rest.len() as u64);
+                    self.add_body_len(take)?;
+                    body.put_slice(&rest[..take as usize]);
+                    pos += take as usize;
+
+                    let remaining = remaining - take;
+                    self.state = match self.state {
+                        State::Length(_) if remaining == 0 => State::Done,
+                        State::Length(_) => State::Length(remaining),
+                        _ if remaining == 0 => State::ChunkEnd,
+                        _ => State::ChunkData(remaining),
+                    };
+                    if remaining > 0 {
+                        break;
+                    }
+                }
+                State::ChunkSize => match httparse::parse_chunk_size(rest) {
+                    Ok(httparse::Status::Complete((len, size))) => {
+                        pos += len;
+                        self.state = if size == 0 {
+                            State::Trailers
+                        } else {
+                            State::ChunkData(size)
+                        };
+                    }
+                    Ok(httparse::Status::Partial) if rest.len() > self.limits.max_line_length => {
+                        return Err(LimitExceeded::LineLength.into())
+                    }
+                    Ok(httparse::Status::Partial) => break,
+                    Err(_) => bail!("invalid chunk size"),
+                },
+                State::ChunkEnd => {
+                    if rest.len() < 2 {
+                        break;
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        bail!("missing CRLF after chunk data");
+                    }
+                    pos += 2;
+                    self.state = State::ChunkSize;
+                }
+                State::Trailers => match self.parse_trailers(rest)? {
+                    Some(len) => {
+                        pos += len;
+                        self.state = State::Done;
+                    }
+                    None => break,
+                },
+                State::Close => {
+                    self.add_body_len(rest.len() as u64)?;
+                    body.put_slice(rest);
+                    pos = input.len();
+                    break;
+                }
+                State::Done => break,
+            }
+        }
+
+        Ok(pos)
+    }
+
+    /// Count decoded body bytes, failing once they exceed the limit.
+    fn add_body_len(&mut self, len: u64) -> Result<()> {
+        self.body_len += len;
+        match self.limits.max_body_size {
+            Some(max) if self.body_len > max => Err(LimitExceeded::BodySize.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse the trailer section, returning its length once it is complete.
+    fn parse_trailers(&mut self, input: &[u8]) -> Result<Option<usize>> {
+        let mut max_headers = INITIAL_HEADERS.min(self.limits.max_headers);
+        loop {
+            let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+            let (len, trailers) = match httparse::parse_headers(input, &mut headers) {
+                Ok(httparse::Status::Complete(complete)) => complete,
+                Ok(httparse::Status::Partial) => {
+                    self.limits.check_head(input)?;
+                    return Ok(None);
+                }
+                Err(httparse::Error::TooManyHeaders) if max_headers < self.limits.max_headers => {
+                    max_headers = max_headers.saturating_mul(2).min(self.limits.max_headers);
+                    continue;
+                }
+                Err(httparse::Error::TooManyHeaders) => return Err(LimitExceeded::Headers.into()),
+                Err(err) => return Err(err).context("parse trailers"),
+            };
+            self.limits.check_head(&input[..len])?;
+
+            for trailer in trailers {
+                self.trailers.append(
+                    HeaderName::from_bytes(trailer.name.as_bytes())
+                        .context("invalid trailer name")?,
+                    HeaderValue::from_bytes(trailer.value).context("invalid trailer value")?,
+                );
+            }
+            return Ok(Some(len));
+        }
+    }
+
+    /// Whether the whole body was decoded.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Signal that the connection was closed, which is only expected for close-delimited bodies.
+    pub fn finish(&mut self) -> Result<()> {
+        match self.state {
+            State::Close | State::Done => {
+                self.state = State::Done;
+                Ok(())
+            }
+            _ => bail!("connection closed before the end of the body"),
+        }
+    }
+
+    /// Take the trailer fields received after the last chunk.
+    pub fn take_trailers(&mut self) -> HeaderMap {
+        std::mem::take(&mut self.trailers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(framing: Framing, input: &[u8]) -> Result<(Vec<u8>, BodyDecoder)> {
+        decode_with(framing, Limits::default(), input)
+    }
+
+    fn decode_with(
+        framing: Framing,
+        limits: Limits,
+        input: &[u8],
+    ) -> Result<(Vec<u8>, BodyDecoder)> {
+        let mut decoder = BodyDecoder::new(framing, limits);
+        let mut body = Vec::new();
+        // Feed byte by byte, to exercise partial lines.
+        let mut pending = Vec::new();
+        for byte in input {
+            pending.push(*byte);
+            let consumed = decoder.decode(&pending, &mut body)?;
+            pending.drain(..consumed);
+        }
+        Ok((body, decoder))
+    }
+
+    #[test]
+    fn test_chunked_with_trailers() -> Result<()> {
+        let (body, mut decoder) = decode_all(
+            Framing::Chunked,
+            b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n",
+        )?;
+
+        assert!(decoder.is_done());
+        assert_eq!(body, b"hello world");
+        assert_eq!(decoder.take_trailers()["expires"], "never");
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked_truncated() -> Result<()> {
+        let (_, mut decoder) = decode_all(Framing::Chunked, b"5\r\nhel")?;
+
+        assert!(!decoder.is_done());
+        assert!(decoder.finish().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_leaves_rest() -> Result<()> {
+        let mut decoder = BodyDecoder::new(Framing::Length(3), Limits::default());
+        let mut body = Vec::new();
+
+        assert_eq!(decoder.decode(b"abcdef", &mut body)?, 3);
+        assert!(decoder.is_done());
+        assert_eq!(body, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() {
+        let limit_of = |limits: Limits, framing: Framing, input: &[u8]| {
+            decode_with(framing, limits, input)
+                .expect_err("limit exceeded")
+                .downcast_ref::<LimitExceeded>()
+                .copied()
+        };
+        let max_body = Limits {
+            max_body_size: Some(4),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            limit_of(max_body, Framing::Chunked, b"3\r\nhel\r\n2\r\nlo\r\n"),
+            Some(LimitExceeded::BodySize)
+        );
+        assert_eq!(
+            limit_of(max_body, Framing::Close, b"hello"),
+            Some(LimitExceeded::BodySize)
+        );
+        assert!(decode_with(Framing::Length(4), max_body, b"hell").is_ok());
+        assert_eq!(
+            limit_of(
+                Limits {
+                    max_line_length: 8,
+                    ..Default::default()
+                },
+                Framing::Chunked,
+                b"5;extension=1\r\nhello\r\n"
+            ),
+            Some(LimitExceeded::LineLength)
+        );
+        assert_eq!(
+            limit_of(
+                Limits {
+                    max_headers: 1,
+                    ..Default::default()
+                },
+                Framing::Chunked,
+                b"0\r\nA: 1\r\nB: 2\r\n\r\n"
+            ),
+            Some(LimitExceeded::Headers)
+        );
+    }
+
+    #[test]
+    fn test_framing() -> Result<()> {
+        let response = |headers: &[(&str, &str)], status: u16| {
+            let mut builder = Response::builder().status(status);
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(()).unwrap()
+        };
+
+        let ok = response(&[("Content-Length", "12")], 200);
+        assert_eq!(Framing::of(&Method::GET, &ok)?, Framing::Length(12));
+        assert_eq!(Framing::of(&Method::HEAD, &ok)?, Framing::Empty);
+        assert_eq!(
+            Framing::of(&Method::GET, &response(&[("Content-Length", "12")], 304))?,
+            Framing::Empty
+        );
+        assert_eq!(
+            Framing::of(
+                &Method::GET,
+                &response(
+                    &[
+                        ("Transfer-Encoding", "gzip, chunked"),
+                        ("Content-Length", "3")
+                    ],
+                    200
+                )
+            )?,
+            Framing::Chunked
+        );
+        assert_eq!(
+            Framing::of(&Method::GET, &response(&[], 200))?,
+            Framing::Close
+        );
+        assert!(Framing::of(
+            &Method::GET,
+            &response(&[("Content-Length", "1"), ("Content-Length", "2")], 200)
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Limits on the size of responses, checked while they are read.
+
+use std::fmt;
+
+/// Default maximum number of header fields in a response head or trailer section.
+const DEFAULT_MAX_HEADERS: usize = 256;
+/// Default maximum size of a response head.
+const DEFAULT_MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Default maximum length of a line of a response head, or of a chunk-size line.
+const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Limits on the responses read by a [`crate::Client`], protecting against misbehaving servers.
+///
+/// They are set for all requests of a client, and can be overridden for a single request by
+/// inserting them in the request's extensions. A response exceeding them fails with the matching
+/// [`LimitExceeded`] error as soon as the limit is crossed, without reading the rest of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of header fields in the head of a response, and in its trailer section.
+    pub max_headers: usize,
+    /// Maximum size of the head of a response, and of its trailer section.
+    pub max_head_size: usize,
+    /// Maximum length of a single line of the head, including the status line, and of a
+    /// chunk-size line.
+    pub max_line_length: usize,
+    /// Maximum size of a response body, once the transfer coding is removed. Unlimited if `None`.
+    pub max_body_size: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            max_body_size: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Check the lines of a head, or of the part of it received so far.
+    pub(crate) fn check_head(&self, head: &[u8]) -> Result<(), LimitExceeded> {
+        if head.len() > self.max_head_size {
+            return Err(LimitExceeded::HeadSize);
+        }
+        if head
+            .split(|byte| *byte == b'\n')
+            .any(|line| line.len() > self.max_line_length)
+        {
+            return Err(LimitExceeded::LineLength);
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when a response exceeds its [`Limits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The head or the trailer section has too many header fields.
+    Headers,
+    /// The head or the trailer section is too large.
+    HeadSize,
+    /// A line of the head, or a chunk-size line, is too long.
+    LineLength,
+    /// The body is too large.
+    BodySize,
+    /// The body is too large once decompressed, see [`crate::Decompression`].
+    DecodedSize,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Headers => "too many header fields in the response",
+            Self::HeadSize => "response head too large",
+            Self::LineLength => "line too long in the response",
+            Self::BodySize => "response body too large",
+            Self::DecodedSize => "decompressed response body too large",
+        })
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
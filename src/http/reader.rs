@@ -0,0 +1,227 @@
+//! Incremental reading of a response body from a connection.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::Stream;
+use http::HeaderMap;
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::warn;
+
+use super::framing::{BodyDecoder, Framing};
+use super::{LimitExceeded, Limits, READ_SIZE};
+
+/// Reads and decodes a response body from a stream, as the caller consumes it.
+pub struct BodyReader<S> {
+    stream: Option<S>,
+    framing: Framing,
+    decoder: BodyDecoder,
+    /// Bytes received but not decoded yet.
+    raw: Vec<u8>,
+    /// Decoded bytes not returned to the caller yet.
+    pending: BytesMut,
+}
+
+impl<S: AsyncRead + Unpin> BodyReader<S> {
+    /// Create a reader for a body with the given framing, starting with the bytes already read
+    /// after the head.
+    pub fn new(stream: S, framing: Framing, raw: Vec<u8>, limits: Limits) -> Self {
+        Self {
+            stream: Some(stream),
+            framing,
+            decoder: BodyDecoder::new(framing, limits),
+            raw,
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// The framing of the body.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Whether the whole body was read by the caller.
+    pub fn is_done(&self) -> bool {
+        self.decoder.is_done() && self.pending.is_empty()
+    }
+
+    /// Take the trailer fields, available once the whole body was read.
+    pub fn take_trailers(&mut self) -> HeaderMap {
+        self.decoder.take_trailers()
+    }
+
+    /// Take back the stream once the whole body was read, if it can carry another response.
+    pub fn take_reusable(&mut self) -> Option<S> {
+        if !self.is_done() || self.framing == Framing::Close {
+            return None;
+        }
+        if !self.raw.is_empty() {
+            warn!(
+                "ignoring {} bytes after the end of the response",
+                self.raw.len()
+            );
+            return None;
+        }
+        self.stream.take()
+    }
+
+    /// Close the stream, so that reading fails from now on.
+    pub fn close(&mut self) {
+        self.stream = None;
+    }
+
+    /// Make decoded data available, returning `false` once the body is complete.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            if !self.pending.is_empty() {
+                return Poll::Ready(Ok(true));
+            }
+
+            let consumed = self
+                .decoder
+                .decode(&self.raw, &mut self.pending)
+                .map_err(invalid_data)?;
+            self.raw.drain(..consumed);
+            if !self.pending.is_empty() {
+                continue;
+            }
+            if self.decoder.is_done() {
+                return Poll::Ready(Ok(false));
+            }
+
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+            };
+            let mut chunk = [0u8; READ_SIZE];
+            let mut buf = ReadBuf::new(&mut chunk);
+            match ready!(Pin::new(stream).poll_read(cx, &mut buf)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    // see rustls/rustls#b84721ef0d72e7f2747105f6b76a6bcbb8aa0ea4
+                    warn!("server didn't close TLS stream");
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+
+            if buf.filled().is_empty() {
+                self.decoder.finish().map_err(invalid_data)?;
+            } else {
+                self.raw.extend_from_slice(buf.filled());
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for BodyReader<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if ready!(self.poll_fill(cx))? {
+            let len = self.pending.len().min(buf.remaining());
+            buf.put_slice(&self.pending[..len]);
+            self.pending.advance(len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> Stream for BodyReader<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match ready!(self.poll_fill(cx)) {
+            Ok(true) => Some(Ok(self.pending.split().freeze())),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        })
+    }
+}
+
+/// Wrap a decoding error as an I/O error, keeping a [`LimitExceeded`] for the caller.
+fn invalid_data(err: anyhow::Error) -> io::Error {
+    match err.downcast::<LimitExceeded>() {
+        Ok(limit) => io::Error::new(io::ErrorKind::InvalidData, limit),
+        Err(err) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            crate::Error::HttpParse(format!("{:#}", err).into()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_read_chunked() -> io::Result<()> {
+        let raw = b"lo\r\n0\r\n\r\nnext response".as_slice();
+        let mut reader = BodyReader::new(
+            raw,
+            Framing::Chunked,
+            b"5\r\nhel".to_vec(),
+            Limits::default(),
+        );
+
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await?;
+
+        assert_eq!(body, b"hello");
+        assert!(reader.is_done());
+        // What follows the body is not part of it.
+        assert!(reader.take_reusable().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_length() -> io::Result<()> {
+        let raw = b" world".as_slice();
+        let mut reader = BodyReader::new(
+            raw,
+            Framing::Length(11),
+            b"hello".to_vec(),
+            Limits::default(),
+        );
+
+        let chunks: Vec<Bytes> = (&mut reader).try_collect().await?;
+
+        assert_eq!(chunks.concat(), b"hello world");
+        assert!(reader.take_reusable().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_truncated() {
+        let raw = b"hel".as_slice();
+        let mut reader = BodyReader::new(raw, Framing::Length(5), Vec::new(), Limits::default());
+
+        let mut body = Vec::new();
+        let err = reader.read_to_end(&mut body).await.expect_err("truncated");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_read_too_large() {
+        // The limit applies while reading, not once the whole body was received.
+        let limits = Limits {
+            max_body_size: Some(1024),
+            ..Default::default()
+        };
+        let mut reader = BodyReader::new(tokio::io::repeat(0), Framing::Close, Vec::new(), limits);
+
+        let mut body = Vec::new();
+        let err = reader.read_to_end(&mut body).await.expect_err("too large");
+        assert!(body.len() <= 1024);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<LimitExceeded>()),
+            Some(&LimitExceeded::BodySize)
+        );
+    }
+}
@@ -0,0 +1,807 @@
+//! Private HTTP cache in front of [`crate::Client::send`], see RFC 9111.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use http::{header, request, HeaderMap, HeaderName, HeaderValue, Response, StatusCode, Version};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::cookies::{parse_cookie_date, unix_now};
+use crate::Error;
+
+/// HTTP_CACHE_DIRNAME is the name of the directory where an [`HttpCache`] is conventionally kept
+/// in the cache directory.
+pub const HTTP_CACHE_DIRNAME: &str = "http-cache";
+
+/// Default maximum size of a cached response body.
+const DEFAULT_MAX_ENTRY_SIZE: usize = 8 * 1024 * 1024;
+/// Default maximum size of all the files of the cache.
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// Fraction of the time since the last modification used as heuristic freshness, see RFC 9111
+/// section 4.2.2.
+const HEURISTIC_FRACTION: u64 = 10;
+
+/// How a response was obtained with an [`HttpCache`], available in its extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// The response was fresh in the cache, nothing was sent.
+    Hit,
+    /// The cached response was confirmed by the server with a 304 (Not Modified).
+    Revalidated,
+    /// The response comes from the server.
+    Miss,
+}
+
+/// Cache of responses stored on disk, used by [`crate::Client::send`].
+///
+/// It behaves as a private cache: responses to GET requests are stored when Cache-Control and
+/// Expires allow it, or heuristically from Last-Modified, and served while they are fresh. Stale
+/// responses are revalidated with If-None-Match and If-Modified-Since, and served again when the
+/// server answers 304 (Not Modified). Responses which could neither be fresh nor revalidated
+/// aren't stored. Unsafe requests invalidate the response cached for their URI.
+///
+/// The cache is kept within a maximum size on disk by removing the responses stored or
+/// revalidated the longest ago. Its files are read and written on the blocking threads of the
+/// Tokio runtime.
+///
+/// Requests isolated with [`crate::IsolationPolicy::PerRequest`] or an explicit token bypass the
+/// cache, as a cached response would link them to earlier requests.
+#[derive(Clone, Debug)]
+pub struct HttpCache {
+    dir: PathBuf,
+    max_entry_size: usize,
+    max_size: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+/// Sizes of the stored responses, kept to stay within the maximum size without listing the
+/// directory on each store.
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<String, Stored>,
+    /// Total size of the files of the responses.
+    size: u64,
+    /// Order of the next response written.
+    next: u64,
+}
+
+/// Sizes of the files of a stored response, and when it was written relative to the others.
+#[derive(Debug, Default)]
+struct Stored {
+    order: u64,
+    head: u64,
+    body: u64,
+}
+
+/// Stored head of a response, along with what is needed to compute its age and match requests.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    uri: String,
+    status: u16,
+    http10: bool,
+    headers: Vec<(String, String)>,
+    /// Values of the request headers named by Vary.
+    vary: Vec<(String, Option<String>)>,
+    /// When the request was sent, in seconds since the Unix epoch.
+    request_time: u64,
+    /// When the response was received, in seconds since the Unix epoch.
+    response_time: u64,
+}
+
+/// Directives of a Cache-Control header, see RFC 9111 section 5.2.
+struct CacheControl(Vec<(String, Option<String>)>);
+
+impl CacheControl {
+    fn of(headers: &HeaderMap) -> Self {
+        Self(
+            headers
+                .get_all(header::CACHE_CONTROL)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|directive| {
+                    let (name, value) = match directive.split_once('=') {
+                        Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                        None => (directive, None),
+                    };
+                    let name = name.trim().to_ascii_lowercase();
+                    (!name.is_empty()).then(|| (name, value.map(str::to_owned)))
+                })
+                .collect(),
+        )
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.0.iter().any(|(directive, _)| directive == name)
+    }
+
+    fn seconds(&self, name: &str) -> Option<u64> {
+        self.0
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.as_deref()?.parse().ok())
+    }
+}
+
+impl HttpCache {
+    /// Use the given directory to store responses, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        let index = Index::scan(&dir)?;
+        Ok(Self {
+            dir,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+
+    /// Don't store responses with a larger body.
+    pub fn with_max_entry_size(mut self, max_entry_size: usize) -> Self {
+        self.max_entry_size = max_entry_size;
+        self
+    }
+
+    /// Keep the files of the cache within the given size, removing the oldest responses.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Remove all the stored responses.
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut index = self.index.lock().expect("cache index lock poisoned");
+        index.entries.clear();
+        index.size = 0;
+        for file in fs::read_dir(&self.dir).context("list cache")? {
+            let file = file.context("list cache")?;
+            fs::remove_file(file.path()).context("remove cached response")?;
+        }
+        Ok(())
+    }
+
+    /// The stored response for the request, if it matches its Vary headers, and whether it is
+    /// fresh enough for it.
+    pub(crate) async fn lookup(
+        &self,
+        request: &request::Parts,
+    ) -> Option<(Response<Vec<u8>>, bool)> {
+        let uri = request.uri.to_string();
+        let headers = request.headers.clone();
+        let (entry, mut response) = self
+            .blocking(move |cache| cache.read(&uri, &headers))
+            .await?;
+
+        let now = unix_now();
+        let age = entry.current_age(response.headers(), now);
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(age));
+
+        let request_cc = CacheControl::of(&request.headers);
+        let response_cc = CacheControl::of(response.headers());
+        let no_cache = request_cc.has("no-cache")
+            || response_cc.has("no-cache")
+            || (request_cc.0.is_empty() && is_pragma_no_cache(&request.headers));
+        let fresh = !no_cache
+            && age < entry.freshness_lifetime(response.headers(), &response_cc)
+            && request_cc.seconds("max-age").map_or(true, |max| age <= max);
+        Some((response, fresh))
+    }
+
+    /// Store the response to the request if it is allowed, and give it back.
+    pub(crate) async fn store(
+        &self,
+        request: &request::Parts,
+        response: Response<Vec<u8>>,
+        request_time: u64,
+    ) -> Response<Vec<u8>> {
+        if !is_storable(request, &response, self.max_entry_size) {
+            self.invalidate(request).await;
+            return response;
+        }
+        let vary = response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let value = header_value(&request.headers, &name);
+                (name, value)
+            })
+            .collect();
+        let entry = Entry {
+            uri: request.uri.to_string(),
+            status: response.status().as_u16(),
+            http10: response.version() == Version::HTTP_10,
+            headers: response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            vary,
+            request_time,
+            response_time: unix_now(),
+        };
+
+        self.blocking(move |cache| {
+            match cache.write(&entry, Some(response.body())) {
+                Ok(()) => cache.evict(),
+                Err(err) => warn!("unable to cache response: {:#}", err),
+            }
+            response
+        })
+        .await
+    }
+
+    /// Update the stored response with the headers of a 304 (Not Modified) answering its
+    /// revalidation, and return it.
+    pub(crate) async fn freshen(
+        &self,
+        request: &request::Parts,
+        not_modified: &Response<Vec<u8>>,
+        request_time: u64,
+    ) -> Option<Response<Vec<u8>>> {
+        let uri = request.uri.to_string();
+        let headers = not_modified.headers().clone();
+        self.blocking(move |cache| cache.update(&uri, &headers, request_time))
+            .await
+    }
+
+    /// Remove the response stored for the URI of the request.
+    pub(crate) async fn invalidate(&self, request: &request::Parts) {
+        let key = key(&request.uri.to_string());
+        self.blocking(move |cache| cache.remove(&key)).await
+    }
+
+    /// Run the file operations on the blocking threads of the runtime, not to stall its tasks.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&Self) -> T + Send + 'static,
+    ) -> T {
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || operation(&cache))
+            .await
+            .expect("cache operation panicked")
+    }
+
+    /// The stored entry of the URI and its response, if it matches the Vary headers of the
+    /// request.
+    fn read(&self, uri: &str, headers: &HeaderMap) -> Option<(Entry, Response<Vec<u8>>)> {
+        let key = key(uri);
+        let entry: Entry = fs::read(self.dir.join(format!("{}.json", key)))
+            .ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())?;
+        if entry.uri != uri
+            || entry
+                .vary
+                .iter()
+                .any(|(name, value)| header_value(headers, name) != *value)
+        {
+            return None;
+        }
+        let body = fs::read(self.dir.join(format!("{}.body", key))).ok()?;
+        let response = entry.to_response(body).ok()?;
+        Some((entry, response))
+    }
+
+    /// Replace the headers of the stored response of the URI with those of a 304 (Not Modified).
+    fn update(
+        &self,
+        uri: &str,
+        not_modified: &HeaderMap,
+        request_time: u64,
+    ) -> Option<Response<Vec<u8>>> {
+        let key = key(uri);
+        let mut entry: Entry = fs::read(self.dir.join(format!("{}.json", key)))
+            .ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())?;
+        for name in not_modified.keys() {
+            if matches!(
+                *name,
+                header::CONTENT_LENGTH | header::TRANSFER_ENCODING | header::CONTENT_ENCODING
+            ) {
+                continue;
+            }
+            entry
+                .headers
+                .retain(|(stored, _)| !name.as_str().eq_ignore_ascii_case(stored));
+            for value in not_modified.get_all(name) {
+                if let Ok(value) = value.to_str() {
+                    entry.headers.push((name.to_string(), value.to_owned()));
+                }
+            }
+        }
+        entry.request_time = request_time;
+        entry.response_time = unix_now();
+        if let Err(err) = self.write(&entry, None) {
+            warn!("unable to update cached response: {:#}", err);
+        }
+
+        let body = fs::read(self.dir.join(format!("{}.body", key))).ok()?;
+        entry.to_response(body).ok()
+    }
+
+    /// Remove the oldest responses until the files of the cache fit in its maximum size.
+    ///
+    /// The age of a response is the last time its head was written, when it was stored or
+    /// revalidated.
+    fn evict(&self) {
+        let mut index = self.index.lock().expect("cache index lock poisoned");
+        while index.size > self.max_size {
+            let key = match index.oldest() {
+                Some(key) => key,
+                None => break,
+            };
+            debug!("evicting cached response {}", key);
+            index.remove(&key);
+            self.remove_files(&key);
+        }
+    }
+
+    /// Remove the response with the given key.
+    fn remove(&self, key: &str) {
+        let mut index = self.index.lock().expect("cache index lock poisoned");
+        index.remove(key);
+        self.remove_files(key);
+    }
+
+    /// Remove the files of the response with the given key.
+    fn remove_files(&self, key: &str) {
+        for extension in ["json", "body"] {
+            match fs::remove_file(self.dir.join(format!("{}.{}", key, extension))) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    warn!("unable to remove cached response: {}", err)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Write the entry, and its body if given, replacing the files atomically.
+    fn write(&self, entry: &Entry, body: Option<&[u8]>) -> Result<()> {
+        let key = key(&entry.uri);
+        let replace = |extension: &str, data: &[u8]| -> Result<()> {
+            let path = self.dir.join(format!("{}.{}", key, extension));
+            let tmp = path.with_extension(format!("{}.tmp", extension));
+            fs::write(&tmp, data).context("write cache file")?;
+            fs::rename(&tmp, &path).context("replace cache file")
+        };
+        if let Some(body) = body {
+            replace("body", body)?;
+        }
+        let head = serde_json::to_vec(entry).context("serialize entry")?;
+        replace("json", &head)?;
+        debug!("cached response of {}", entry.uri);
+
+        let mut index = self.index.lock().expect("cache index lock poisoned");
+        let body = match body {
+            Some(body) => body.len() as u64,
+            None => index.entries.get(&key).map_or(0, |stored| stored.body),
+        };
+        index.insert(
+            key,
+            Stored {
+                order: 0,
+                head: head.len() as u64,
+                body,
+            },
+        );
+        Ok(())
+    }
+}
+
+impl Index {
+    /// List the files of the cache, ordering the responses by the last time their head was
+    /// written.
+    fn scan(dir: &Path) -> Result<Self> {
+        let mut entries: HashMap<String, (SystemTime, Stored)> = HashMap::new();
+        for file in fs::read_dir(dir).context("list cache")? {
+            let file = file.context("list cache")?;
+            let name = file.file_name();
+            let (key, extension) = match name.to_str().and_then(|name| name.split_once('.')) {
+                Some((key, extension)) => (key.to_owned(), extension.to_owned()),
+                None => continue,
+            };
+            // Files being written are only counted once in place.
+            if extension != "json" && extension != "body" {
+                continue;
+            }
+            let metadata = match file.metadata() {
+                Ok(metadata) => metadata,
+                // Removed meanwhile.
+                Err(_) => continue,
+            };
+            // A body without its head is removed first.
+            let (modified, stored) = entries
+                .entry(key)
+                .or_insert((SystemTime::UNIX_EPOCH, Stored::default()));
+            if extension == "json" {
+                *modified = metadata.modified().context("modification time")?;
+                stored.head = metadata.len();
+            } else {
+                stored.body = metadata.len();
+            }
+        }
+
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, (modified, _))| *modified);
+        let mut index = Self::default();
+        for (key, (_, stored)) in entries {
+            index.insert(key, stored);
+        }
+        Ok(index)
+    }
+
+    /// Record the files of a response as the newest ones.
+    fn insert(&mut self, key: String, mut stored: Stored) {
+        self.remove(&key);
+        stored.order = self.next;
+        self.next += 1;
+        self.size += stored.head + stored.body;
+        self.entries.insert(key, stored);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(stored) = self.entries.remove(key) {
+            self.size -= stored.head + stored.body;
+        }
+    }
+
+    /// Key of the response written the longest ago.
+    fn oldest(&self) -> Option<String> {
+        self.entries
+            .iter()
+            .min_by_key(|(_, stored)| stored.order)
+            .map(|(key, _)| key.clone())
+    }
+}
+
+impl Entry {
+    fn to_response(&self, body: Vec<u8>) -> Result<Response<Vec<u8>>> {
+        let mut response = Response::builder()
+            .status(self.status)
+            .version(if self.http10 {
+                Version::HTTP_10
+            } else {
+                Version::HTTP_11
+            })
+            .body(body)
+            .context("build cached response")?;
+        let headers = response.headers_mut();
+        for (name, value) in &self.headers {
+            headers.append(
+                HeaderName::try_from(name.as_str()).context("invalid cached header")?,
+                HeaderValue::try_from(value.as_str()).context("invalid cached header")?,
+            );
+        }
+        Ok(response)
+    }
+
+    /// Age of the response, see RFC 9111 section 4.2.3.
+    fn current_age(&self, headers: &HeaderMap, now: u64) -> u64 {
+        let date = header_date(headers, header::DATE).unwrap_or(self.response_time);
+        let age = header_value(headers, header::AGE.as_str())
+            .and_then(|age| age.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let apparent_age = self.response_time.saturating_sub(date);
+        let response_delay = self.response_time.saturating_sub(self.request_time);
+        let corrected_initial_age = apparent_age.max(age + response_delay);
+        corrected_initial_age + now.saturating_sub(self.response_time)
+    }
+
+    /// How long the response is fresh after it was generated, see RFC 9111 section 4.2.1.
+    fn freshness_lifetime(&self, headers: &HeaderMap, cache_control: &CacheControl) -> u64 {
+        if let Some(max_age) = cache_control.seconds("max-age") {
+            return max_age;
+        }
+        let date = header_date(headers, header::DATE).unwrap_or(self.response_time);
+        if headers.contains_key(header::EXPIRES) {
+            // An invalid date means the response is already expired.
+            return header_date(headers, header::EXPIRES)
+                .map_or(0, |expires| expires.saturating_sub(date));
+        }
+        match header_date(headers, header::LAST_MODIFIED) {
+            Some(last_modified)
+                if is_heuristically_cacheable(self.status) || cache_control.has("public") =>
+            {
+                date.saturating_sub(last_modified) / HEURISTIC_FRACTION
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Whether the response to the request can be stored, see RFC 9111 section 3.
+fn is_storable(request: &request::Parts, response: &Response<Vec<u8>>, max_size: usize) -> bool {
+    let request_cc = CacheControl::of(&request.headers);
+    let response_cc = CacheControl::of(response.headers());
+    let headers = response.headers();
+    let has_freshness = response_cc.has("max-age")
+        || headers.contains_key(header::EXPIRES)
+        || response_cc.has("public")
+        || is_heuristically_cacheable(response.status().as_u16());
+    let has_validator =
+        headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+    // Without a validator, the response can only be served while fresh.
+    let has_lifetime = response_cc
+        .seconds("max-age")
+        .map_or(headers.contains_key(header::EXPIRES), |max_age| max_age > 0);
+
+    request.method == http::Method::GET
+        && !response.status().is_informational()
+        && response.status() != StatusCode::PARTIAL_CONTENT
+        && !request_cc.has("no-store")
+        && !response_cc.has("no-store")
+        && response.body().len() <= max_size
+        && has_freshness
+        && (has_validator || has_lifetime)
+        && !headers
+            .get_all(header::VARY)
+            .iter()
+            .any(|value| value.to_str().map_or(true, |value| value.contains('*')))
+}
+
+/// Whether a response with this status can be cached without explicit freshness, see RFC 9110
+/// section 15.1.
+fn is_heuristically_cacheable(status: u16) -> bool {
+    matches!(
+        status,
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+/// Whether the request has `Pragma: no-cache`, honored when it has no Cache-Control, see RFC 9111
+/// section 5.4.
+fn is_pragma_no_cache(headers: &HeaderMap) -> bool {
+    header_value(headers, header::PRAGMA.as_str()).map_or(false, |pragma| {
+        pragma.to_ascii_lowercase().contains("no-cache")
+    })
+}
+
+/// Conditional headers to revalidate the stored response, see RFC 9111 section 4.3.1.
+pub(crate) fn validators(stored: &Response<Vec<u8>>) -> Vec<(HeaderName, HeaderValue)> {
+    let mut validators = Vec::new();
+    if let Some(etag) = stored.headers().get(header::ETAG) {
+        validators.push((header::IF_NONE_MATCH, etag.clone()));
+    }
+    if let Some(last_modified) = stored.headers().get(header::LAST_MODIFIED) {
+        validators.push((header::IF_MODIFIED_SINCE, last_modified.clone()));
+    }
+    validators
+}
+
+/// Values of the header, joined as in a single field line.
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).trim().to_owned())
+        .collect::<Vec<_>>();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    parse_cookie_date(headers.get(name)?.to_str().ok()?)
+}
+
+/// Name of the files of a URI in the cache.
+fn key(uri: &str) -> String {
+    hex::encode(Sha256::digest(uri.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> request::Parts {
+        let mut builder = http::Request::get(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &str) -> Response<Vec<u8>> {
+        let mut builder = Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    fn entry(response_time: u64) -> Entry {
+        Entry {
+            uri: "https://example.com/".to_owned(),
+            status: 200,
+            http10: false,
+            headers: Vec::new(),
+            vary: Vec::new(),
+            request_time: response_time,
+            response_time,
+        }
+    }
+
+    #[test]
+    fn test_freshness() {
+        let date = "Thu, 01 Jan 2015 00:00:00 GMT";
+        let now = 1_420_070_400;
+        let lifetime = |headers: &[(&str, &str)]| {
+            let response = response(200, headers, "");
+            entry(now).freshness_lifetime(response.headers(), &CacheControl::of(response.headers()))
+        };
+
+        assert_eq!(lifetime(&[("cache-control", "public, max-age=60")]), 60);
+        assert_eq!(
+            lifetime(&[("date", date), ("expires", "Thu, 01 Jan 2015 00:02:00 GMT")]),
+            120
+        );
+        assert_eq!(lifetime(&[("date", date), ("expires", "0")]), 0);
+        assert_eq!(
+            lifetime(&[
+                ("date", date),
+                ("last-modified", "Wed, 31 Dec 2014 00:00:00 GMT")
+            ]),
+            8640
+        );
+        assert_eq!(lifetime(&[]), 0);
+
+        let response = response(200, &[("date", date), ("age", "30")], "");
+        let mut entry = entry(now + 10);
+        entry.request_time = now + 5;
+        assert_eq!(entry.current_age(response.headers(), now + 20), 45);
+    }
+
+    #[test]
+    fn test_storable() {
+        let get = request("https://example.com/", &[]);
+        let storable = |request: &request::Parts, status, headers: &[(&str, &str)]| {
+            is_storable(request, &response(status, headers, "body"), 16)
+        };
+
+        assert!(!storable(&get, 200, &[]));
+        assert!(storable(&get, 200, &[("etag", "\"1\"")]));
+        assert!(storable(&get, 200, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&get, 200, &[("cache-control", "no-store")]));
+        assert!(!storable(&get, 200, &[("vary", "*")]));
+        assert!(!storable(&get, 206, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&get, 500, &[]));
+        assert!(storable(&get, 500, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&get, 200, &[("cache-control", "max-age=0")]));
+        assert!(storable(
+            &get,
+            200,
+            &[("cache-control", "max-age=0"), ("etag", "\"1\"")]
+        ));
+        assert!(!storable(
+            &request("https://example.com/", &[("cache-control", "no-store")]),
+            200,
+            &[]
+        ));
+        assert!(!is_storable(&get, &response(200, &[], "too large body"), 8));
+    }
+
+    #[tokio::test]
+    async fn test_lookup() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cache = HttpCache::new(tmp.path().join(HTTP_CACHE_DIRNAME))?;
+        let get = request("https://example.com/", &[("accept-language", "en")]);
+        let now = unix_now();
+
+        let stored = || {
+            response(
+                200,
+                &[
+                    ("cache-control", "max-age=60"),
+                    ("etag", "\"1\""),
+                    ("vary", "Accept-Language"),
+                ],
+                "body",
+            )
+        };
+        cache.store(&get, stored(), now).await;
+
+        let (cached, fresh) = cache.lookup(&get).await.expect("cached response");
+        assert!(fresh);
+        assert_eq!(cached.body(), b"body");
+        assert_eq!(cached.headers()[header::AGE], "0");
+
+        let no_cache = request(
+            "https://example.com/",
+            &[("accept-language", "en"), ("cache-control", "no-cache")],
+        );
+        let (cached, fresh) = cache.lookup(&no_cache).await.expect("cached response");
+        assert!(!fresh);
+        assert_eq!(
+            validators(&cached),
+            vec![(header::IF_NONE_MATCH, HeaderValue::from_static("\"1\""))]
+        );
+        assert!(cache
+            .lookup(&request(
+                "https://example.com/",
+                &[("accept-language", "fr")]
+            ))
+            .await
+            .is_none());
+        assert!(cache
+            .lookup(&request("https://example.com/other", &[]))
+            .await
+            .is_none());
+
+        let not_modified = response(304, &[("etag", "\"1\""), ("x-updated", "1")], "");
+        let cached = cache
+            .freshen(&get, &not_modified, now)
+            .await
+            .expect("freshened");
+        assert_eq!(cached.status(), StatusCode::OK);
+        assert_eq!(cached.body(), b"body");
+        assert_eq!(cached.headers()["x-updated"], "1");
+        assert_eq!(cached.headers().get_all(header::ETAG).iter().count(), 1);
+
+        cache.invalidate(&get).await;
+        assert!(cache.lookup(&get).await.is_none());
+
+        cache.store(&get, stored(), now).await;
+        cache.clear()?;
+        assert!(cache.lookup(&get).await.is_none());
+
+        let unservable = response(200, &[], "body");
+        cache.store(&get, unservable, now).await;
+        assert!(cache.lookup(&get).await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().join(HTTP_CACHE_DIRNAME);
+        let get = |path: &str| request(&format!("https://example.com/{}", path), &[]);
+        let stored = || response(200, &[("cache-control", "max-age=60")], "body");
+        let now = unix_now();
+
+        let cache = HttpCache::new(&dir)?;
+        cache.store(&get("a"), stored(), now).await;
+        let entry_size = fs::read_dir(&dir)?
+            .map(|file| Ok(file?.metadata()?.len()))
+            .sum::<Result<u64>>()?;
+
+        let cache = cache.with_max_size(2 * entry_size);
+        for path in ["b", "c"] {
+            cache.store(&get(path), stored(), now).await;
+        }
+        assert!(cache.lookup(&get("a")).await.is_none());
+        assert!(cache.lookup(&get("b")).await.is_some());
+        assert!(cache.lookup(&get("c")).await.is_some());
+
+        let not_modified = response(304, &[], "");
+        assert!(cache.freshen(&get("b"), &not_modified, now).await.is_some());
+        cache.store(&get("d"), stored(), now).await;
+        assert!(cache.lookup(&get("b")).await.is_some());
+        assert!(cache.lookup(&get("c")).await.is_none());
+        assert!(cache.lookup(&get("d")).await.is_some());
+
+        // The responses already stored count when the cache is opened again.
+        std::thread::sleep(Duration::from_millis(10));
+        let cache = HttpCache::new(&dir)?.with_max_size(2 * entry_size);
+        cache.store(&get("e"), stored(), now).await;
+        assert!(cache.lookup(&get("b")).await.is_none());
+        assert!(cache.lookup(&get("d")).await.is_some());
+        assert!(cache.lookup(&get("e")).await.is_some());
+        Ok(())
+    }
+}
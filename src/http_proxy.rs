@@ -0,0 +1,445 @@
+//! Local HTTP proxy forwarding CONNECT tunnels and plain-HTTP requests over Tor.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use arti_client::{ErrorKind, HasKind, IsolationToken, TorClient};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http::{header, uri::Authority, Method, StatusCode, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tor_rtcompat::tokio::TokioRustlsRuntime as Runtime;
+
+use crate::isolation::Credentials;
+use crate::proxy::{self, Proxy};
+
+/// Maximum size of the head of a request sent to the proxy.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Maximum number of header fields of a request sent to the proxy.
+const MAX_HEADERS: usize = 128;
+/// Realm of the credentials asked by the proxy.
+const REALM: &str = "lightarti";
+
+/// Settings of [`crate::Client::start_http_proxy`].
+#[derive(Clone, Debug, Default)]
+pub struct HttpProxyConfig {
+    /// Hosts which can be reached through the proxy, all of them if `None`. An entry starting
+    /// with `*.` allows the subdomains of the rest, e.g. `*.example.com` allows
+    /// `www.example.com` but not `example.com`. Other requests are refused with a 403
+    /// (Forbidden).
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Which clients of the proxy can share circuits.
+    pub isolation: ProxyIsolation,
+}
+
+/// Which clients of an HTTP proxy can share circuits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProxyIsolation {
+    /// Clients share circuits, except the ones sending different credentials in
+    /// Proxy-Authorization, which are isolated from each other.
+    #[default]
+    Credentials,
+    /// Like [`ProxyIsolation::Credentials`], but clients without credentials are asked for them
+    /// with a 407 (Proxy Authentication Required), so that each client can be isolated. Any
+    /// credentials are accepted.
+    RequireCredentials,
+    /// Each connection to the proxy uses its own circuits.
+    PerConnection,
+}
+
+/// Request sent to the proxy.
+#[derive(Debug, PartialEq, Eq)]
+struct ProxyRequest {
+    host: String,
+    port: u16,
+    /// Head to send to the server, `None` for a CONNECT tunnel.
+    forward: Option<Vec<u8>>,
+    /// Credentials of the Proxy-Authorization header.
+    credentials: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Start an HTTP proxy on the given loopback address, forwarding the connections with the Tor
+/// client.
+///
+/// CONNECT requests open a tunnel to their target. Requests in absolute form, e.g.
+/// `GET http://example.com/ HTTP/1.1`, are sent to their server with `Connection: close`, and the
+/// connection to the proxy is closed with the response. Their hop-by-hop fields are removed, and
+/// those with a chunked body are refused with a 411 (Length Required).
+pub(crate) async fn start(
+    tor_client: TorClient<Runtime>,
+    addr: SocketAddr,
+    config: HttpProxyConfig,
+) -> Result<Proxy> {
+    let config = Arc::new(config);
+    let credentials = Arc::new(Credentials::default());
+    Proxy::listen(addr, "HTTP", move |stream| {
+        serve(
+            stream,
+            tor_client.clone(),
+            config.clone(),
+            credentials.clone(),
+        )
+    })
+    .await
+}
+
+/// Handle a connection to the proxy, and forward it once Tor connected.
+async fn serve(
+    mut stream: TcpStream,
+    tor_client: TorClient<Runtime>,
+    config: Arc<HttpProxyConfig>,
+    credentials: Arc<Credentials>,
+) -> Result<()> {
+    let mut raw = Vec::new();
+    let request = match read_request(&mut stream, &mut raw).await? {
+        Ok(request) => request,
+        Err(status) => {
+            respond(&mut stream, status).await?;
+            bail!("invalid request: {}", status);
+        }
+    };
+    if !is_allowed(config.allowed_hosts.as_deref(), &request.host) {
+        respond(&mut stream, StatusCode::FORBIDDEN).await?;
+        bail!("host not allowed: {}", request.host);
+    }
+
+    let isolation = match (config.isolation, &request.credentials) {
+        (ProxyIsolation::PerConnection, _) => Some(IsolationToken::new()),
+        (_, Some((username, password))) => Some(credentials.token(username, password)),
+        (ProxyIsolation::RequireCredentials, None) => {
+            respond(&mut stream, StatusCode::PROXY_AUTHENTICATION_REQUIRED).await?;
+            bail!("no credentials");
+        }
+        (ProxyIsolation::Credentials, None) => None,
+    };
+
+    let mut tor_stream =
+        match proxy::connect(&tor_client, &request.host, request.port, isolation).await {
+            Ok(tor_stream) => tor_stream,
+            Err(err) => {
+                respond(&mut stream, status_of(err.kind())).await?;
+                return Err(err)
+                    .with_context(|| format!("tor connect to {}:{}", request.host, request.port));
+            }
+        };
+
+    match &request.forward {
+        Some(head) => tor_stream.write_all(head).await.context("write request")?,
+        None => stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .context("write response")?,
+    }
+    // Bytes sent after the head, e.g. the start of a body or of a TLS handshake.
+    tor_stream.write_all(&raw).await.context("forward data")?;
+    tor_stream.flush().await.context("forward data")?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut tor_stream)
+        .await
+        .context("forward connection")?;
+    Ok(())
+}
+
+/// Read the head of a request, leaving the bytes following it in `raw`. Returns the status to
+/// answer an invalid request with.
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    raw: &mut Vec<u8>,
+) -> Result<std::result::Result<ProxyRequest, StatusCode>> {
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(raw) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let request = parse_request(&parsed);
+                raw.drain(..head_len);
+                return Ok(request);
+            }
+            Ok(httparse::Status::Partial) if raw.len() < MAX_HEAD_SIZE => {}
+            Ok(httparse::Status::Partial) | Err(httparse::Error::TooManyHeaders) => {
+                return Ok(Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE))
+            }
+            Err(_) => return Ok(Err(StatusCode::BAD_REQUEST)),
+        }
+        if stream.read_buf(raw).await.context("read request")? == 0 {
+            bail!("connection closed before the request");
+        }
+    }
+}
+
+fn parse_request(request: &httparse::Request) -> std::result::Result<ProxyRequest, StatusCode> {
+    let method = request.method.unwrap_or_default();
+    let target = request.path.unwrap_or_default();
+    let credentials = request
+        .headers
+        .iter()
+        .find(|field| {
+            field
+                .name
+                .eq_ignore_ascii_case(header::PROXY_AUTHORIZATION.as_str())
+        })
+        .and_then(|field| basic_credentials(field.value));
+
+    if method == Method::CONNECT {
+        let authority = target
+            .parse::<Authority>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let port = authority.port_u16().ok_or(StatusCode::BAD_REQUEST)?;
+        return Ok(ProxyRequest {
+            host: unbracketed(authority.host()).to_owned(),
+            port,
+            forward: None,
+            credentials,
+        });
+    }
+
+    let uri = target.parse::<Uri>().map_err(|_| StatusCode::BAD_REQUEST)?;
+    match uri.scheme_str() {
+        Some("http") => {}
+        // Encrypted requests have to use CONNECT, and origin-form requests are not for a proxy.
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+    let host = uri.host().ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Fields only meant for the connection to the proxy, see RFC 9110 section 7.6.1.
+    let mut hop_by_hop = vec![
+        "connection".to_owned(),
+        "keep-alive".to_owned(),
+        "proxy-authorization".to_owned(),
+        "proxy-connection".to_owned(),
+        "te".to_owned(),
+        "transfer-encoding".to_owned(),
+        "upgrade".to_owned(),
+    ];
+    for field in request.headers.iter() {
+        if field.name.eq_ignore_ascii_case(header::CONNECTION.as_str()) {
+            hop_by_hop.extend(
+                String::from_utf8_lossy(field.value)
+                    .split(',')
+                    .map(|name| name.trim().to_ascii_lowercase()),
+            );
+        }
+        // The body is forwarded as is, so its chunks can't be decoded for the server.
+        if field
+            .name
+            .eq_ignore_ascii_case(header::TRANSFER_ENCODING.as_str())
+        {
+            return Err(StatusCode::LENGTH_REQUIRED);
+        }
+    }
+
+    // The target replaces any Host field, see RFC 9112 section 3.2.2.
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\nHost: {}",
+        method,
+        uri.path_and_query().map_or("/", |path| path.as_str()),
+        request.version.unwrap_or(1),
+        host
+    )
+    .into_bytes();
+    if let Some(port) = uri.port() {
+        head.extend_from_slice(format!(":{}", port).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    for field in request.headers.iter() {
+        let name = field.name.to_ascii_lowercase();
+        if name == "host" || hop_by_hop.contains(&name) {
+            continue;
+        }
+        head.extend_from_slice(field.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(field.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    Ok(ProxyRequest {
+        host: unbracketed(host).to_owned(),
+        port: uri.port_u16().unwrap_or(80),
+        forward: Some(head),
+        credentials,
+    })
+}
+
+/// Host without the brackets of an IPv6 literal, as Tor expects it.
+fn unbracketed(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Username and password of a `Basic` authorization.
+fn basic_credentials(value: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let colon = decoded.iter().position(|byte| *byte == b':')?;
+    Some((decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+/// Whether the host matches an entry of the allowlist, if any.
+fn is_allowed(allowed_hosts: Option<&[String]>, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let allowed_hosts = match allowed_hosts {
+        Some(allowed_hosts) => allowed_hosts,
+        None => return true,
+    };
+    allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.trim_end_matches('.').to_ascii_lowercase();
+        match allowed.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .map_or(false, |subdomain| subdomain.ends_with('.')),
+            None => host == allowed,
+        }
+    })
+}
+
+async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: StatusCode) -> Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        response.push_str(&format!(
+            "Proxy-Authenticate: Basic realm=\"{}\"\r\n",
+            REALM
+        ));
+    }
+    response.push_str("\r\n");
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("write response")
+}
+
+/// Status answering a failure to connect through Tor.
+fn status_of(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::ExitPolicyRejected | ErrorKind::ForbiddenStreamTarget => StatusCode::FORBIDDEN,
+        ErrorKind::TorNetworkTimeout | ErrorKind::ExitTimeout | ErrorKind::RemoteNetworkTimeout => {
+            StatusCode::GATEWAY_TIMEOUT
+        }
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(
+        raw: &[u8],
+    ) -> Result<(std::result::Result<ProxyRequest, StatusCode>, Vec<u8>)> {
+        let mut remaining = Vec::new();
+        let request = read_request(&mut &raw[..], &mut remaining).await?;
+        Ok((request, remaining))
+    }
+
+    #[tokio::test]
+    async fn test_connect() -> Result<()> {
+        let (parsed, remaining) = request(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+              Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n\x16\x03",
+        )
+        .await?;
+        assert_eq!(
+            parsed,
+            Ok(ProxyRequest {
+                host: "example.com".to_owned(),
+                port: 443,
+                forward: None,
+                credentials: Some((b"user".to_vec(), b"pass".to_vec())),
+            })
+        );
+        assert_eq!(remaining, b"\x16\x03");
+
+        let (parsed, _) = request(b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n").await?;
+        let parsed = parsed.expect("valid request");
+        assert_eq!((parsed.host.as_str(), parsed.port), ("2001:db8::1", 443));
+
+        let (parsed, _) = request(b"CONNECT example.com HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(parsed, Err(StatusCode::BAD_REQUEST));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward() -> Result<()> {
+        let (parsed, remaining) = request(
+            b"POST http://example.com:8080/path?q=1 HTTP/1.1\r\nHost: example.com:8080\r\n\
+              Proxy-Connection: keep-alive\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .await?;
+        let parsed = parsed.expect("valid request");
+        assert_eq!((parsed.host.as_str(), parsed.port), ("example.com", 8080));
+        assert_eq!(
+            parsed.forward.as_deref(),
+            Some(
+                &b"POST /path?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 4\r\n\
+                   Connection: close\r\n\r\n"[..]
+            )
+        );
+        assert_eq!(parsed.credentials, None);
+        assert_eq!(remaining, b"body");
+
+        let (parsed, _) = request(b"GET http://example.com HTTP/1.0\r\n\r\n").await?;
+        assert_eq!(
+            parsed.expect("valid request").forward.as_deref(),
+            Some(&b"GET / HTTP/1.0\r\nHost: example.com\r\nConnection: close\r\n\r\n"[..])
+        );
+
+        let (parsed, _) = request(
+            b"GET http://[2001:db8::1]/ HTTP/1.1\r\nHost: other.example\r\n\
+              Connection: keep-alive, X-Hop\r\nX-Hop: 1\r\nTE: trailers\r\n\
+              Upgrade: websocket\r\nAccept: */*\r\n\r\n",
+        )
+        .await?;
+        let parsed = parsed.expect("valid request");
+        assert_eq!((parsed.host.as_str(), parsed.port), ("2001:db8::1", 80));
+        assert_eq!(
+            parsed.forward.as_deref(),
+            Some(
+                &b"GET / HTTP/1.1\r\nHost: [2001:db8::1]\r\nAccept: */*\r\n\
+                   Connection: close\r\n\r\n"[..]
+            )
+        );
+
+        let (parsed, _) =
+            request(b"POST http://example.com/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await?;
+        assert_eq!(parsed, Err(StatusCode::LENGTH_REQUIRED));
+        let (parsed, _) = request(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await?;
+        assert_eq!(parsed, Err(StatusCode::BAD_REQUEST));
+        let (parsed, _) = request(b"GET https://example.com/ HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(parsed, Err(StatusCode::BAD_REQUEST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let allowed = vec!["example.com".to_owned(), "*.example.org".to_owned()];
+
+        assert!(is_allowed(None, "example.net"));
+        assert!(is_allowed(Some(&allowed), "EXAMPLE.com."));
+        assert!(!is_allowed(Some(&allowed), "www.example.com"));
+        assert!(is_allowed(Some(&allowed), "www.example.org"));
+        assert!(!is_allowed(Some(&allowed), "example.org"));
+        assert!(!is_allowed(Some(&allowed), "badexample.org"));
+        assert!(!is_allowed(Some(&[]), "example.com"));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(
+            basic_credentials(b"Basic dXNlcjpwYXNzOndvcmQ="),
+            Some((b"user".to_vec(), b"pass:word".to_vec()))
+        );
+        assert_eq!(basic_credentials(b"Bearer dXNlcjpwYXNz"), None);
+        assert_eq!(basic_credentials(b"Basic dXNlcg=="), None);
+    }
+}
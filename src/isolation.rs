@@ -0,0 +1,162 @@
+//! Isolation of the requests of a client from each other, on separate circuits.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use arti_client::IsolationToken;
+
+/// Maximum number of isolation tokens remembered by an [`Isolator`] or [`Credentials`].
+const MAX_TOKENS: usize = 1024;
+
+/// Which requests of a [`crate::Client`] can share a circuit, and thus be linked by an exit relay.
+///
+/// It is set for all requests of a client, and can be overridden for a single request by inserting
+/// it in the request's extensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IsolationPolicy {
+    /// All requests can share circuits.
+    #[default]
+    None,
+    /// Requests share circuits only with the requests to the same host. Redirects stay isolated
+    /// with the host of the original request, like first-party isolation in browsers. The tokens
+    /// of the hosts requested the longest ago are forgotten when there are too many, so a new
+    /// request to such a host is isolated from the earlier ones.
+    PerHost,
+    /// Each request, including the redirects it follows, uses its own circuits. Its connections
+    /// are not kept for reuse.
+    PerRequest,
+    /// Requests share circuits only with the other requests using the same token.
+    Token(IsolationToken),
+}
+
+/// Isolation tokens handed out to the requests of a client.
+#[derive(Default)]
+pub(crate) struct Isolator {
+    per_host: Mutex<Tokens<String>>,
+}
+
+impl Isolator {
+    /// Isolation token to use for a request to the given host, `None` for the default isolation.
+    pub fn token(&self, policy: IsolationPolicy, host: &str) -> Option<IsolationToken> {
+        match policy {
+            IsolationPolicy::None => None,
+            IsolationPolicy::PerHost => Some(
+                self.per_host
+                    .lock()
+                    .expect("isolation lock poisoned")
+                    .get(host.to_ascii_lowercase()),
+            ),
+            IsolationPolicy::PerRequest => Some(IsolationToken::new()),
+            IsolationPolicy::Token(token) => Some(token),
+        }
+    }
+}
+
+/// Username and password sent by a client of a proxy.
+type UserPass = (Vec<u8>, Vec<u8>);
+
+/// Isolation tokens handed out to the credentials sent by the clients of a proxy, as in Tor's
+/// `IsolateSOCKSAuth`.
+///
+/// The tokens of the credentials used the longest ago are forgotten when there are too many, so
+/// that clients sending new credentials on each connection can't exhaust the memory.
+#[derive(Default)]
+pub(crate) struct Credentials(Mutex<Tokens<UserPass>>);
+
+impl Credentials {
+    /// Isolation token of the given username and password.
+    pub fn token(&self, username: &[u8], password: &[u8]) -> IsolationToken {
+        self.0
+            .lock()
+            .expect("credentials lock poisoned")
+            .get((username.to_vec(), password.to_vec()))
+    }
+}
+
+/// Isolation tokens by key, keeping the [`MAX_TOKENS`] used the most recently.
+struct Tokens<K> {
+    tokens: HashMap<K, (IsolationToken, u64)>,
+    /// Incremented on each use, to find the token used the longest ago.
+    uses: u64,
+}
+
+impl<K> Default for Tokens<K> {
+    fn default() -> Self {
+        Self {
+            tokens: HashMap::new(),
+            uses: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Tokens<K> {
+    /// Token of the key, a new one if it has none.
+    fn get(&mut self, key: K) -> IsolationToken {
+        self.uses += 1;
+        if let Some((token, used)) = self.tokens.get_mut(&key) {
+            *used = self.uses;
+            return *token;
+        }
+        if self.tokens.len() >= MAX_TOKENS {
+            let oldest = self
+                .tokens
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.tokens.remove(&oldest);
+            }
+        }
+        let token = IsolationToken::new();
+        self.tokens.insert(key, (token, self.uses));
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let isolator = Isolator::default();
+
+        assert_eq!(isolator.token(IsolationPolicy::None, "example.com"), None);
+        assert_eq!(
+            isolator.token(IsolationPolicy::PerHost, "example.com"),
+            isolator.token(IsolationPolicy::PerHost, "EXAMPLE.com"),
+        );
+        assert_ne!(
+            isolator.token(IsolationPolicy::PerHost, "example.com"),
+            isolator.token(IsolationPolicy::PerHost, "example.org"),
+        );
+        assert_ne!(
+            isolator.token(IsolationPolicy::PerRequest, "example.com"),
+            isolator.token(IsolationPolicy::PerRequest, "example.com"),
+        );
+
+        let token = IsolationToken::new();
+        assert_eq!(
+            isolator.token(IsolationPolicy::Token(token), "example.com"),
+            Some(token)
+        );
+    }
+
+    #[test]
+    fn test_tokens_bounded() {
+        let credentials = Credentials::default();
+        let first = credentials.token(b"user", b"0");
+        let second = credentials.token(b"user", b"1");
+        for password in 2..MAX_TOKENS {
+            credentials.token(b"user", password.to_string().as_bytes());
+        }
+        // Using the first credentials again keeps them over the second ones.
+        assert_eq!(credentials.token(b"user", b"0"), first);
+
+        credentials.token(b"user", b"new");
+        assert_eq!(credentials.0.lock().unwrap().tokens.len(), MAX_TOKENS);
+        assert_eq!(credentials.token(b"user", b"0"), first);
+        assert_ne!(credentials.token(b"user", b"1"), second);
+    }
+}
@@ -2,15 +2,56 @@
 
 #![deny(missing_docs)]
 
+mod batch;
+mod body;
+mod builder;
+mod churn;
 mod client;
+mod connection;
+mod cookies;
+mod download;
+mod error;
 mod ffi;
 mod flatfiledirmgr;
 mod http;
+mod http_cache;
+mod http_proxy;
+mod isolation;
+mod pool;
+mod progress;
+mod proxy;
+mod redirect;
+mod relays;
+mod retry;
+mod socks;
+mod timeout;
+mod tls;
 
+pub use arti_client::IsolationToken;
+pub use batch::BatchLimits;
+pub use body::{Body, RequestBody};
+pub use builder::{ClientBuilder, FreshnessPolicy, Refresh};
 pub use client::Client;
 pub use client::AUTHORITY_FILENAME;
+pub use cookies::{CookieJar, COOKIES_FILENAME};
+pub use download::{DownloadError, DownloadOptions};
+pub use error::Error;
 pub use flatfiledirmgr::check_directory;
 pub use flatfiledirmgr::CERTIFICATE_FILENAME;
 pub use flatfiledirmgr::CHURN_FILENAME;
 pub use flatfiledirmgr::CONSENSUS_FILENAME;
 pub use flatfiledirmgr::MICRODESCRIPTORS_FILENAME;
+pub use http::{Decompression, LimitExceeded, Limits};
+pub use http_cache::{CacheStatus, HttpCache, HTTP_CACHE_DIRNAME};
+pub use http_proxy::{HttpProxyConfig, ProxyIsolation};
+pub use isolation::IsolationPolicy;
+pub use pool::PoolConfig;
+pub use progress::BootstrapProgress;
+pub use proxy::Proxy;
+pub use redirect::{RedirectPolicy, Redirects};
+pub use relays::{RelayPolicy, RelayPolicyError, RelaySelector};
+pub use retry::RetryPolicy;
+pub use timeout::{Cancelled, Timeout, Timeouts};
+pub use tls::{SpkiHash, TlsConfig};
+pub use tokio_util::sync::CancellationToken;
+pub use tor_cell::relaycell::msg::EndReason;
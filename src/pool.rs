@@ -0,0 +1,170 @@
+//! Pool of idle connections, so that requests to the same host can reuse
+//! an existing Tor stream and TLS session instead of setting up new ones.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Context;
+use std::time::{Duration, Instant};
+
+use arti_client::IsolationToken;
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::debug;
+
+use crate::connection::{Connection, Scheme};
+
+/// Default time an idle connection is kept in the pool.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default number of idle connections kept per host.
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 4;
+
+/// Configuration of the connection pool of a [`crate::Client`].
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// How long an idle connection is kept before being closed.
+    /// The connections of all hosts are checked each time a connection is taken from or given
+    /// back to the pool.
+    pub idle_timeout: Duration,
+    /// Maximum number of idle connections kept per host.
+    /// Setting it to 0 disables connection reuse.
+    pub max_idle_per_host: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+        }
+    }
+}
+
+/// Identifies which connections can be used interchangeably.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PoolKey {
+    scheme: Scheme,
+    host: String,
+    port: u16,
+    /// Isolation token the stream was opened with, `None` for the client's default isolation.
+    isolation: Option<IsolationToken>,
+}
+
+impl PoolKey {
+    pub fn new(scheme: Scheme, host: &str, port: u16, isolation: Option<IsolationToken>) -> Self {
+        Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            isolation,
+        }
+    }
+}
+
+/// A connection waiting to be reused.
+struct IdleConnection {
+    stream: Connection,
+    since: Instant,
+}
+
+/// Idle connections, grouped by [`PoolKey`].
+pub(crate) struct Pool {
+    config: PoolConfig,
+    idle: Mutex<BTreeMap<PoolKey, Vec<IdleConnection>>>,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Whether connections are kept for reuse at all.
+    pub fn is_enabled(&self) -> bool {
+        self.config.max_idle_per_host > 0
+    }
+
+    /// Take an idle connection for the given key, if a live one is available.
+    pub fn checkout(&self, key: &PoolKey) -> Option<Connection> {
+        let mut idle = self.idle.lock().expect("pool lock poisoned");
+        self.prune(&mut idle);
+        let connections = idle.get_mut(key)?;
+
+        let mut found = None;
+        // Most recently used first, as it is the least likely to have been closed.
+        while let Some(mut connection) = connections.pop() {
+            if is_closed(&mut connection.stream) {
+                debug!("dropping connection to {} closed by the server", key.host);
+                continue;
+            }
+            found = Some(connection.stream);
+            break;
+        }
+
+        if connections.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Give a connection back to the pool, once its response has been fully read.
+    pub fn checkin(&self, key: PoolKey, stream: Connection) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().expect("pool lock poisoned");
+        self.prune(&mut idle);
+        let connections = idle.entry(key).or_default();
+        if connections.len() >= self.config.max_idle_per_host {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            stream,
+            since: Instant::now(),
+        });
+    }
+
+    /// Close the connections of all hosts which have been idle for too long, so that those of
+    /// hosts not requested again don't stay open.
+    fn prune(&self, idle: &mut BTreeMap<PoolKey, Vec<IdleConnection>>) {
+        idle.retain(|key, connections| {
+            connections.retain(|connection| {
+                let expired = connection.since.elapsed() > self.config.idle_timeout;
+                if expired {
+                    debug!("dropping expired connection to {}", key.host);
+                }
+                !expired
+            });
+            !connections.is_empty()
+        });
+    }
+}
+
+/// Checks, without blocking, whether the peer closed an idle connection.
+///
+/// An idle connection must not have anything to read: end of stream, an error or unsolicited
+/// data all mean that it can't be reused.
+fn is_closed<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut byte = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut byte);
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_ready()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_closed() {
+        let (mut local, remote) = tokio::io::duplex(64);
+        assert!(!is_closed(&mut local));
+
+        drop(remote);
+        assert!(is_closed(&mut local));
+    }
+}
@@ -0,0 +1,117 @@
+//! Progress of the bootstrap of a client from the directory cache.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::Stream;
+use postage::watch;
+
+/// Step reached while loading the directory cache, see [`crate::ClientBuilder::bootstrap_progress`]
+/// and [`crate::Client::bootstrap_progress`].
+///
+/// The steps come in order, but start again from [`BootstrapProgress::CacheChecked`] when the
+/// directory is loaded again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum BootstrapProgress {
+    /// The directory isn't loaded yet, e.g. the cache is being downloaded.
+    #[default]
+    NotStarted,
+    /// All the files of the directory cache exist.
+    CacheChecked,
+    /// The consensus is valid and signed by the authority.
+    ConsensusVerified,
+    /// The microdescriptors of the relays are loaded.
+    MicrodescriptorsLoaded,
+    /// The directory is sufficient to build circuits, and in use.
+    DirectoryReady,
+}
+
+impl BootstrapProgress {
+    /// Fraction of the bootstrap done, between 0 and 1, suitable for a progress bar.
+    pub fn fraction(&self) -> f32 {
+        match self {
+            Self::NotStarted => 0.0,
+            Self::CacheChecked => 0.25,
+            Self::ConsensusVerified => 0.5,
+            Self::MicrodescriptorsLoaded => 0.75,
+            Self::DirectoryReady => 1.0,
+        }
+    }
+}
+
+impl fmt::Display for BootstrapProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotStarted => "not started",
+            Self::CacheChecked => "directory cache checked",
+            Self::ConsensusVerified => "consensus verified",
+            Self::MicrodescriptorsLoaded => "microdescriptors loaded",
+            Self::DirectoryReady => "directory ready",
+        })
+    }
+}
+
+/// Publisher of the [`BootstrapProgress`], shared by the builder, the directory manager and the
+/// client.
+#[derive(Clone, Debug)]
+pub(crate) struct ProgressSender(Arc<Mutex<watch::Sender<BootstrapProgress>>>);
+
+impl Default for ProgressSender {
+    fn default() -> Self {
+        let (sender, _) = watch::channel();
+        Self(Arc::new(Mutex::new(sender)))
+    }
+}
+
+impl ProgressSender {
+    /// Publish the step reached.
+    pub(crate) fn publish(&self, progress: BootstrapProgress) {
+        *self.0.lock().expect("progress lock poisoned").borrow_mut() = progress;
+    }
+
+    /// Stream of the steps, starting with the current one.
+    ///
+    /// The steps published faster than they are read are skipped.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = BootstrapProgress> + Send + 'static {
+        self.0.lock().expect("progress lock poisoned").subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_progress() {
+        let sender = ProgressSender::default();
+        let mut progress = sender.subscribe().boxed();
+        assert_eq!(progress.next().await, Some(BootstrapProgress::NotStarted));
+        assert!(progress.next().now_or_never().is_none());
+
+        sender.publish(BootstrapProgress::CacheChecked);
+        sender.publish(BootstrapProgress::ConsensusVerified);
+        assert_eq!(
+            progress.next().await,
+            Some(BootstrapProgress::ConsensusVerified)
+        );
+        assert_eq!(
+            sender.subscribe().boxed().next().await,
+            Some(BootstrapProgress::ConsensusVerified)
+        );
+
+        drop(sender);
+        assert_eq!(progress.next().await, None);
+    }
+
+    #[test]
+    fn test_fraction() {
+        assert_eq!(BootstrapProgress::NotStarted.fraction(), 0.0);
+        assert!(
+            BootstrapProgress::CacheChecked.fraction()
+                < BootstrapProgress::MicrodescriptorsLoaded.fraction()
+        );
+        assert_eq!(BootstrapProgress::DirectoryReady.fraction(), 1.0);
+    }
+}
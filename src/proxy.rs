@@ -0,0 +1,140 @@
+//! Local proxies forwarding the connections of other components over Tor.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use anyhow::{ensure, Context, Result};
+use arti_client::{config::BoolOrAuto, DataStream, IsolationToken, StreamPrefs, TorClient};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tor_rtcompat::tokio::TokioRustlsRuntime as Runtime;
+use tracing::{debug, info, warn};
+
+use crate::connection::is_onion;
+
+/// Proxy listening on a loopback address, forwarding the connections of other components, e.g. a
+/// webview, over Tor. See [`crate::Client::start_socks_proxy`] and
+/// [`crate::Client::start_http_proxy`].
+///
+/// Unlike [`crate::Client::send`], a proxy can't always tell plaintext connections from encrypted
+/// ones, so it forwards both. It is stopped with [`Proxy::stop`], or when dropped.
+pub struct Proxy {
+    local_addr: SocketAddr,
+    stop: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Proxy {
+    /// Listen on the given loopback address, and handle each connection with `serve` until
+    /// stopped.
+    pub(crate) async fn listen<F, Fut>(
+        addr: SocketAddr,
+        name: &'static str,
+        serve: F,
+    ) -> Result<Self>
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        ensure!(
+            addr.ip().is_loopback(),
+            "{} proxy must listen on a loopback address, not {}",
+            name,
+            addr.ip()
+        );
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("bind {}", addr))?;
+        let local_addr = listener.local_addr().context("get local address")?;
+        info!("{} proxy listening on {}", name, local_addr);
+
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(accept(listener, name, serve, stop.clone()));
+
+        Ok(Self {
+            local_addr,
+            stop,
+            task: Some(task),
+        })
+    }
+
+    /// Address the proxy listens on, with the port chosen by the system if it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Complete once the proxy is stopped.
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.stop.clone().cancelled_owned()
+    }
+
+    /// Stop listening and close the forwarded connections.
+    pub async fn stop(mut self) {
+        self.stop.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Accept connections until stopped.
+async fn accept<F, Fut>(
+    listener: TcpListener,
+    name: &'static str,
+    serve: F,
+    stop: CancellationToken,
+) where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("{} proxy failed to accept a connection: {}", name, err);
+                    continue;
+                }
+            },
+        };
+
+        let connection = serve(stream);
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {}
+                result = connection => {
+                    if let Err(err) = result {
+                        debug!("{} connection from {} failed: {:#}", name, peer, err);
+                    }
+                }
+            }
+        });
+    }
+    info!("{} proxy stopped", name);
+}
+
+/// Open a stream to the host over Tor, isolated with the token if any.
+pub(crate) async fn connect(
+    tor_client: &TorClient<Runtime>,
+    host: &str,
+    port: u16,
+    isolation: Option<IsolationToken>,
+) -> std::result::Result<DataStream, arti_client::Error> {
+    let mut prefs = StreamPrefs::new();
+    if let Some(token) = isolation {
+        prefs.set_isolation(token);
+    }
+    if is_onion(host) {
+        prefs.connect_to_onion_services(BoolOrAuto::Explicit(true));
+    }
+    tor_client.connect_with_prefs((host, port), &prefs).await
+}
@@ -0,0 +1,253 @@
+//! Following of redirect responses, see RFC 9110 section 15.4.
+
+use anyhow::{Context, Result};
+use http::{header, request, HeaderValue, Method, StatusCode, Uri};
+use tracing::debug;
+use url::Url;
+
+use crate::body::RequestBody;
+
+/// Default maximum number of redirects followed for a request.
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Headers which must not be sent to another origin than the one they were meant for.
+const CREDENTIAL_HEADERS: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+];
+
+/// Which redirects are followed by a [`crate::Client`].
+///
+/// It is set for all requests of a client, and can be overridden for a single request by inserting
+/// it in the request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Redirects are returned as is.
+    None,
+    /// Up to the given number of redirects are followed.
+    Limited(usize),
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self::Limited(DEFAULT_MAX_REDIRECTS)
+    }
+}
+
+impl RedirectPolicy {
+    /// Maximum number of redirects to follow.
+    pub(crate) fn max_redirects(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Limited(max) => *max,
+        }
+    }
+}
+
+/// Redirects that were followed to get a response, available in its extensions.
+#[derive(Clone, Debug)]
+pub struct Redirects {
+    chain: Vec<Uri>,
+    final_uri: Uri,
+}
+
+impl Redirects {
+    pub(crate) fn new(uri: Uri) -> Self {
+        Self {
+            chain: Vec::new(),
+            final_uri: uri,
+        }
+    }
+
+    /// URIs which answered with a redirect, in the order they were requested.
+    pub fn chain(&self) -> &[Uri] {
+        &self.chain
+    }
+
+    /// URI which gave the response.
+    pub fn final_uri(&self) -> &Uri {
+        &self.final_uri
+    }
+
+    fn push(&mut self, uri: Uri) {
+        self.chain.push(std::mem::replace(&mut self.final_uri, uri));
+    }
+}
+
+/// Whether the status is a redirect that can be followed.
+pub(crate) fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// Turn the request into the one following a redirect response.
+///
+/// Returns `false` if the redirect can't be followed, because the body would have to be sent
+/// again but was streamed.
+pub(crate) fn follow(
+    parts: &mut request::Parts,
+    body: &mut RequestBody,
+    redirects: &mut Redirects,
+    status: StatusCode,
+    location: &HeaderValue,
+) -> Result<bool> {
+    let switch_to_get = match status {
+        StatusCode::SEE_OTHER => parts.method != Method::HEAD,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => parts.method == Method::POST,
+        _ => false,
+    };
+    if !switch_to_get && !body.is_replayable() {
+        debug!("not following redirect, the request body can't be sent again");
+        return Ok(false);
+    }
+
+    let current = Url::parse(&parts.uri.to_string()).context("invalid request URI")?;
+    let location = location.to_str().context("invalid Location header")?;
+    let mut next = current
+        .join(location)
+        .context("invalid redirect location")?;
+    // Fragments are never sent to the server.
+    next.set_fragment(None);
+    debug!(%status, "following redirect to {}", next);
+
+    if switch_to_get {
+        parts.method = Method::GET;
+        *body = Vec::new().into();
+        for name in [
+            header::CONTENT_LENGTH,
+            header::CONTENT_TYPE,
+            header::CONTENT_ENCODING,
+            header::TRANSFER_ENCODING,
+        ] {
+            parts.headers.remove(name);
+        }
+    }
+
+    if current.origin() != next.origin() {
+        for name in &CREDENTIAL_HEADERS {
+            parts.headers.remove(name);
+        }
+    }
+    // The Host header is set again from the new URI.
+    parts.headers.remove(header::HOST);
+
+    parts.uri = next.as_str().parse().context("invalid redirect URI")?;
+    redirects.push(parts.uri.clone());
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str) -> request::Parts {
+        let (parts, _) = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::HOST, "example.com")
+            .header(header::AUTHORIZATION, "secret")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(())
+            .expect("build request")
+            .into_parts();
+        parts
+    }
+
+    #[test]
+    fn test_see_other_cross_origin() -> Result<()> {
+        let mut parts = request(Method::POST, "https://example.com/form");
+        let mut body = RequestBody::from(b"data".to_vec());
+        let mut redirects = Redirects::new(parts.uri.clone());
+
+        assert!(follow(
+            &mut parts,
+            &mut body,
+            &mut redirects,
+            StatusCode::SEE_OTHER,
+            &HeaderValue::from_static("https://other.example.com/done"),
+        )?);
+
+        assert_eq!(parts.method, Method::GET);
+        assert_eq!(body.length(), Some(0));
+        assert!(!parts.headers.contains_key(header::AUTHORIZATION));
+        assert!(!parts.headers.contains_key(header::CONTENT_TYPE));
+        assert!(!parts.headers.contains_key(header::HOST));
+        assert_eq!(redirects.chain(), &["https://example.com/form"]);
+        assert_eq!(redirects.final_uri(), "https://other.example.com/done");
+        Ok(())
+    }
+
+    #[test]
+    fn test_temporary_same_origin() -> Result<()> {
+        let mut parts = request(Method::PUT, "https://example.com/a/b?x=1");
+        let mut body = RequestBody::from(b"data".to_vec());
+        let mut redirects = Redirects::new(parts.uri.clone());
+
+        assert!(follow(
+            &mut parts,
+            &mut body,
+            &mut redirects,
+            StatusCode::TEMPORARY_REDIRECT,
+            &HeaderValue::from_static("../c"),
+        )?);
+
+        assert_eq!(parts.method, Method::PUT);
+        assert_eq!(body.length(), Some(4));
+        assert!(parts.headers.contains_key(header::AUTHORIZATION));
+        assert_eq!(parts.uri, "https://example.com/c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_body_not_replayed() -> Result<()> {
+        let mut parts = request(Method::POST, "https://example.com/upload");
+        let mut body = RequestBody::from_reader(&b"data"[..], Some(4));
+        let mut redirects = Redirects::new(parts.uri.clone());
+
+        assert!(!follow(
+            &mut parts,
+            &mut body,
+            &mut redirects,
+            StatusCode::PERMANENT_REDIRECT,
+            &HeaderValue::from_static("/elsewhere"),
+        )?);
+        assert!(redirects.chain().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_body_kept_method() -> Result<()> {
+        let mut parts = request(Method::PUT, "https://example.com/upload");
+        let mut body = RequestBody::from_reader(&b"data"[..], Some(4));
+        let mut redirects = Redirects::new(parts.uri.clone());
+
+        assert!(!follow(
+            &mut parts,
+            &mut body,
+            &mut redirects,
+            StatusCode::FOUND,
+            &HeaderValue::from_static("/elsewhere"),
+        )?);
+        assert_eq!(parts.method, Method::PUT);
+        assert!(redirects.chain().is_empty());
+
+        let mut parts = request(Method::POST, "https://example.com/upload");
+        assert!(follow(
+            &mut parts,
+            &mut body,
+            &mut redirects,
+            StatusCode::FOUND,
+            &HeaderValue::from_static("/elsewhere"),
+        )?);
+        assert_eq!(parts.method, Method::GET);
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+//! Selection of the relays used by a client, like the ExitNodes and ExcludeNodes options of Tor.
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tor_netdoc::doc::netstatus::{MdConsensus, MdConsensusRouterStatus, RelayFlags, RouterStatus};
+use tracing::warn;
+
+/// A set of relays, written as in the configuration of Tor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelaySelector {
+    /// The relay with the given RSA identity fingerprint, written `$` followed by 40 hexadecimal
+    /// digits. The `$` is optional.
+    Fingerprint([u8; 20]),
+    /// The relays located in the given country, written as a two-letter code in braces, e.g.
+    /// `{ch}`. Needs a GeoIP file, see [`RelayPolicy`].
+    Country(String),
+}
+
+impl FromStr for RelaySelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(code) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            ensure!(
+                code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()),
+                "invalid country code {}",
+                s
+            );
+            return Ok(Self::Country(code.to_ascii_uppercase()));
+        }
+
+        let hex = s.strip_prefix('$').unwrap_or(s);
+        let mut fingerprint = [0; 20];
+        hex::decode_to_slice(hex, &mut fingerprint)
+            .map_err(|_| anyhow!("invalid relay selector {}", s))?;
+        Ok(Self::Fingerprint(fingerprint))
+    }
+}
+
+impl fmt::Display for RelaySelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fingerprint(fingerprint) => write!(f, "${}", hex::encode_upper(fingerprint)),
+            Self::Country(code) => write!(f, "{{{}}}", code.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// Restrictions on the relays of the consensus used to build circuits.
+///
+/// Country codes are resolved from the addresses of the relays, with GeoIP files in the format
+/// shipped with Tor: `geoip` lists IPv4 ranges as `FIRST,LAST,CC` with the addresses written as
+/// integers, `geoip6` lists IPv6 ranges as `FIRST,LAST,CC`.
+#[derive(Clone, Debug, Default)]
+pub struct RelayPolicy {
+    /// If not empty, only these relays are used as exits.
+    pub exit_nodes: Vec<RelaySelector>,
+    /// These relays are never used.
+    pub exclude_nodes: Vec<RelaySelector>,
+    /// These relays are never used as exits.
+    pub exclude_exit_nodes: Vec<RelaySelector>,
+    /// GeoIP file of IPv4 addresses.
+    pub geoip: Option<PathBuf>,
+    /// GeoIP file of IPv6 addresses.
+    pub geoip6: Option<PathBuf>,
+}
+
+impl RelayPolicy {
+    /// Whether the policy keeps the consensus as it is.
+    pub fn is_empty(&self) -> bool {
+        self.exit_nodes.is_empty()
+            && self.exclude_nodes.is_empty()
+            && self.exclude_exit_nodes.is_empty()
+    }
+
+    /// Check that the countries of the policy can be resolved.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let uses_countries = self
+            .selectors()
+            .any(|s| matches!(s, RelaySelector::Country(_)));
+        ensure!(
+            !uses_countries || self.geoip.is_some() || self.geoip6.is_some(),
+            "relay policy with country codes needs a GeoIP file"
+        );
+        for path in self.geoip.iter().chain(&self.geoip6) {
+            ensure!(
+                path.is_file(),
+                "GeoIP file {} doesn't exist",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Load the GeoIP files needed to apply the policy.
+    pub(crate) fn load(&self) -> Result<RelayFilter<'_>> {
+        self.validate()?;
+        let mut geoip = GeoIp::default();
+        if self
+            .selectors()
+            .any(|s| matches!(s, RelaySelector::Country(_)))
+        {
+            if let Some(path) = &self.geoip {
+                geoip.v4 = parse_geoip(&read(path)?, |ip| ip.parse::<u32>().ok())?;
+            }
+            if let Some(path) = &self.geoip6 {
+                geoip.v6 = parse_geoip(&read(path)?, |ip| {
+                    ip.parse::<Ipv6Addr>().ok().map(u128::from)
+                })?;
+            }
+        }
+        Ok(RelayFilter {
+            policy: self,
+            geoip,
+        })
+    }
+
+    fn selectors(&self) -> impl Iterator<Item = &RelaySelector> {
+        self.exit_nodes
+            .iter()
+            .chain(&self.exclude_nodes)
+            .chain(&self.exclude_exit_nodes)
+    }
+}
+
+/// Why no circuit can be built with a [`RelayPolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayPolicyError {
+    /// All the relays of the consensus are excluded.
+    NoRelay,
+    /// None of the remaining relays can be used as an exit.
+    NoExit,
+}
+
+impl fmt::Display for RelayPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRelay => write!(f, "no relay of the consensus satisfies the relay policy"),
+            Self::NoExit => write!(
+                f,
+                "no exit relay of the consensus satisfies the relay policy"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RelayPolicyError {}
+
+/// A [`RelayPolicy`] ready to be applied to the relays of a consensus.
+pub(crate) struct RelayFilter<'a> {
+    policy: &'a RelayPolicy,
+    geoip: GeoIp,
+}
+
+impl RelayFilter<'_> {
+    /// Remove the excluded relays, and flag the relays not to use as exits as bad exits, so that
+    /// they are still used in the other positions of a circuit.
+    pub fn apply(&self, relays: &mut Vec<MdConsensusRouterStatus>) {
+        relays.retain(|relay| !self.matches_any(relay, &self.policy.exclude_nodes));
+        *relays = relays
+            .drain(..)
+            .filter_map(|relay| {
+                let allowed = (self.policy.exit_nodes.is_empty()
+                    || self.matches_any(&relay, &self.policy.exit_nodes))
+                    && !self.matches_any(&relay, &self.policy.exclude_exit_nodes);
+                if allowed || relay.is_flagged_bad_exit() {
+                    return Some(relay);
+                }
+                // A relay which can't be flagged is dropped, so that it is never used as an exit.
+                flag_bad_exit(&relay)
+                    .map_err(|err| warn!("dropping relay {}: {}", relay.rsa_identity(), err))
+                    .ok()
+            })
+            .collect();
+    }
+
+    fn matches_any(&self, relay: &MdConsensusRouterStatus, selectors: &[RelaySelector]) -> bool {
+        selectors.iter().any(|selector| match selector {
+            RelaySelector::Fingerprint(fingerprint) => {
+                relay.rsa_identity().as_bytes() == fingerprint
+            }
+            RelaySelector::Country(code) => relay
+                .addrs()
+                .iter()
+                .any(|addr| self.geoip.country(addr.ip()) == Some(code)),
+        })
+    }
+}
+
+/// Copy of the status of a relay, with the BadExit flag set.
+fn flag_bad_exit(
+    relay: &MdConsensusRouterStatus,
+) -> Result<MdConsensusRouterStatus, tor_netdoc::BuildError> {
+    let mut builder = MdConsensus::builder().rs();
+    builder
+        .nickname(relay.nickname().to_owned())
+        .identity(*relay.rsa_identity())
+        .doc_digest(*relay.md_digest())
+        .set_flags(*relay.flags() | RelayFlags::BAD_EXIT)
+        .protos(relay.protovers().clone())
+        .weight(*relay.weight());
+    for addr in relay.addrs() {
+        builder.add_or_port(*addr);
+    }
+    if let Some(version) = relay.version() {
+        builder.version(version.to_string());
+    }
+    builder.build()
+}
+
+/// Country codes of IP ranges, sorted by first address.
+#[derive(Default)]
+struct GeoIp {
+    v4: Vec<(u32, u32, String)>,
+    v6: Vec<(u128, u128, String)>,
+}
+
+impl GeoIp {
+    fn country(&self, ip: IpAddr) -> Option<&String> {
+        match ip {
+            IpAddr::V4(ip) => lookup(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => lookup(&self.v6, u128::from(ip)),
+        }
+    }
+}
+
+fn lookup<T: Copy + Ord>(ranges: &[(T, T, String)], ip: T) -> Option<&String> {
+    let index = ranges.partition_point(|(first, _, _)| *first <= ip);
+    let (_, last, code) = ranges.get(index.checked_sub(1)?)?;
+    (ip <= *last).then_some(code)
+}
+
+fn read(path: &PathBuf) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("read GeoIP file {}", path.display()))
+}
+
+/// Parse the lines `FIRST,LAST,CC` of a GeoIP file, skipping empty lines and comments.
+fn parse_geoip<T: Copy + Ord>(
+    text: &str,
+    parse_ip: impl Fn(&str) -> Option<T>,
+) -> Result<Vec<(T, T, String)>> {
+    let mut ranges = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<_> = line.split(',').collect();
+            let [first, last, code] = fields[..] else {
+                bail!("invalid GeoIP line {}", line);
+            };
+            match (parse_ip(first), parse_ip(last)) {
+                (Some(first), Some(last)) if first <= last => {
+                    Ok((first, last, code.to_ascii_uppercase()))
+                }
+                _ => bail!("invalid GeoIP range {}", line),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ranges.sort_unstable_by_key(|(first, _, _)| *first);
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selector() -> Result<()> {
+        let fingerprint = "$0011223344556677889900112233445566778899";
+        let selector: RelaySelector = fingerprint.parse()?;
+        assert_eq!(selector.to_string(), fingerprint);
+        assert_eq!(selector, fingerprint[1..].parse()?);
+
+        assert_eq!(
+            "{CH}".parse::<RelaySelector>()?,
+            RelaySelector::Country("CH".to_owned())
+        );
+        assert!("{che}".parse::<RelaySelector>().is_err());
+        assert!("$00112233".parse::<RelaySelector>().is_err());
+        assert!("nickname".parse::<RelaySelector>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip() -> Result<()> {
+        let geoip = GeoIp {
+            v4: parse_geoip(
+                "# comment\n16777472,16778239,cn\n16777216,16777471,AU\n",
+                |ip| ip.parse().ok(),
+            )?,
+            v6: parse_geoip(
+                "2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP",
+                |ip| ip.parse::<Ipv6Addr>().ok().map(u128::from),
+            )?,
+        };
+
+        let country = |ip: &str| geoip.country(ip.parse().expect("valid IP")).cloned();
+        assert_eq!(country("1.0.0.1").as_deref(), Some("AU"));
+        assert_eq!(country("1.0.1.0").as_deref(), Some("CN"));
+        assert_eq!(country("1.0.4.0"), None);
+        assert_eq!(country("0.255.255.255"), None);
+        assert_eq!(country("2001:200::1").as_deref(), Some("JP"));
+
+        assert!(parse_geoip("1,2", |ip| ip.parse::<u32>().ok()).is_err());
+        assert!(parse_geoip("2,1,CH", |ip| ip.parse::<u32>().ok()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let relay = |id: u8, addr: &str| {
+            MdConsensus::builder()
+                .rs()
+                .identity([id; 20].into())
+                .add_or_port(addr.parse().expect("valid address"))
+                .doc_digest([id; 32])
+                .set_flags(RelayFlags::EXIT)
+                .protos("Link=1-5".parse().expect("valid protocols"))
+                .build()
+        };
+        let mut relays = vec![
+            relay(1, "1.0.0.1:9001")?,
+            relay(2, "1.0.1.1:9001")?,
+            relay(3, "1.0.2.1:9001")?,
+        ];
+
+        let policy = RelayPolicy {
+            exit_nodes: vec![RelaySelector::Country("AU".to_owned())],
+            exclude_nodes: vec![RelaySelector::Fingerprint([3; 20])],
+            ..Default::default()
+        };
+        let filter = RelayFilter {
+            policy: &policy,
+            geoip: GeoIp {
+                v4: parse_geoip("16777216,16777471,AU\n16777472,16778239,CN", |ip| {
+                    ip.parse().ok()
+                })?,
+                v6: Vec::new(),
+            },
+        };
+        filter.apply(&mut relays);
+
+        assert_eq!(relays.len(), 2);
+        assert!(!relays[0].is_flagged_bad_exit());
+        assert!(relays[1].is_flagged_bad_exit());
+        assert_eq!(relays[1].rsa_identity().as_bytes(), &[2; 20]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut policy = RelayPolicy {
+            exclude_exit_nodes: vec![RelaySelector::Country("RU".to_owned())],
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+
+        policy.exclude_exit_nodes = vec![RelaySelector::Fingerprint([0; 20])];
+        assert!(policy.validate().is_ok());
+    }
+}
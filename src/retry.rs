@@ -0,0 +1,154 @@
+//! Retries of requests which failed because of the Tor network.
+
+use std::io;
+use std::time::Duration;
+
+use arti_client::{ErrorKind, HasKind};
+
+use crate::Timeout;
+
+/// Default number of attempts of a request.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Default delay before the first retry.
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Default maximum delay between two attempts.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// When requests of a [`crate::Client`] are sent again after failing.
+///
+/// Only failures which are likely to succeed on another circuit are retried: Tor failing to open
+/// the stream, the exit relay refusing it, and the stream closing before the response head was
+/// received, e.g. during the TLS handshake. Each retry uses a new circuit, after a delay which
+/// doubles with every attempt. Requests with a body streamed from a reader are never retried.
+///
+/// It is set for all requests of a client, and can be overridden for a single request by
+/// inserting it in the request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of attempts made before giving up, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two attempts.
+    pub max_backoff: Duration,
+    /// Whether requests with a method which is not idempotent, e.g. POST, are retried. The server
+    /// may have processed them already.
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry requests.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before the retry following the given attempt, counted from 1.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether the request failed in a way that another circuit may avoid.
+pub(crate) fn is_retryable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(timeout) = cause.downcast_ref::<Timeout>() {
+            return matches!(timeout, Timeout::Connect | Timeout::Handshake);
+        }
+        if let Some(err) = cause.downcast_ref::<arti_client::Error>() {
+            return is_retryable_kind(err.kind());
+        }
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            return matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            );
+        }
+        false
+    })
+}
+
+pub(crate) fn is_retryable_kind(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::TorAccessFailed
+            | ErrorKind::TorNetworkTimeout
+            | ErrorKind::CircuitCollapse
+            | ErrorKind::CircuitRefused
+            | ErrorKind::TransientFailure
+            | ErrorKind::RelayTooBusy
+            | ErrorKind::ExitPolicyRejected
+            | ErrorKind::ExitTimeout
+            | ErrorKind::RemoteConnectionRefused
+            | ErrorKind::RemoteNetworkFailed
+            | ErrorKind::RemoteNetworkTimeout
+            | ErrorKind::RemoteHostResolutionFailed
+            | ErrorKind::RemoteStreamClosed
+            | ErrorKind::RemoteStreamReset
+            | ErrorKind::RemoteStreamError
+            | ErrorKind::OnionServiceConnectionFailed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4), Duration::from_secs(5));
+        assert_eq!(policy.backoff(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(&anyhow::Error::from(Timeout::Connect)));
+        assert!(!is_retryable(&anyhow::Error::from(Timeout::FirstByte)));
+
+        let eof: Result<()> = Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        assert!(is_retryable(&eof.context("tls connect").unwrap_err()));
+        let closed = anyhow::Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let tls = Error::tls(closed.context("wrap in TLS"));
+        assert!(matches!(tls, Error::Connection(_)));
+        assert!(is_retryable(&tls.into()));
+        let denied = anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!is_retryable(&denied));
+
+        assert!(!is_retryable(&anyhow!("invalid host")));
+        assert!(is_retryable_kind(ErrorKind::ExitPolicyRejected));
+        assert!(!is_retryable_kind(ErrorKind::RemoteHostNotFound));
+    }
+}
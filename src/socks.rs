@@ -0,0 +1,276 @@
+//! Local SOCKS5 proxy forwarding connections over Tor, see RFC 1928 and RFC 1929.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Context, Result};
+use arti_client::{ErrorKind, HasKind, IsolationToken, TorClient};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tor_rtcompat::tokio::TokioRustlsRuntime as Runtime;
+
+use crate::isolation::Credentials;
+use crate::proxy::{self, Proxy};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Start a SOCKS5 proxy on the given loopback address, forwarding the connections with the Tor
+/// client.
+///
+/// Connections authenticated with a username and a password are isolated from the ones using
+/// other credentials, as in Tor's `IsolateSOCKSAuth`. Connections without credentials share
+/// circuits.
+pub(crate) async fn start(tor_client: TorClient<Runtime>, addr: SocketAddr) -> Result<Proxy> {
+    let credentials = Arc::new(Credentials::default());
+    Proxy::listen(addr, "SOCKS", move |stream| {
+        serve(stream, tor_client.clone(), credentials.clone())
+    })
+    .await
+}
+
+/// Handle a SOCKS connection, and forward it once Tor connected.
+async fn serve(
+    mut stream: TcpStream,
+    tor_client: TorClient<Runtime>,
+    credentials: Arc<Credentials>,
+) -> Result<()> {
+    let isolation = handshake(&mut stream, &credentials).await?;
+    let (host, port) = match read_request(&mut stream).await? {
+        Ok(target) => target,
+        Err(code) => {
+            reply(&mut stream, code).await?;
+            bail!("unsupported request");
+        }
+    };
+
+    let mut tor_stream = match proxy::connect(&tor_client, &host, port, isolation).await {
+        Ok(tor_stream) => tor_stream,
+        Err(err) => {
+            reply(&mut stream, reply_code(err.kind())).await?;
+            return Err(err).with_context(|| format!("tor connect to {}:{}", host, port));
+        }
+    };
+    reply(&mut stream, REPLY_SUCCEEDED).await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut tor_stream)
+        .await
+        .context("forward connection")?;
+    Ok(())
+}
+
+/// Negotiate the authentication method, and return the isolation of the credentials if any.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: &Credentials,
+) -> Result<Option<IsolationToken>> {
+    let mut header = [0u8; 2];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("read greeting")?;
+    ensure!(
+        header[0] == VERSION,
+        "unsupported SOCKS version {}",
+        header[0]
+    );
+    let mut methods = vec![0u8; header[1] as usize];
+    stream
+        .read_exact(&mut methods)
+        .await
+        .context("read methods")?;
+
+    if methods.contains(&METHOD_USERNAME_PASSWORD) {
+        stream
+            .write_all(&[VERSION, METHOD_USERNAME_PASSWORD])
+            .await
+            .context("write method")?;
+
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).await.context("read auth")?;
+        ensure!(
+            version[0] == AUTH_VERSION,
+            "unsupported authentication version {}",
+            version[0]
+        );
+        let username = read_field(stream).await.context("read username")?;
+        let password = read_field(stream).await.context("read password")?;
+        // Any credentials are accepted, they only select the isolation.
+        stream
+            .write_all(&[AUTH_VERSION, 0])
+            .await
+            .context("write auth status")?;
+        Ok(Some(credentials.token(&username, &password)))
+    } else if methods.contains(&METHOD_NONE) {
+        stream
+            .write_all(&[VERSION, METHOD_NONE])
+            .await
+            .context("write method")?;
+        Ok(None)
+    } else {
+        stream
+            .write_all(&[VERSION, METHOD_UNACCEPTABLE])
+            .await
+            .context("write method")?;
+        bail!("no acceptable authentication method")
+    }
+}
+
+/// Read a CONNECT request, returning its target, or the reply code of an unsupported request.
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<std::result::Result<(String, u16), u8>> {
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("read request")?;
+    ensure!(
+        header[0] == VERSION,
+        "unsupported SOCKS version {}",
+        header[0]
+    );
+
+    let host = match header[3] {
+        ADDRESS_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await.context("read address")?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await.context("read address")?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ADDRESS_DOMAIN => {
+            let domain = read_field(stream).await.context("read address")?;
+            match String::from_utf8(domain) {
+                Ok(domain) => domain,
+                Err(_) => return Ok(Err(REPLY_ADDRESS_NOT_SUPPORTED)),
+            }
+        }
+        _ => return Ok(Err(REPLY_ADDRESS_NOT_SUPPORTED)),
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await.context("read port")?;
+
+    if header[1] != COMMAND_CONNECT {
+        return Ok(Err(REPLY_COMMAND_NOT_SUPPORTED));
+    }
+    Ok(Ok((host, u16::from_be_bytes(port))))
+}
+
+/// Read a field prefixed by its length on one byte.
+async fn read_field<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut field = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut field).await?;
+    Ok(field)
+}
+
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> Result<()> {
+    // The bound address is of no use through Tor.
+    stream
+        .write_all(&[VERSION, code, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+        .context("write reply")
+}
+
+/// Reply code of a failure to connect through Tor.
+fn reply_code(kind: ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::ExitPolicyRejected | ErrorKind::ForbiddenStreamTarget => REPLY_NOT_ALLOWED,
+        ErrorKind::RemoteHostNotFound
+        | ErrorKind::RemoteHostResolutionFailed
+        | ErrorKind::RemoteNetworkFailed
+        | ErrorKind::OnionServiceNotFound
+        | ErrorKind::OnionServiceNotRunning
+        | ErrorKind::OnionServiceConnectionFailed => REPLY_HOST_UNREACHABLE,
+        ErrorKind::RemoteConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::TorNetworkTimeout | ErrorKind::ExitTimeout | ErrorKind::RemoteNetworkTimeout => {
+            REPLY_TTL_EXPIRED
+        }
+        _ => REPLY_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake() -> Result<()> {
+        let credentials = Credentials::default();
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 2, 0, 2, 1, 1, b'a', 1, b'b']).await?;
+        let token = handshake(&mut server, &credentials).await?;
+        let mut answer = [0u8; 4];
+        client.read_exact(&mut answer).await?;
+        assert_eq!(answer, [5, 2, 1, 0]);
+        assert_eq!(token, Some(credentials.token(b"a", b"b")));
+        assert_ne!(token, Some(credentials.token(b"a", b"c")));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0]).await?;
+        assert_eq!(handshake(&mut server, &credentials).await?, None);
+        let mut answer = [0u8; 2];
+        client.read_exact(&mut answer).await?;
+        assert_eq!(answer, [5, 0]);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 1]).await?;
+        assert!(handshake(&mut server, &credentials).await.is_err());
+        client.read_exact(&mut answer).await?;
+        assert_eq!(answer, [5, 0xff]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_request() -> Result<()> {
+        let request = |bytes: &'static [u8]| async move { read_request(&mut &bytes[..]).await };
+
+        assert!(request(&[5, 1, 0, 3, 11, b'e']).await.is_err());
+        assert_eq!(
+            request(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb").await?,
+            Ok(("example.com".to_owned(), 443))
+        );
+        assert_eq!(
+            request(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).await?,
+            Ok(("127.0.0.1".to_owned(), 80))
+        );
+        assert_eq!(
+            request(&[5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80]).await?,
+            Ok(("::1".to_owned(), 80))
+        );
+        assert_eq!(
+            request(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await?,
+            Err(REPLY_COMMAND_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            request(&[5, 1, 0, 9, 0, 80]).await?,
+            Err(REPLY_ADDRESS_NOT_SUPPORTED)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Deadlines and cancellation of requests.
+
+use std::fmt;
+use std::future::{pending, Future};
+use std::io;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::http::LimitExceeded;
+
+/// Default time allowed to open a stream through Tor.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default time allowed for the TLS handshake.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time allowed between sending a request and receiving its response head.
+const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on how long the steps of a request can take, `None` meaning unlimited.
+///
+/// They are set for all requests of a client, and can be overridden for a single request by
+/// inserting them in the request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time allowed to open a stream through Tor.
+    pub connect: Option<Duration>,
+    /// Time allowed for the TLS handshake.
+    pub handshake: Option<Duration>,
+    /// Time allowed between sending the request and receiving the head of its response.
+    pub first_byte: Option<Duration>,
+    /// Time allowed for the whole request, including following redirects and reading the body.
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(DEFAULT_CONNECT_TIMEOUT),
+            handshake: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            first_byte: Some(DEFAULT_FIRST_BYTE_TIMEOUT),
+            total: None,
+        }
+    }
+}
+
+/// Error returned when a step of a request took longer than allowed by its [`Timeouts`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// Opening the stream through Tor timed out.
+    Connect,
+    /// The TLS handshake timed out.
+    Handshake,
+    /// The response head didn't arrive in time.
+    FirstByte,
+    /// The whole request didn't complete in time.
+    Total,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "timed out connecting through Tor",
+            Self::Handshake => "timed out during the TLS handshake",
+            Self::FirstByte => "timed out waiting for the response",
+            Self::Total => "timed out before the request completed",
+        })
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Error returned when a request was cancelled through its [`CancellationToken`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Run a step of a request, failing with the given [`Timeout`] if it takes too long.
+pub(crate) async fn limit<T>(
+    duration: Option<Duration>,
+    timeout: Timeout,
+    step: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, step)
+            .await
+            .unwrap_or_else(|_| Err(timeout.into())),
+        None => step.await,
+    }
+}
+
+/// Deadline and cancellation covering a whole request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Abort {
+    deadline: Option<Instant>,
+    cancel: Option<CancellationToken>,
+}
+
+impl Abort {
+    pub fn new(total: Option<Duration>, cancel: Option<CancellationToken>) -> Self {
+        Self {
+            deadline: total.map(|total| Instant::now() + total),
+            cancel,
+        }
+    }
+
+    /// Run the future, unless the deadline expires or the request is cancelled first.
+    pub async fn guard<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            result = future => result,
+            err = self.clone().wait() => Err(from_io(err)),
+        }
+    }
+
+    /// Wait for the deadline to expire or the request to be cancelled.
+    ///
+    /// The error wraps either [`Timeout::Total`] or [`Cancelled`].
+    pub async fn wait(self) -> io::Error {
+        let expired = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => pending().await,
+            }
+        };
+        let cancelled = async {
+            match self.cancel {
+                Some(cancel) => cancel.cancelled_owned().await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = expired => io::Error::new(io::ErrorKind::TimedOut, Timeout::Total),
+            _ = cancelled => io::Error::new(io::ErrorKind::Interrupted, Cancelled),
+        }
+    }
+
+    /// Whether there is anything to wait for.
+    pub fn is_set(&self) -> bool {
+        self.deadline.is_some() || self.cancel.is_some()
+    }
+}
+
+/// Convert an I/O error to an [`anyhow::Error`], exposing a wrapped [`Timeout`], [`Cancelled`],
+/// [`LimitExceeded`] or [`crate::Error`] so that callers can downcast to them.
+pub(crate) fn from_io(err: io::Error) -> anyhow::Error {
+    if !err.get_ref().map_or(false, |inner| {
+        inner.is::<Timeout>()
+            || inner.is::<Cancelled>()
+            || inner.is::<LimitExceeded>()
+            || inner.is::<crate::Error>()
+    }) {
+        return err.into();
+    }
+    let inner = err.into_inner().expect("checked above");
+    let inner = match inner.downcast::<Timeout>() {
+        Ok(timeout) => return (*timeout).into(),
+        Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<LimitExceeded>() {
+        Ok(limit) => return (*limit).into(),
+        Err(inner) => inner,
+    };
+    match inner.downcast::<crate::Error>() {
+        Ok(err) => (*err).into(),
+        Err(_) => Cancelled.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit() {
+        let err = limit(Some(Duration::from_millis(1)), Timeout::Connect, async {
+            pending::<Result<()>>().await
+        })
+        .await
+        .expect_err("timed out");
+
+        assert_eq!(err.downcast_ref::<Timeout>(), Some(&Timeout::Connect));
+    }
+
+    #[tokio::test]
+    async fn test_guard_cancelled() {
+        let cancel = CancellationToken::new();
+        let abort = Abort::new(None, Some(cancel.clone()));
+        cancel.cancel();
+
+        let err = abort
+            .guard(async { pending::<Result<()>>().await })
+            .await
+            .expect_err("cancelled");
+
+        assert!(err.is::<Cancelled>());
+    }
+}
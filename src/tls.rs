@@ -0,0 +1,225 @@
+//! TLS settings: trusted roots and pinned public keys.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{
+    self,
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, RootCertStore, ServerName,
+};
+use tracing::warn;
+
+use crate::Error;
+
+/// SHA-256 hash of a DER-encoded SubjectPublicKeyInfo.
+pub type SpkiHash = [u8; 32];
+
+/// TLS settings of a [`crate::Client`].
+///
+/// Servers are always authenticated against the bundled web PKI roots, plus the root
+/// certificates added here, e.g. for private CAs. The public keys of a host can additionally be
+/// pinned, so that a certificate wrongly issued by a trusted CA is still refused.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// Pinned SPKI hashes, by lowercase hostname.
+    pins: HashMap<String, Vec<SpkiHash>>,
+    /// DER-encoded root certificates trusted in addition to the web PKI.
+    roots: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// Pin the public key of the given host, by the SHA-256 hash of its DER-encoded
+    /// SubjectPublicKeyInfo.
+    ///
+    /// A host with pins is accepted only if one of the certificates of its chain has one of the
+    /// pinned keys, which lets backup keys and intermediate CA keys be pinned alongside the current
+    /// one. Only the certificates linked by their signatures to the server's certificate count, not
+    /// any other certificate the server sends. The key of a root CA can't be pinned, as servers
+    /// don't send their root certificate.
+    pub fn add_pin(&mut self, host: &str, spki_sha256: SpkiHash) {
+        self.pins
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(spki_sha256);
+    }
+
+    /// Trust the given DER-encoded root certificate, in addition to the web PKI roots.
+    pub fn add_root_certificate(&mut self, der: &[u8]) -> Result<(), Error> {
+        RootCertStore::empty()
+            .add(&Certificate(der.to_vec()))
+            .context("invalid root certificate")?;
+        self.roots.push(der.to_vec());
+        Ok(())
+    }
+
+    /// Build the rustls configuration to connect to the given host.
+    pub(crate) fn client_config(&self, host: &str) -> Result<rustls::ClientConfig> {
+        let root_store = self.root_store()?;
+        let builder = rustls::ClientConfig::builder().with_safe_defaults();
+        Ok(match self.pins.get(&host.to_ascii_lowercase()) {
+            Some(pins) => builder
+                .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+                    inner: WebPkiVerifier::new(root_store, None),
+                    pins: pins.clone(),
+                }))
+                .with_no_client_auth(),
+            None => builder
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        })
+    }
+
+    /// The web PKI roots, and the added root certificates.
+    fn root_store(&self) -> Result<RootCertStore> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for root in &self.roots {
+            root_store
+                .add(&Certificate(root.clone()))
+                .context("add root certificate")?;
+        }
+        Ok(root_store)
+    }
+}
+
+/// Verifies the certificate chain against the roots, then checks that it contains a pinned key.
+struct PinningVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<SpkiHash>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        for cert in issuers_chain(end_entity, intermediates) {
+            match spki_hash(&cert.0) {
+                Ok(hash) if self.pins.contains(&hash) => return Ok(verified),
+                Ok(_) => {}
+                Err(err) => warn!("unable to hash certificate key: {:#}", err),
+            }
+        }
+        Err(rustls::Error::General(
+            "no pinned public key in the certificate chain".to_owned(),
+        ))
+    }
+}
+
+/// The end-entity certificate, and the intermediates which issued it, directly or through other
+/// intermediates.
+///
+/// The other certificates sent by the server aren't part of the chain which was verified, so a
+/// server could add any certificate with a pinned key to them.
+fn issuers_chain<'a>(
+    end_entity: &'a Certificate,
+    intermediates: &'a [Certificate],
+) -> Vec<&'a Certificate> {
+    let parsed = |cert: &'a Certificate| x509_signature::parse_certificate(&cert.0).ok();
+    let mut chain = vec![end_entity];
+    let mut issued = parsed(end_entity).into_iter().collect::<Vec<_>>();
+    let mut remaining = intermediates
+        .iter()
+        .filter_map(|cert| Some((cert, parsed(cert)?)))
+        .collect::<Vec<_>>();
+    // Cross-signed intermediates can give several paths, so all the issuers are kept.
+    while let Some(index) = remaining.iter().position(|(_, issuer)| {
+        issued
+            .iter()
+            .any(|cert| cert.check_issued_by(issuer).is_ok())
+    }) {
+        let (cert, issuer) = remaining.swap_remove(index);
+        chain.push(cert);
+        issued.push(issuer);
+    }
+    chain
+}
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of a DER-encoded certificate.
+fn spki_hash(der: &[u8]) -> Result<SpkiHash> {
+    let cert = x509_signature::parse_certificate(der)
+        .map_err(|err| anyhow::anyhow!("{:?}", err))
+        .context("parse certificate")?;
+    Ok(Sha256::digest(cert.subject_public_key_info().spki()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_root() {
+        let mut config = TlsConfig::default();
+        assert!(config.add_root_certificate(b"not a certificate").is_err());
+        assert!(config.roots.is_empty());
+    }
+
+    #[test]
+    fn test_pin_case_insensitive() -> Result<()> {
+        let mut config = TlsConfig::default();
+        config.add_pin("Example.COM", [0; 32]);
+
+        assert_eq!(config.pins["example.com"], vec![[0; 32]]);
+        config.client_config("example.com")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_on_issuers_chain() -> Result<()> {
+        // A chain of a root, an intermediate and a certificate for example.com, and an unrelated
+        // CA certificate.
+        let root = include_bytes!("../tests/certs/root.der");
+        let intermediate = Certificate(include_bytes!("../tests/certs/intermediate.der").to_vec());
+        let leaf = Certificate(include_bytes!("../tests/certs/leaf.der").to_vec());
+        let unrelated = Certificate(include_bytes!("../tests/certs/pinned.der").to_vec());
+
+        let mut config = TlsConfig::default();
+        config.add_root_certificate(root)?;
+        let verify = |pin: &[u8]| {
+            let verifier = PinningVerifier {
+                inner: WebPkiVerifier::new(config.root_store().unwrap(), None),
+                pins: vec![spki_hash(pin).unwrap()],
+            };
+            verifier.verify_server_cert(
+                &leaf,
+                &[intermediate.clone(), unrelated.clone()],
+                &ServerName::try_from("example.com").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+
+        assert!(verify(&leaf.0).is_ok());
+        assert!(verify(&intermediate.0).is_ok());
+        // Sent by the server, but not part of the chain.
+        assert!(verify(&unrelated.0).is_err());
+        // Not sent by the server.
+        assert!(verify(root).is_err());
+        Ok(())
+    }
+}
@@ -1,11 +1,17 @@
-use http::request::{Builder, Parts};
+use std::path::Path;
+
+use futures::{StreamExt, TryStreamExt};
 use http::Request;
 use lightarti_rest::AUTHORITY_FILENAME;
 use lightarti_rest::CERTIFICATE_FILENAME;
 use lightarti_rest::CHURN_FILENAME;
 use lightarti_rest::CONSENSUS_FILENAME;
 use lightarti_rest::MICRODESCRIPTORS_FILENAME;
-use lightarti_rest::{check_directory, Client};
+use lightarti_rest::{
+    check_directory, BatchLimits, BootstrapProgress, CacheStatus, Client, ClientBuilder,
+    Decompression, DownloadOptions, Error, HttpCache, RequestBody, RetryPolicy, Timeout, Timeouts,
+    HTTP_CACHE_DIRNAME,
+};
 use url::Url;
 
 mod utils;
@@ -27,6 +33,11 @@ pub async fn test_get_many_headers() {
     test_get("https://www.sunrise.ch/en/home").await;
 }
 
+#[tokio::test]
+pub async fn test_get_redirect() {
+    test_get("https://httpbin.org/redirect/2").await;
+}
+
 #[tokio::test]
 pub async fn test_post() {
     test_client(
@@ -39,6 +50,230 @@ pub async fn test_post() {
     .await;
 }
 
+#[tokio::test]
+pub async fn test_get_chunked() {
+    test_client(
+        Request::get("https://httpbin.org/stream/3")
+            .header("Host", "httpbin.org")
+            .version(http::Version::HTTP_11)
+            .body(vec![])
+            .expect("Couldn't build request"),
+    )
+    .await;
+}
+
+#[tokio::test]
+pub async fn test_get_streaming() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = new_client(cache.path()).await;
+    let request = Request::get("https://httpbin.org/bytes/65536")
+        .header("Host", "httpbin.org")
+        .version(http::Version::HTTP_11)
+        .body(vec![])
+        .expect("Couldn't build request");
+
+    let mut body = client
+        .send_streaming(request)
+        .await
+        .expect("send request")
+        .into_body();
+    let mut received = 0;
+    while let Some(chunk) = body.try_next().await.expect("read body") {
+        received += chunk.len();
+    }
+    assert_eq!(received, 65536);
+}
+
+#[tokio::test]
+pub async fn test_post_streaming() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = new_client(cache.path()).await;
+    let request = Request::post("https://httpbin.org/post")
+        .header("Host", "httpbin.org")
+        .version(http::Version::HTTP_11)
+        .body(RequestBody::from_reader(&b"key1=val1&key2=val2"[..], None))
+        .expect("Couldn't build request");
+
+    let response = client.send(request).await.expect("send request");
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+pub async fn test_get_gzip() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = client_builder(cache.path())
+        .decompression(Decompression::default())
+        .build()
+        .await
+        .expect("create client");
+    let request = Request::get("https://httpbin.org/gzip")
+        .header("Host", "httpbin.org")
+        .version(http::Version::HTTP_11)
+        .body(vec![])
+        .expect("Couldn't build request");
+
+    let response = client.send(request).await.expect("send request");
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("content-encoding").is_none());
+    serde_json::from_slice::<serde_json::Value>(response.body()).expect("decoded JSON");
+}
+
+#[tokio::test]
+pub async fn test_get_plain_http() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = new_client(cache.path()).await;
+    let request = || {
+        Request::get("http://httpbin.org/get")
+            .version(http::Version::HTTP_11)
+            .body(vec![])
+            .expect("Couldn't build request")
+    };
+    assert!(client.send(request()).await.is_err());
+
+    let client = client_builder(cache.path())
+        .plain_http(true)
+        .build()
+        .await
+        .expect("create client");
+    let response = client.send(request()).await.expect("send request");
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+pub async fn test_get_onion() {
+    test_get("https://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion").await;
+}
+
+#[tokio::test]
+pub async fn test_get_first_byte_timeout() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = Client::new(cache.path()).await.expect("create client");
+    let mut request = Request::get("https://httpbin.org/delay/10")
+        .header("Host", "httpbin.org")
+        .version(http::Version::HTTP_11)
+        .body(vec![])
+        .expect("Couldn't build request");
+    request.extensions_mut().insert(Timeouts {
+        first_byte: Some(std::time::Duration::from_secs(2)),
+        ..Timeouts::default()
+    });
+
+    let err = client.send(request).await.expect_err("request timed out");
+    assert!(matches!(err, Error::Timeout(Timeout::FirstByte)));
+    assert!(err.is_retryable());
+}
+
+#[tokio::test]
+pub async fn test_get_http_cache() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let http_cache = HttpCache::new(cache.path().join(HTTP_CACHE_DIRNAME)).expect("create cache");
+    let client = client_builder(cache.path())
+        .http_cache(http_cache)
+        .build()
+        .await
+        .expect("create client");
+    let request = || {
+        Request::get("https://httpbin.org/cache/60")
+            .version(http::Version::HTTP_11)
+            .body(vec![])
+            .expect("Couldn't build request")
+    };
+
+    let response = client.send(request()).await.expect("send request");
+    assert_eq!(response.status(), 200);
+    let cached = client.send(request()).await.expect("cached response");
+    assert_eq!(cached.extensions().get(), Some(&CacheStatus::Hit));
+    assert_eq!(cached.body(), response.body());
+}
+
+#[tokio::test]
+pub async fn test_download_to_path() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = Client::new(cache.path()).await.expect("create client");
+    let path = cache.path().join("download");
+    let request = Request::get("https://httpbin.org/range/4096")
+        .version(http::Version::HTTP_11)
+        .body(())
+        .expect("Couldn't build request");
+
+    let size = client
+        .download_to_path(request, &path, DownloadOptions::default())
+        .await
+        .expect("download");
+    assert_eq!(size, 4096);
+    assert_eq!(
+        std::fs::metadata(&path).expect("downloaded file").len(),
+        4096
+    );
+}
+
+#[tokio::test]
+pub async fn test_send_all() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = new_client(cache.path()).await;
+    let requests = (0..6).map(|i| {
+        Request::get(format!("https://httpbin.org/anything/{}", i))
+            .version(http::Version::HTTP_11)
+            .body(vec![])
+            .expect("Couldn't build request")
+    });
+
+    let results = client.send_all(requests, BatchLimits::default()).await;
+    assert_eq!(results.len(), 6);
+    for (i, result) in results.into_iter().enumerate() {
+        let response = result.expect("response");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).expect("JSON body");
+        assert_eq!(body["url"], format!("https://httpbin.org/anything/{}", i));
+    }
+}
+
+#[tokio::test]
+pub async fn test_reload_directory() {
+    utils::setup_tracing();
+
+    let cache = utils::setup_cache();
+    let client = new_client(cache.path()).await;
+    client.reload_directory().await.expect("reload directory");
+    assert_eq!(
+        client.bootstrap_progress().next().await,
+        Some(BootstrapProgress::DirectoryReady)
+    );
+
+    std::fs::remove_file(cache.path().join(CONSENSUS_FILENAME)).expect("remove consensus");
+    let err = client.reload_directory().await.unwrap_err();
+    assert!(matches!(err, Error::CacheCorrupt(_)), "{:?}", err);
+    assert_eq!(
+        client.bootstrap_progress().next().await,
+        Some(BootstrapProgress::DirectoryReady)
+    );
+
+    let request = Request::get("https://www.example.com")
+        .version(http::Version::HTTP_11)
+        .body(vec![])
+        .expect("Couldn't build request");
+    let response = client
+        .send(request)
+        .await
+        .expect("send with the previous directory");
+    assert_eq!(response.status(), 200);
+}
+
 // Creates a GET request from an URI and calls test_client on it.
 async fn test_get(uri: &str) {
     let url = Url::parse(uri).unwrap();
@@ -54,40 +289,36 @@ async fn test_get(uri: &str) {
     .await;
 }
 
-// Calls the lightarti-rest code up to MAX_TRIES to get a correct answer.
-// Returns on the first correct answer, or throws a panic if all MAX_TRIES failed.
-// This is necessary due to the sometimes erratic behaviour of the tor-nodes.
+// Sends the request with a client retrying it on new circuits, and checks that it succeeds.
 async fn test_client(req: Request<Vec<u8>>) {
     utils::setup_tracing();
-    let (header, body) = req.into_parts();
-    let host = header.uri.clone();
+    let cache = utils::setup_cache();
+    let host = req.uri().clone();
 
-    for i in 1..=MAX_TRIES {
-        let request = clone_request(&header, &body);
-        let cache = utils::setup_cache();
+    let response = new_client(cache.path())
+        .await
+        .send(req)
+        .await
+        .unwrap_or_else(|e| panic!("Didn't manage to pass for domain {}: {:?}", host, e));
+    assert_eq!(response.status(), 200, "wrong status for domain {}", host);
+}
 
-        let res = Client::new(cache.path())
-            .await
-            .expect("create client")
-            .send(request)
-            .await
-            .and_then(|r| {
-                (r.status() == 200)
-                    .then_some(())
-                    .ok_or_else(|| anyhow::anyhow!("wrong status"))
-            });
-
-        if let Err(e) = res {
-            tracing::warn!("Call failed in step {} / {}: {:?}", i, MAX_TRIES, e)
-        } else {
-            return;
-        }
-    }
+// Creates a client retrying the requests failing on the Tor network up to MAX_TRIES times, on new
+// circuits. This is necessary due to the sometimes erratic behaviour of the tor-nodes.
+async fn new_client(cache_path: &Path) -> Client {
+    client_builder(cache_path)
+        .build()
+        .await
+        .expect("create client")
+}
 
-    panic!(
-        "Didn't manage to pass in {} steps for domain {}",
-        MAX_TRIES, host
-    )
+// Configures a client like new_client, to add other settings.
+fn client_builder(cache_path: &Path) -> ClientBuilder {
+    Client::builder(cache_path).retry_policy(RetryPolicy {
+        max_attempts: MAX_TRIES,
+        retry_non_idempotent: true,
+        ..RetryPolicy::default()
+    })
 }
 
 #[tokio::test]
@@ -101,7 +332,7 @@ async fn test_required_files_ok() {
 
 #[tokio::test]
 // Tests that an error is raised by FlatFileDirMgr::check_directory if any of the required files
-// are missing. The authority.json file is checked for in Client::check_directory since it is used
+// are missing. The authority.json file is also checked for by ClientBuilder since it is used
 // before the other files are read in.
 async fn test_required_files_missing() {
     for filename in [
@@ -115,6 +346,7 @@ async fn test_required_files_missing() {
         let _ = std::fs::remove_file(cache.path().join(filename));
         let res = check_directory(cache.path());
         let error = res.expect_err("");
+        assert!(matches!(error, Error::CacheMissing(_)));
         assert_eq!(
             format!("{}", error),
             "Corrupt cache: required file(s) missing in cache"
@@ -128,22 +360,10 @@ async fn test_directory_not_existing() {
     let _ = std::fs::remove_dir_all(cache.path());
     let res = Client::new(cache.path()).await;
     let error = res.err().expect("");
-    let root_cause = error.root_cause();
+    assert!(matches!(error, Error::CacheMissing(_)));
+    assert!(!error.is_retryable());
     assert_eq!(
-        format!("{}", root_cause),
+        format!("{}", error),
         "Corrupt cache: cache-directory doesn't exist"
     );
 }
-
-fn clone_request(header: &Parts, body: &[u8]) -> Request<Vec<u8>> {
-    let mut builder = Builder::new()
-        .method(header.method.clone())
-        .uri(header.uri.clone())
-        .version(header.version);
-    let builder_header = builder.headers_mut().unwrap();
-    for header in header.headers.clone() {
-        builder_header.insert(header.0.unwrap(), header.1);
-    }
-
-    builder.body(body.to_vec()).unwrap()
-}